@@ -1,8 +1,7 @@
 //! This benchmark aims to compare the rate of fragmentation between `Khf`s with different depths.
 
 use criterion::{criterion_group, Criterion};
-use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
-use khf::{Consolidation, Khf};
+use khf::{Consolidation, Khf256};
 use kms::KeyManagementScheme;
 use rand::thread_rng;
 
@@ -19,13 +18,13 @@ const KEYS: usize = 32768;
 
 struct TestCase {
     name: String,
-    forest: Khf<Sha3_256, SHA3_256_MD_SIZE>,
+    forest: Khf256,
 }
 
 fn setup() -> Vec<TestCase> {
     (1..FANOUTS.len())
         .map(|i| {
-            let mut forest = Khf::new(&FANOUTS[..i], thread_rng());
+            let mut forest = Khf256::new(&FANOUTS[..i], thread_rng());
 
             forest.derive(KEYS as u64 - 1).unwrap();
 
@@ -51,7 +50,9 @@ fn bench(c: &mut Criterion) {
                     key += 1;
                 }
 
-                test.forest.consolidate(Consolidation::Full, thread_rng());
+                test.forest
+                    .open_epoch()
+                    .consolidate(Consolidation::Full, thread_rng());
                 test.forest.derive(KEYS as u64 - 1).unwrap();
             })
         });
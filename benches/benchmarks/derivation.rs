@@ -3,8 +3,7 @@
 //! `Khf`s. We fragment each `Khf` and consolidate it to roots of a different level.
 
 use criterion::{criterion_group, BatchSize, Criterion};
-use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
-use khf::{Consolidation, Khf};
+use khf::{Consolidation, Khf256};
 use kms::KeyManagementScheme;
 use rand::thread_rng;
 
@@ -21,20 +20,20 @@ const FANOUTS: &[u64] = &[4, 4, 4, 4, 4, 4, 4, 4];
 // 131072 keys means 2 L1 roots using the fanouts defined above.
 const KEYS: usize = 131072;
 
-struct TestCase<F: FnMut() -> Khf<Sha3_256, SHA3_256_MD_SIZE>> {
+struct TestCase<F: FnMut() -> Khf256> {
     name: String,
     forest: F,
 }
 
-fn setup() -> Vec<TestCase<impl FnMut() -> Khf<Sha3_256, SHA3_256_MD_SIZE>>> {
+fn setup() -> Vec<TestCase<impl FnMut() -> Khf256>> {
     (0..FANOUTS.len())
         .map(|level| TestCase {
             name: format!("L{level} consolidation"),
             forest: move || {
-                let mut forest = Khf::new(FANOUTS, thread_rng());
+                let mut forest = Khf256::new(FANOUTS, thread_rng());
 
                 forest.derive(KEYS as u64 - 1).unwrap();
-                forest.consolidate(
+                forest.open_epoch().consolidate(
                     Consolidation::Leveled {
                         level: level as u64,
                     },
@@ -2,8 +2,7 @@
 //! topologies. Each topology has the same number of L1 descendants.
 
 use criterion::{criterion_group, Criterion};
-use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
-use khf::{Consolidation, Khf};
+use khf::{Consolidation, Khf256};
 use kms::KeyManagementScheme;
 use rand::thread_rng;
 
@@ -21,14 +20,14 @@ const KEYS: usize = 16384;
 
 struct TestCase {
     name: String,
-    forest: Khf<Sha3_256, SHA3_256_MD_SIZE>,
+    forest: Khf256,
 }
 
 fn setup() -> Vec<TestCase> {
     TOPOLOGIES
         .iter()
         .map(|fanouts| {
-            let mut forest = Khf::new(fanouts, thread_rng());
+            let mut forest = Khf256::new(fanouts, thread_rng());
 
             forest.derive(KEYS as u64 - 1).unwrap();
 
@@ -54,7 +53,9 @@ fn bench(c: &mut Criterion) {
                     key += 1;
                 }
 
-                test.forest.consolidate(Consolidation::Full, thread_rng());
+                test.forest
+                    .open_epoch()
+                    .consolidate(Consolidation::Full, thread_rng());
                 test.forest.derive(KEYS as u64 - 1).unwrap();
             })
         });
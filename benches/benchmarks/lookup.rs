@@ -0,0 +1,72 @@
+//! This benchmark aims to compare the latency of key derivation between the different strategies
+//! for looking up the root covering a key. Each `Khf` is fragmented into leaf-level roots, so
+//! derivation cost is dominated by the root lookup.
+
+use criterion::{criterion_group, Criterion};
+use khf::{CachePolicy, Khf256, RootLookup};
+use kms::KeyManagementScheme;
+use rand::{thread_rng, Rng};
+
+// 4^10 = 1048576 leaves.
+const FANOUTS: &[u64] = &[4; 10];
+
+const ROOTS: &[u64] = &[100_000, 1_000_000];
+
+const DERIVATIONS: usize = 1000;
+
+struct TestCase {
+    name: String,
+    forest: Khf256,
+    keys: Vec<u64>,
+}
+
+fn setup() -> Vec<TestCase> {
+    ROOTS
+        .iter()
+        .flat_map(|roots| {
+            let mut forest = Khf256::new(FANOUTS, thread_rng());
+
+            // Fragment the forest into leaf-level roots and disable caching.
+            forest.open_epoch().append(*roots);
+            forest.commit(thread_rng()).unwrap();
+            forest
+                .open_epoch()
+                .fragment(0..*roots, FANOUTS.len() as u64 + 1);
+            forest.set_cache_policy(CachePolicy::Adaptive { min: 0, max: 0 });
+
+            let keys: Vec<u64> = (0..DERIVATIONS)
+                .map(|_| thread_rng().gen_range(0..*roots))
+                .collect();
+
+            [RootLookup::Binary, RootLookup::Interpolation]
+                .into_iter()
+                .map(move |lookup| {
+                    let mut forest = forest.clone();
+                    forest.set_root_lookup(lookup);
+                    TestCase {
+                        name: format!("{lookup:?} ({roots} roots)"),
+                        forest,
+                        keys: keys.clone(),
+                    }
+                })
+        })
+        .collect()
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("Root Lookup ({DERIVATIONS} derivations)"));
+
+    for test in setup().iter_mut() {
+        group.bench_function(&test.name, |b| {
+            b.iter(|| {
+                for key in &test.keys {
+                    test.forest.derive(*key).unwrap();
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench);
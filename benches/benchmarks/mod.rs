@@ -1,4 +1,6 @@
 pub mod depth;
 pub mod derivation;
 pub mod heterogeneity;
+pub mod lookup;
+pub mod root_level;
 pub mod width;
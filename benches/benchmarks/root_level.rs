@@ -0,0 +1,68 @@
+//! This benchmark aims to compare the latency of committing sparse updates between `Khf`s that
+//! fragment to roots of different levels.
+
+use criterion::{criterion_group, BatchSize, Criterion};
+use khf::Khf256;
+use kms::KeyManagementScheme;
+use rand::{thread_rng, Rng};
+
+const FANOUTS: &[u64] = &[4; 8];
+const LEVELS: &[u64] = &[1, 3, 5, 7];
+const KEYS: u64 = 65536;
+const UPDATES: usize = 64;
+
+struct TestCase {
+    name: String,
+    forest: Khf256,
+    keys: Vec<u64>,
+}
+
+fn setup() -> Vec<TestCase> {
+    let keys: Vec<u64> = (0..UPDATES)
+        .map(|_| thread_rng().gen_range(0..KEYS))
+        .collect();
+
+    LEVELS
+        .iter()
+        .map(|level| {
+            let mut forest = Khf256::builder()
+                .fanouts(FANOUTS)
+                .root_level(*level)
+                .build(thread_rng());
+
+            forest.open_epoch().append(KEYS);
+            forest.commit(thread_rng()).unwrap();
+
+            TestCase {
+                name: format!("level {level}"),
+                forest,
+                keys: keys.clone(),
+            }
+        })
+        .collect()
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!(
+        "Sparse Commit With Variable Root Level ({UPDATES} updates)"
+    ));
+
+    for test in setup().iter() {
+        group.bench_function(&test.name, |b| {
+            b.iter_batched(
+                || test.forest.clone(),
+                |mut forest| {
+                    for key in &test.keys {
+                        forest.update(*key).unwrap();
+                    }
+                    forest.commit(thread_rng()).unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench);
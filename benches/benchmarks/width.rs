@@ -1,8 +1,7 @@
 //! This benchmark aims to compare the rate of fragmentation between `Khf`s with different widths.
 
 use criterion::{criterion_group, Criterion};
-use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
-use khf::{Consolidation, Khf};
+use khf::{Consolidation, Khf256};
 use kms::KeyManagementScheme;
 use rand::thread_rng;
 
@@ -12,14 +11,14 @@ const KEYS: usize = 32768;
 
 struct TestCase {
     name: String,
-    forest: Khf<Sha3_256, SHA3_256_MD_SIZE>,
+    forest: Khf256,
 }
 
 fn setup() -> Vec<TestCase> {
     WIDTHS
         .iter()
         .map(|width| {
-            let mut forest = Khf::new(&vec![*width; DEPTH], thread_rng());
+            let mut forest = Khf256::new(&vec![*width; DEPTH], thread_rng());
 
             forest.derive(KEYS as u64 - 1).unwrap();
 
@@ -45,7 +44,9 @@ fn bench(c: &mut Criterion) {
                     key += 1;
                 }
 
-                test.forest.consolidate(Consolidation::Full, thread_rng());
+                test.forest
+                    .open_epoch()
+                    .consolidate(Consolidation::Full, thread_rng());
                 test.forest.derive(KEYS as u64 - 1).unwrap();
             })
         });
@@ -7,4 +7,6 @@ criterion_main! {
     benchmarks::derivation::benches,
     benchmarks::width::benches,
     benchmarks::heterogeneity::benches,
+    benchmarks::lookup::benches,
+    benchmarks::root_level::benches,
 }
@@ -0,0 +1,147 @@
+//! A regression gate for the latency of core `Khf` operations. Each metric is the median latency
+//! (in nanoseconds) of an operation over a number of samples, and the metrics are printed as JSON.
+//!
+//! The gate is configured through environment variables:
+//!  - `KHF_BENCH_SAVE`: a path to store the metrics at, e.g., to record a baseline.
+//!  - `KHF_BENCH_BASELINE`: a path to a stored baseline to compare the metrics against.
+//!  - `KHF_BENCH_THRESHOLD`: the slowdown (in percent) over the baseline tolerated before a
+//!    metric is considered a regression. Defaults to 10.
+//!
+//! The process exits with a failure if any metric regresses.
+
+use khf::{Consolidation, Khf256};
+use kms::KeyManagementScheme;
+use rand::{thread_rng, Rng};
+use std::{collections::BTreeMap, env, fs, process::ExitCode, time::Instant};
+
+const FANOUTS: &[u64] = &[4; 8];
+const KEYS: u64 = 65536;
+const UPDATES: usize = 64;
+const SAMPLES: usize = 51;
+const DEFAULT_THRESHOLD: f64 = 10.0;
+
+type Metrics = BTreeMap<String, u64>;
+
+// Returns the median latency of a routine over a number of samples, with a fresh input produced
+// by `setup` for each sample.
+fn measure<T>(mut setup: impl FnMut() -> T, mut routine: impl FnMut(T)) -> u64 {
+    let mut samples: Vec<u64> = (0..SAMPLES)
+        .map(|_| {
+            let input = setup();
+            let start = Instant::now();
+            routine(input);
+            start.elapsed().as_nanos() as u64
+        })
+        .collect();
+    samples.sort_unstable();
+    samples[SAMPLES / 2]
+}
+
+fn collect() -> Metrics {
+    let mut forest = Khf256::new(FANOUTS, thread_rng());
+    forest.open_epoch().append(KEYS);
+    forest.commit(thread_rng()).unwrap();
+
+    let mut fragmented = forest.clone();
+    fragmented
+        .open_epoch()
+        .consolidate(Consolidation::Leveled { level: 4 }, thread_rng());
+
+    let keys: Vec<u64> = (0..UPDATES)
+        .map(|_| thread_rng().gen_range(0..KEYS))
+        .collect();
+
+    let mut metrics = Metrics::new();
+
+    metrics.insert(
+        "derive".into(),
+        measure(
+            || fragmented.clone(),
+            |mut forest| {
+                for key in &keys {
+                    forest.derive(*key).unwrap();
+                }
+            },
+        ),
+    );
+
+    metrics.insert(
+        "commit".into(),
+        measure(
+            || forest.clone(),
+            |mut forest| {
+                for key in &keys {
+                    forest.update(*key).unwrap();
+                }
+                forest.commit(thread_rng()).unwrap();
+            },
+        ),
+    );
+
+    metrics.insert(
+        "persist".into(),
+        measure(
+            || fragmented.clone(),
+            |forest| {
+                bincode::serialize(&forest).unwrap();
+            },
+        ),
+    );
+
+    metrics.insert(
+        "coverage".into(),
+        measure(
+            || (),
+            |_| {
+                for key in &keys {
+                    forest.plan_coverage(1, *key, KEYS - 1);
+                }
+            },
+        ),
+    );
+
+    metrics
+}
+
+// Returns the metrics that regressed beyond the threshold along with their slowdown in percent.
+fn regressions(baseline: &Metrics, metrics: &Metrics, threshold: f64) -> Vec<(String, f64)> {
+    metrics
+        .iter()
+        .filter_map(|(name, latency)| {
+            let base = *baseline.get(name)?;
+            let slowdown = (*latency as f64 / base.max(1) as f64 - 1.0) * 100.0;
+            (slowdown > threshold).then(|| (name.clone(), slowdown))
+        })
+        .collect()
+}
+
+fn main() -> ExitCode {
+    let metrics = collect();
+    let json = serde_json::to_string_pretty(&metrics).unwrap();
+    println!("{json}");
+
+    if let Ok(path) = env::var("KHF_BENCH_SAVE") {
+        fs::write(path, &json).unwrap();
+    }
+
+    let Ok(path) = env::var("KHF_BENCH_BASELINE") else {
+        return ExitCode::SUCCESS;
+    };
+
+    let baseline: Metrics = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    let threshold = env::var("KHF_BENCH_THRESHOLD")
+        .ok()
+        .and_then(|threshold| threshold.parse().ok())
+        .unwrap_or(DEFAULT_THRESHOLD);
+
+    let regressions = regressions(&baseline, &metrics, threshold);
+    for (name, slowdown) in &regressions {
+        eprintln!("regression: {name} is {slowdown:.1}% slower than the baseline");
+    }
+
+    if regressions.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -2,7 +2,7 @@ use crate::command::Command;
 use anyhow::Result;
 use crossterm::event::{self, Event, KeyCode, KeyModifiers};
 use hasher::Hasher;
-use khf::Khf;
+use khf::{encoding::encode_hex, Khf};
 use kms::KeyManagementScheme;
 use rand::{CryptoRng, RngCore};
 use std::{fmt::Write, str::FromStr};
@@ -16,6 +16,9 @@ use tui::{
 };
 use unicode_width::UnicodeWidthStr;
 
+// How many levels below each root are drawn, so that wide topologies stay drawable.
+const MAX_DEPTH: u64 = 4;
+
 pub struct App<H, const N: usize> {
     command: String,
     history: Vec<String>,
@@ -55,10 +58,10 @@ where
                         let mut command = self.command.drain(3..).collect::<String>();
                         match Command::from_str(&command)? {
                             Command::Derive(key) => {
-                                write!(command, " [{}]", hex::encode(self.forest.derive(key)?))?;
+                                write!(command, " [{}]", *encode_hex(&self.forest.derive(key)?))?;
                             }
                             Command::Update(key) => {
-                                write!(command, " [{}]", hex::encode(self.forest.update(key)?))?;
+                                write!(command, " [{}]", *encode_hex(&self.forest.update(key)?))?;
                             }
                             Command::Commit => {
                                 write!(command, " {:?}", self.forest.commit(&mut rng))?;
@@ -68,8 +71,19 @@ where
                                 continue;
                             }
                             Command::Truncate(keys) => {
-                                self.forest.truncate(keys);
+                                self.forest.open_epoch().truncate(keys);
                             }
+                            Command::Topology(fanouts) => match Khf::try_new(&fanouts, &mut rng) {
+                                Ok(forest) => {
+                                    // Recreate the forest, keeping its keys for comparison.
+                                    let keys = self.forest.in_flight_len();
+                                    self.forest = forest;
+                                    let mut epoch = self.forest.open_epoch();
+                                    epoch.append(keys);
+                                    epoch.commit(&mut rng)?;
+                                }
+                                Err(err) => write!(command, " [{err}]")?,
+                            },
                             Command::Invalid => {}
                         }
                         self.history.push(command);
@@ -127,6 +141,8 @@ where
     fn draw_forest_ui<B: Backend>(&self, f: &mut Frame<B>, area: Rect) {
         let padding = self
             .forest
+            .dump_unredacted()
+            .max_depth(MAX_DEPTH)
             .to_string()
             .split('\n')
             .map(|line| line.chars().count())
@@ -135,6 +151,8 @@ where
 
         let string = self
             .forest
+            .dump_unredacted()
+            .max_depth(MAX_DEPTH)
             .to_string()
             .split('\n')
             .map(|line| line.to_owned() + &" ".repeat(padding - line.chars().count()))
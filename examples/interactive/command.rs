@@ -2,8 +2,9 @@ use anyhow::Error;
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag},
-    character::complete::multispace0,
-    combinator::{map, map_res},
+    character::complete::{digit1, multispace0},
+    combinator::{map, map_res, verify},
+    multi::separated_list1,
     sequence::{delimited, tuple},
     IResult,
 };
@@ -16,6 +17,7 @@ pub enum Command {
     Invalid,
     Clear,
     Truncate(u64),
+    Topology(Vec<u64>),
 }
 
 impl FromStr for Command {
@@ -26,7 +28,14 @@ impl FromStr for Command {
 }
 
 pub fn parse_cmd(input: &str) -> IResult<&str, Command> {
-    alt((derive_cmd, update_cmd, commit_cmd, clear_cmd, truncate_cmd))(input)
+    alt((
+        derive_cmd,
+        update_cmd,
+        commit_cmd,
+        clear_cmd,
+        truncate_cmd,
+        topology_cmd,
+    ))(input)
 }
 
 fn derive_cmd(input: &str) -> IResult<&str, Command> {
@@ -79,3 +88,19 @@ fn truncate_cmd(input: &str) -> IResult<&str, Command> {
         |(_, _, _, keys, _)| Command::Truncate(keys),
     )(input)
 }
+
+fn topology_cmd(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((
+            multispace0,
+            tag("topology"),
+            multispace0,
+            separated_list1(
+                tag(","),
+                verify(map_res(digit1, u64::from_str), |fanout| *fanout > 0),
+            ),
+            multispace0,
+        )),
+        |(_, _, _, fanouts, _)| Command::Topology(fanouts),
+    )(input)
+}
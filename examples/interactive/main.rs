@@ -5,8 +5,7 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use hasher::sha3::*;
-use khf::Khf;
+use khf::Khf256;
 use rand::prelude::ThreadRng;
 use std::io;
 use tui::{backend::CrosstermBackend, Terminal};
@@ -16,8 +15,6 @@ use app::App;
 
 pub mod command;
 
-type DefaultKhf = Khf<Sha3_256, SHA3_256_MD_SIZE>;
-
 #[derive(Parser)]
 struct Args {
     /// The fanout list defining the topology of the interactive forest.
@@ -29,7 +26,7 @@ fn main() -> Result<()> {
     let args = Args::parse();
 
     let mut rng = ThreadRng::default();
-    let forest = DefaultKhf::new(&args.fanouts, ThreadRng::default());
+    let forest = Khf256::new(&args.fanouts, ThreadRng::default());
 
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -0,0 +1,23 @@
+//! The dependency-light core of `khf`: the topology of keyed hash trees, and the derivation of
+//! keys from their nodes. Serialization is available behind the `serde` feature.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod node;
+pub mod topology;
+
+#[cfg(feature = "serde")]
+mod serde_key;
+
+pub use crate::{
+    node::{KeyCache, Node, PathCache},
+    topology::{Coverage, Topology},
+};
+
+/// A key, which has the same size as the digest of the hash function deriving it.
+pub type Key<const N: usize> = [u8; N];
+
+/// The position of a node in a topology, as its level and offset within the level.
+pub type Pos = (u64, u64);
@@ -0,0 +1,198 @@
+use crate::{topology::Topology, Key, Pos};
+use core::{fmt, marker::PhantomData};
+use hasher::Hasher;
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Node<H, const N: usize> {
+    pub pos: Pos,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_key"))]
+    pub key: Key<N>,
+    // Nodes don't hold a hasher, so they're `Send` and `Sync` whatever the hasher.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pd: PhantomData<fn() -> H>,
+}
+
+/// Redacts the key, so that logging a node doesn't leak key material. Use `dump_unredacted()` to
+/// include it.
+impl<H, const N: usize> fmt::Debug for Node<H, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("pos", &self.pos)
+            .field("key", &format_args!("<redacted>"))
+            .finish()
+    }
+}
+
+// Formats a node along with its key.
+struct Unredacted<'a, H, const N: usize>(&'a Node<H, N>);
+
+impl<'a, H, const N: usize> fmt::Debug for Unredacted<'a, H, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("pos", &self.0.pos)
+            .field("key", &HexKey(&self.0.key))
+            .finish()
+    }
+}
+
+/// A cache of the keys of nodes, used to skip recomputing them when deriving keys.
+pub trait KeyCache<const N: usize> {
+    /// Returns the cached key of a node, if there is one.
+    fn get(&self, pos: &Pos) -> Option<Key<N>>;
+
+    /// Caches the key of a node.
+    fn insert(&mut self, pos: Pos, key: Key<N>);
+}
+
+/// The unit cache caches nothing.
+impl<const N: usize> KeyCache<N> for () {
+    fn get(&self, _: &Pos) -> Option<Key<N>> {
+        None
+    }
+
+    fn insert(&mut self, _: Pos, _: Key<N>) {}
+}
+
+// Formats a key as lowercase hex.
+struct HexKey<'a, const N: usize>(&'a Key<N>);
+
+impl<'a, const N: usize> fmt::Debug for HexKey<'a, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+// Manually implemented to avoid restrictive bounds on `H`.
+impl<H, const N: usize> Clone for Node<H, N> {
+    fn clone(&self) -> Self {
+        Self {
+            pos: self.pos,
+            key: self.key,
+            pd: PhantomData,
+        }
+    }
+}
+
+// Node keys are wiped when dropped, so they don't linger in freed memory.
+impl<H, const N: usize> Drop for Node<H, N> {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl<H, const N: usize> Node<H, N>
+where
+    H: Hasher<N>,
+{
+    pub fn new(key: Key<N>) -> Self {
+        Self {
+            pos: (0, 0),
+            key,
+            pd: PhantomData,
+        }
+    }
+
+    pub fn with_rng<R>(rng: &mut R) -> Self
+    where
+        R: RngCore + CryptoRng,
+    {
+        let mut key = [0; N];
+        rng.fill_bytes(&mut key);
+        Self::new(key)
+    }
+
+    pub fn with_pos(pos: Pos, key: Key<N>) -> Self {
+        Self {
+            pos,
+            key,
+            pd: PhantomData,
+        }
+    }
+
+    /// Returns a `Debug` view of the node that includes its key.
+    pub fn dump_unredacted(&self) -> impl fmt::Debug + '_ {
+        Unredacted(self)
+    }
+
+    pub fn derive(&self, topology: &Topology, pos: Pos) -> Key<N> {
+        self.derive_with(topology, pos, &mut ())
+    }
+
+    /// Derives the key of a descendant, looking up the keys of nodes along the path in a cache
+    /// and caching the ones that had to be computed.
+    pub fn derive_with(
+        &self,
+        topology: &Topology,
+        pos: Pos,
+        cache: &mut impl KeyCache<N>,
+    ) -> Key<N> {
+        if self.pos == pos {
+            self.key
+        } else {
+            topology.path(self.pos, pos).fold(self.key, |key, pos| {
+                if let Some(cached_key) = cache.get(&pos) {
+                    cached_key
+                } else {
+                    let mut hasher = H::new();
+                    hasher.update(&key);
+                    hasher.update(&pos.0.to_le_bytes());
+                    hasher.update(&pos.1.to_le_bytes());
+
+                    let key = hasher.finish();
+                    cache.insert(pos, key);
+                    key
+                }
+            })
+        }
+    }
+
+    pub fn coverage(
+        &self,
+        topology: &Topology,
+        level: u64,
+        start: u64,
+        end: u64,
+    ) -> alloc::vec::Vec<Self> {
+        // Covering nodes are derived in order, so consecutive nodes share most of their paths.
+        let mut cache = PathCache::default();
+        topology
+            .coverage(level, start, end)
+            .map(|pos| Self::with_pos(pos, self.derive_with(topology, pos, &mut cache)))
+            .collect()
+    }
+}
+
+/// Caches the keys along the most recently derived path, one per level, which is all that's needed
+/// to share work between derivations of nodes in order. The keys are wiped when it's dropped.
+#[derive(Default)]
+pub struct PathCache<const N: usize> {
+    path: alloc::vec::Vec<(Pos, Key<N>)>,
+}
+
+impl<const N: usize> KeyCache<N> for PathCache<N> {
+    fn get(&self, pos: &Pos) -> Option<Key<N>> {
+        self.path
+            .get(pos.0 as usize)
+            .filter(|(cached, _)| cached == pos)
+            .map(|(_, key)| *key)
+    }
+
+    fn insert(&mut self, pos: Pos, key: Key<N>) {
+        let level = pos.0 as usize;
+        if self.path.len() <= level {
+            self.path.resize(level + 1, ((u64::MAX, 0), [0; N]));
+        }
+        self.path[level].1.zeroize();
+        self.path[level] = (pos, key);
+    }
+}
+
+impl<const N: usize> Drop for PathCache<N> {
+    fn drop(&mut self) {
+        for (_, key) in &mut self.path {
+            key.zeroize();
+        }
+    }
+}
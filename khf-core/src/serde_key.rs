@@ -0,0 +1,49 @@
+use crate::Key;
+use core::{fmt, marker::PhantomData};
+use serde::{
+    de::{Error, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserializer, Serializer,
+};
+
+// Keys are serialized as tuples, since `serde` only implements its traits for small arrays.
+pub fn serialize<S, const N: usize>(key: &Key<N>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut tuple = serializer.serialize_tuple(N)?;
+    for byte in key {
+        tuple.serialize_element(byte)?;
+    }
+    tuple.end()
+}
+
+pub fn deserialize<'de, D, const N: usize>(deserializer: D) -> Result<Key<N>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_tuple(N, KeyVisitor(PhantomData))
+}
+
+struct KeyVisitor<const N: usize>(PhantomData<Key<N>>);
+
+impl<'de, const N: usize> Visitor<'de> for KeyVisitor<N> {
+    type Value = Key<N>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a key of {N} bytes")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut key = [0; N];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+        }
+        Ok(key)
+    }
+}
@@ -1,11 +1,34 @@
-use crate::aliases::Pos;
-use serde::{Deserialize, Serialize};
+use crate::Pos;
+use alloc::vec::Vec;
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Topology {
     descendants: Vec<u64>,
 }
 
+/// Serializes the topology as its fanout list.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Topology {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(&self.fanouts(), serializer)
+    }
+}
+
+/// Deserializes the topology from its fanout list, rejecting invalid fanouts (see `try_new()`).
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Topology {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let fanouts: Vec<u64> = serde::Deserialize::deserialize(deserializer)?;
+        Self::try_new(&fanouts).ok_or_else(|| serde::de::Error::custom("invalid fanouts"))
+    }
+}
+
 impl Default for Topology {
     fn default() -> Self {
         Self::new(&[4, 4, 4, 4])
@@ -13,8 +36,22 @@ impl Default for Topology {
 }
 
 impl Topology {
+    /// Constructs a topology from a list of fanouts.
+    ///
+    /// Panics if the fanouts are invalid (see `try_new()`).
     pub fn new(fanouts: &[u64]) -> Self {
-        let mut leaves = fanouts.iter().product();
+        Self::try_new(fanouts).expect("invalid fanouts")
+    }
+
+    /// Constructs a topology from a list of fanouts, or returns `None` if the list is empty, a
+    /// fanout is less than 2, or the number of keys under a top-level node overflows a `u64`.
+    pub fn try_new(fanouts: &[u64]) -> Option<Self> {
+        if fanouts.is_empty() || fanouts.iter().any(|fanout| *fanout < 2) {
+            return None;
+        }
+        let mut leaves = fanouts
+            .iter()
+            .try_fold(1u64, |leaves, fanout| leaves.checked_mul(*fanout))?;
         let mut descendants = Vec::with_capacity(fanouts.len() + 2);
 
         descendants.push(0);
@@ -24,7 +61,31 @@ impl Topology {
         }
         descendants.push(1);
 
-        Self { descendants }
+        Some(Self { descendants })
+    }
+
+    /// Constructs a topology from the number of leaves under a node at each level, from the
+    /// top-level nodes down to the parents of the leaves, e.g., `[64, 16, 4]` for the fanouts
+    /// `[4, 4, 4]`. This is convenient for matching levels to extent sizes. Returns `None` unless
+    /// each count is a multiple of the next, larger than it, and the last is at least 2.
+    pub fn from_descendants(descendants: &[u64]) -> Option<Self> {
+        let last = *descendants.last()?;
+        let mut fanouts: Vec<u64> = descendants
+            .windows(2)
+            .map(|pair| match pair[0].checked_rem(pair[1]) {
+                Some(0) => pair[0] / pair[1],
+                // An invalid fanout, which `try_new()` rejects.
+                _ => 0,
+            })
+            .collect();
+        fanouts.push(last);
+        Self::try_new(&fanouts)
+    }
+
+    /// Returns `true` if nodes can be exchanged between forests with the two topologies, i.e., if
+    /// they bind every key to the same positions.
+    pub fn compatible_with(&self, other: &Self) -> bool {
+        self.descendants == other.descendants
     }
 
     pub fn height(&self) -> u64 {
@@ -41,10 +102,21 @@ impl Topology {
         }
     }
 
+    pub fn fanouts(&self) -> Vec<u64> {
+        (1..self.height() - 1)
+            .map(|level| self.fanout(level))
+            .collect()
+    }
+
     pub fn descendants(&self, level: u64) -> u64 {
         self.descendants[level as usize]
     }
 
+    pub fn max_leaves(&self) -> u64 {
+        let leaves = self.descendants(1);
+        (u64::MAX / leaves) * leaves
+    }
+
     pub fn start(&self, node: Pos) -> u64 {
         if node.0 == 0 {
             0
@@ -118,6 +190,8 @@ impl<'a> Iterator for Path<'a> {
     }
 }
 
+/// Iterates over the positions of the minimal set of nodes covering a range of leaves, preferring
+/// nodes of a certain level and using finer nodes only at the unaligned edges of the range.
 pub struct Coverage<'a> {
     level: u64,
     start: u64,
@@ -133,6 +207,7 @@ enum State {
 }
 
 impl<'a> Coverage<'a> {
+    /// Panics if `level` isn't between 1 and the height of the topology (exclusive).
     pub fn new(topology: &'a Topology, level: u64, start: u64, end: u64) -> Self {
         // Easiest way to enforce correctness (for now).
         assert!(0 < level && level < topology.height());
@@ -1,2 +1,24 @@
-pub type Key<const N: usize> = [u8; N];
-pub type Pos = (u64, u64);
+use crate::{Khf, Kht};
+use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+
+pub use khf_core::{Key, Pos};
+
+/// A `Khf` using SHA3-256.
+pub type Khf256 = Khf<Sha3_256, SHA3_256_MD_SIZE>;
+
+/// A `Kht` using SHA3-256.
+pub type Kht256 = Kht<Sha3_256, SHA3_256_MD_SIZE>;
+
+/// A `Khf` using SHA3-256 through OpenSSL where it's supported, and the portable implementation
+/// otherwise. It derives the same keys as a `Khf256`.
+#[cfg(feature = "openssl")]
+pub type KhfAccelerated256 =
+    Khf<crate::Fallback<crate::hashers::OpensslSha3_256, Sha3_256>, SHA3_256_MD_SIZE>;
+
+/// A `Khf` using BLAKE3.
+#[cfg(feature = "blake3")]
+pub type KhfBlake3 = Khf<crate::hashers::Blake3, { crate::hashers::BLAKE3_MD_SIZE }>;
+
+/// A `Kht` using BLAKE3.
+#[cfg(feature = "blake3")]
+pub type KhtBlake3 = Kht<crate::hashers::Blake3, { crate::hashers::BLAKE3_MD_SIZE }>;
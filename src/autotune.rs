@@ -0,0 +1,336 @@
+//! Tuning the topology and consolidation policy of a `Khf` to its workload.
+//!
+//! A `Recorder` observes a live `Khf` for a number of epochs, recording the keys appended,
+//! truncated, and updated in each. The recorded workload is then replayed against candidate
+//! configurations, and the one with the lowest estimated cost is recommended. A recommended fanout
+//! list can be applied to a live `Khf` with `Khf::retopologize()`, which rotates every key into the
+//! new topology at the next commit, or to forests created afterwards (e.g., when reformatting). A
+//! recommended root level can be applied right away with `Khf::set_root_level()`.
+
+use crate::{
+    error::Error,
+    khf::{EpochOp, Khf, KhfBuilder},
+};
+use hasher::Hasher;
+use kms::KeyManagementScheme;
+use rand::{CryptoRng, RngCore};
+use std::collections::VecDeque;
+
+/// A configuration of a `Khf` to evaluate against a recorded workload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    /// The fanout list of the topology.
+    pub fanouts: Vec<u64>,
+    /// The level of roots created when fragmenting.
+    pub root_level: u64,
+}
+
+/// The simulated behavior of a candidate configuration under a recorded workload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+    /// The configuration simulated.
+    pub candidate: Candidate,
+    /// The mean number of roots after each commit, i.e., the size of the persisted root list.
+    pub mean_roots: f64,
+    /// The largest number of roots after any commit.
+    pub max_roots: u64,
+    /// The mean number of hashes needed to derive an updated key from its root.
+    pub mean_derivation: f64,
+    /// The estimated work per epoch: a unit per persisted root, and per hash spent deriving
+    /// updated keys.
+    pub cost: f64,
+}
+
+/// A recommended configuration, along with the estimates it's based on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recommendation {
+    /// The estimate for the current configuration.
+    pub current: Estimate,
+    /// The estimate for the configuration with the lowest cost, which may be the current one.
+    pub best: Estimate,
+    /// The estimates for every candidate, in the order given.
+    pub estimates: Vec<Estimate>,
+}
+
+impl Recommendation {
+    /// The expected relative reduction in cost from switching to the best configuration.
+    pub fn improvement(&self) -> f64 {
+        if self.current.cost > 0.0 {
+            1.0 - self.best.cost / self.current.cost
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns `true` if the best configuration differs from the current one.
+    pub fn should_retune(&self) -> bool {
+        self.best.candidate != self.current.candidate
+    }
+}
+
+/// Records the workload of a live `Khf` over a window of its most recent epochs.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    window: usize,
+    // The number of keys before the first recorded epoch.
+    base_keys: Option<u64>,
+    // The number of keys as of the last observation.
+    keys: u64,
+    epochs: VecDeque<Vec<EpochOp>>,
+}
+
+impl Recorder {
+    /// Creates a recorder keeping the workload of up to `window` epochs.
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            base_keys: None,
+            keys: 0,
+            epochs: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of recorded epochs.
+    pub fn epochs(&self) -> usize {
+        self.epochs.len()
+    }
+
+    /// Returns `true` if the window of epochs is full.
+    pub fn is_complete(&self) -> bool {
+        self.epochs.len() >= self.window
+    }
+
+    /// Records the epoch a `Khf` is about to commit. This must be called right before each commit.
+    pub fn observe<H, const N: usize>(&mut self, khf: &Khf<H, N>)
+    where
+        H: Hasher<N>,
+    {
+        let base = *self.base_keys.get_or_insert(khf.len());
+        if self.epochs.is_empty() {
+            self.keys = base;
+        }
+
+        // Keys truncated and then appended again in the same epoch are recorded as both.
+        let mut ops = Vec::new();
+        if let Some(truncated) = khf.truncated_len().filter(|keys| *keys < self.keys) {
+            ops.push(EpochOp::Truncate(truncated));
+            self.keys = truncated;
+        }
+
+        let keys = khf.in_flight_len();
+        if keys > self.keys {
+            ops.push(EpochOp::Append(keys - self.keys));
+        } else if keys < self.keys {
+            ops.push(EpochOp::Truncate(keys));
+        }
+        self.keys = keys;
+
+        let mut updated: Vec<u64> = khf.updated_keys().iter().collect();
+        updated.sort_unstable();
+        ops.extend(updated.into_iter().map(EpochOp::Update));
+        self.epochs.push_back(ops);
+
+        // Fold epochs falling out of the window into the base key count.
+        while self.epochs.len() > self.window {
+            for op in self.epochs.pop_front().unwrap_or_default() {
+                match op {
+                    EpochOp::Append(n) => self.base_keys = self.base_keys.map(|keys| keys + n),
+                    EpochOp::Truncate(n) => self.base_keys = Some(n),
+                    EpochOp::Update(_) => {}
+                }
+            }
+        }
+    }
+
+    /// Replays the recorded workload against the current configuration and each candidate,
+    /// recommending the one with the lowest estimated cost. Errors with `Error::InvalidTopology`
+    /// if any configuration has invalid fanouts or an invalid root level.
+    pub fn recommend<H, const N: usize>(
+        &self,
+        current: &Candidate,
+        candidates: &[Candidate],
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Recommendation, Error>
+    where
+        H: Hasher<N>,
+    {
+        let current = self.simulate::<H, N>(current, &mut rng)?;
+        let estimates = candidates
+            .iter()
+            .map(|candidate| self.simulate::<H, N>(candidate, &mut rng))
+            .collect::<Result<Vec<_>, _>>()?;
+        let best = estimates
+            .iter()
+            .filter(|estimate| estimate.cost < current.cost)
+            .min_by(|a, b| a.cost.total_cmp(&b.cost))
+            .unwrap_or(&current)
+            .clone();
+
+        Ok(Recommendation {
+            current,
+            best,
+            estimates,
+        })
+    }
+
+    // Replays the recorded workload against a configuration.
+    fn simulate<H, const N: usize>(
+        &self,
+        candidate: &Candidate,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Estimate, Error>
+    where
+        H: Hasher<N>,
+    {
+        let mut khf: Khf<H, N> = KhfBuilder::new()
+            .fanouts(&candidate.fanouts)
+            .root_level(candidate.root_level)
+            .try_build(&mut rng)?;
+        khf.append(self.base_keys.unwrap_or_default());
+        let _ = khf.commit(&mut rng);
+
+        let leaf_level = khf.topology().height() - 1;
+        let mut roots = Vec::new();
+        let (mut hashes, mut derivations) = (0, 0);
+
+        for epoch in &self.epochs {
+            // The roots covering committed keys, as `(start, level)` pairs.
+            let covering: Vec<(u64, u64)> = khf
+                .roots()
+                .map(|(level, _, start, _)| (start, level))
+                .collect();
+
+            for op in epoch {
+                match *op {
+                    EpochOp::Append(n) => {
+                        khf.append(n);
+                    }
+                    EpochOp::Truncate(n) => khf.truncate_keys(n),
+                    EpochOp::Update(key) => {
+                        if khf.update(key).is_err() {
+                            continue;
+                        }
+                        let index = covering.partition_point(|&(start, _)| start <= key);
+                        let level = index
+                            .checked_sub(1)
+                            .filter(|_| key < khf.len())
+                            .map_or(0, |index| covering[index].1);
+                        hashes += leaf_level - level;
+                        derivations += 1;
+                    }
+                }
+            }
+
+            let _ = khf.commit(&mut rng);
+            roots.push(khf.fragmentation());
+        }
+
+        let epochs = roots.len().max(1) as f64;
+        let mean_roots = roots.iter().sum::<u64>() as f64 / epochs;
+        let mean_derivation = hashes as f64 / derivations.max(1) as f64;
+        Ok(Estimate {
+            candidate: candidate.clone(),
+            mean_roots,
+            max_roots: roots.iter().copied().max().unwrap_or_default(),
+            mean_derivation,
+            cost: mean_roots + hashes as f64 / epochs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use rand::prelude::*;
+
+    #[test]
+    fn recommend() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], thread_rng());
+        khf.append(64);
+        khf.commit(thread_rng())?;
+
+        let mut recorder = Recorder::new(4);
+        for epoch in 0..6 {
+            khf.update(epoch * 9)?;
+            khf.append(2);
+            recorder.observe(&khf);
+            khf.commit(thread_rng())?;
+        }
+        assert!(recorder.is_complete());
+        assert_eq!(recorder.epochs(), 4);
+
+        let current = Candidate {
+            fanouts: vec![4, 4, 4],
+            root_level: 1,
+        };
+        let candidates = [
+            Candidate {
+                fanouts: vec![4, 4, 4],
+                root_level: 3,
+            },
+            Candidate {
+                fanouts: vec![8, 8],
+                root_level: 1,
+            },
+        ];
+        let recommendation = recorder.recommend::<Sha3_256, SHA3_256_MD_SIZE>(
+            &current,
+            &candidates,
+            thread_rng(),
+        )?;
+        assert_eq!(recommendation.current.candidate, current);
+        assert_eq!(recommendation.estimates.len(), 2);
+        assert!(recommendation.best.cost <= recommendation.current.cost);
+        assert!(recommendation.improvement() >= 0.0);
+
+        // Invalid candidates are rejected rather than simulated.
+        for candidate in [
+            Candidate {
+                fanouts: vec![1, 4],
+                root_level: 1,
+            },
+            Candidate {
+                fanouts: vec![4, 4],
+                root_level: 4,
+            },
+        ] {
+            assert!(matches!(
+                recorder.recommend::<Sha3_256, SHA3_256_MD_SIZE>(
+                    &current,
+                    &[candidate],
+                    thread_rng()
+                ),
+                Err(Error::InvalidTopology)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_and_append() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], thread_rng());
+        khf.append(64);
+        khf.commit(thread_rng())?;
+
+        // A truncation undone by appends in the same epoch is still recorded.
+        let mut recorder = Recorder::new(4);
+        khf.truncate_keys(16);
+        khf.append(48);
+        recorder.observe(&khf);
+        khf.commit(thread_rng())?;
+        assert_eq!(
+            recorder.epochs[0],
+            vec![EpochOp::Truncate(16), EpochOp::Append(48)]
+        );
+
+        // The truncation doesn't carry over into the next epoch.
+        khf.append(4);
+        recorder.observe(&khf);
+        assert_eq!(recorder.epochs[1], vec![EpochOp::Append(4)]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use crate::aliases::{Key, Pos};
+#[cfg(feature = "mlock")]
+use crate::secure::{LockedRegion, LockedRegions};
+use khf_core::KeyCache;
+use std::{collections::HashMap, mem};
+use zeroize::Zeroize;
+
+/// The number of lookups between adjustments of an adaptive cache's capacity.
+const WINDOW: u64 = 1024;
+
+/// The hit rate (in percent) over a window above which a full adaptive cache grows.
+const HOT_HIT_RATE: u64 = 50;
+
+/// The hit rate (in percent) over a window below which an adaptive cache shrinks.
+const COLD_HIT_RATE: u64 = 5;
+
+/// A policy for sizing the cache of keys derived between commits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// The cache holds every key derived between commits.
+    #[default]
+    Unbounded,
+    /// The cache capacity adapts to the observed hit rate within a set of bounds, growing when
+    /// the cache is hot and shrinking when it is cold.
+    Adaptive { min: usize, max: usize },
+}
+
+/// Statistics about the cache of keys derived between commits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of lookups that hit the cache this epoch.
+    pub hits: u64,
+    /// The number of lookups that missed the cache this epoch.
+    pub misses: u64,
+    /// The number of cached keys.
+    pub len: usize,
+    /// The current capacity of the cache, or `None` if it's unbounded.
+    pub capacity: Option<usize>,
+}
+
+impl CacheStats {
+    /// The fraction of lookups that hit the cache, or 0 if there were no lookups.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// Holds keys of nodes computed between commits.
+pub struct Cache<const N: usize> {
+    // The slot holding the key of each cached position. Keys are kept out of the map so that the
+    // memory holding them can be locked.
+    keys: HashMap<Pos, usize>,
+    slots: Vec<Key<N>>,
+    // Slots of evicted keys, which are reused first.
+    free: Vec<usize>,
+    policy: CachePolicy,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+    window_hits: u64,
+    window_lookups: u64,
+    // The memory region holding the slots, if locked.
+    #[cfg(feature = "mlock")]
+    locked: LockedRegions,
+}
+
+impl<const N: usize> Clone for Cache<N> {
+    fn clone(&self) -> Self {
+        let mut cache = Self {
+            keys: self.keys.clone(),
+            slots: self.slots.clone(),
+            free: self.free.clone(),
+            policy: self.policy,
+            capacity: self.capacity,
+            hits: self.hits,
+            misses: self.misses,
+            window_hits: self.window_hits,
+            window_lookups: self.window_lookups,
+            #[cfg(feature = "mlock")]
+            locked: LockedRegions::default(),
+        };
+        cache.lock();
+        cache
+    }
+}
+
+impl<const N: usize> Default for Cache<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Cache<N> {
+    pub fn new() -> Self {
+        Self::with_policy(CachePolicy::default())
+    }
+
+    pub fn with_policy(policy: CachePolicy) -> Self {
+        let mut cache = Self {
+            keys: HashMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            policy,
+            capacity: 0,
+            hits: 0,
+            misses: 0,
+            window_hits: 0,
+            window_lookups: 0,
+            #[cfg(feature = "mlock")]
+            locked: LockedRegions::default(),
+        };
+        cache.set_policy(policy);
+        cache
+    }
+
+    pub fn policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: CachePolicy) {
+        self.policy = policy;
+        self.capacity = match policy {
+            CachePolicy::Unbounded => usize::MAX,
+            CachePolicy::Adaptive { min, max } => self.keys.len().clamp(min, max.max(min)),
+        };
+        self.evict();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            len: self.keys.len(),
+            capacity: (self.policy != CachePolicy::Unbounded).then_some(self.capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Estimates the heap memory held by the cache, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.keys.capacity() * mem::size_of::<(Pos, usize)>()
+            + self.slots.capacity() * mem::size_of::<Key<N>>()
+            + self.free.capacity() * mem::size_of::<usize>()
+    }
+
+    /// Shrinks the cache's storage to fit the cached keys.
+    pub fn shrink_to_fit(&mut self) {
+        self.keys.shrink_to_fit();
+        self.free.shrink_to_fit();
+        if self.free.is_empty() {
+            self.slots.shrink_to_fit();
+            self.lock();
+        }
+    }
+
+    /// Resets the hit and miss counters.
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// Looks up a key, recording whether it was a hit or miss.
+    pub fn get(&mut self, pos: &Pos) -> Option<Key<N>> {
+        let key = self.peek(pos);
+
+        if key.is_some() {
+            self.hits += 1;
+            self.window_hits += 1;
+        } else {
+            self.misses += 1;
+        }
+
+        self.window_lookups += 1;
+        if self.window_lookups == WINDOW {
+            self.adapt();
+        }
+
+        key
+    }
+
+    /// Looks up a key without recording it in the statistics.
+    pub fn peek(&self, pos: &Pos) -> Option<Key<N>> {
+        self.keys.get(pos).map(|slot| self.slots[*slot])
+    }
+
+    /// Caches a key if there's room for it.
+    pub fn insert(&mut self, pos: Pos, key: Key<N>) {
+        if let Some(slot) = self.keys.get(&pos) {
+            self.slots[*slot].zeroize();
+            self.slots[*slot] = key;
+        } else if self.keys.len() < self.capacity {
+            let slot = match self.free.pop() {
+                Some(slot) => {
+                    self.slots[slot] = key;
+                    slot
+                }
+                None => {
+                    self.slots.push(key);
+                    self.lock();
+                    self.slots.len() - 1
+                }
+            };
+            self.keys.insert(pos, slot);
+        }
+    }
+
+    /// Locks the memory holding the cached keys so that it isn't swapped to disk, returning `true`
+    /// on success. This happens automatically whenever that memory is reallocated.
+    #[cfg(feature = "mlock")]
+    pub fn lock(&mut self) -> bool {
+        self.locked.relock(vec![LockedRegion::of_vec(&self.slots)])
+    }
+
+    #[cfg(not(feature = "mlock"))]
+    fn lock(&mut self) {}
+
+    /// Zeroizes and removes every cached key.
+    pub fn clear(&mut self) {
+        self.slots.zeroize();
+        self.keys.clear();
+        self.free.clear();
+    }
+
+    // Grows or shrinks the capacity based on the hit rate over the last window.
+    fn adapt(&mut self) {
+        if let CachePolicy::Adaptive { min, max } = self.policy {
+            let hit_rate = self.window_hits * 100 / self.window_lookups;
+            if hit_rate >= HOT_HIT_RATE && self.keys.len() >= self.capacity {
+                self.capacity = self.capacity.saturating_mul(2).min(max).max(min);
+            } else if hit_rate < COLD_HIT_RATE {
+                self.capacity = (self.capacity / 2).max(min);
+                self.evict();
+            }
+        }
+
+        self.window_hits = 0;
+        self.window_lookups = 0;
+    }
+
+    // Evicts keys until the cache is within its capacity.
+    fn evict(&mut self) {
+        if self.keys.len() > self.capacity {
+            let excess = self.keys.len() - self.capacity;
+            let evicted: Vec<Pos> = self.keys.keys().take(excess).copied().collect();
+            for pos in evicted {
+                if let Some(slot) = self.keys.remove(&pos) {
+                    self.slots[slot].zeroize();
+                    self.free.push(slot);
+                }
+            }
+        }
+    }
+}
+
+impl<const N: usize> Drop for Cache<N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<const N: usize> KeyCache<N> for Cache<N> {
+    fn get(&self, pos: &Pos) -> Option<Key<N>> {
+        self.peek(pos)
+    }
+
+    fn insert(&mut self, pos: Pos, key: Key<N>) {
+        Cache::insert(self, pos, key)
+    }
+}
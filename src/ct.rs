@@ -0,0 +1,37 @@
+//! Constant-time helpers, and the side-channel posture of the crate.
+//!
+//! Deriving a key hashes its way down from a root, so its control flow and memory accesses depend
+//! only on the topology and the positions involved, never on key bytes. Root and cache lookups
+//! branch on positions and key IDs, which aren't secret. Wherever key material is compared (e.g.,
+//! verifying keys, cross-checking derivations, or verifying receipts), the comparison goes through
+//! `eq()`, which doesn't short-circuit. Text encodings of keys are constant-time as well (see
+//! `encoding`). The hashers themselves are out of the crate's control, so their side-channel
+//! resistance must be assessed separately.
+
+use std::hint::black_box;
+
+/// Compares two byte strings in time that depends only on their lengths, which aren't considered
+/// secret.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    // Accumulate the differences so the comparison doesn't short-circuit, and keep the compiler
+    // from reintroducing an early exit.
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare() {
+        assert!(eq(b"key", b"key"));
+        assert!(!eq(b"key", b"kez"));
+        assert!(!eq(b"key", b"keys"));
+        assert!(eq(b"", b""));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{aliases::Key, node::Node, topology::Topology};
+use hasher::Hasher;
+use serde::de::{DeserializeSeed, Deserializer, Error as _, SeqAccess, Visitor};
+use std::{collections::BTreeSet, fmt, marker::PhantomData, ops::Range};
+
+/// Incrementally computes the digest (commitment) of a `Khf`.
+pub(crate) struct Digest<H, const N: usize> {
+    hasher: H,
+}
+
+impl<H, const N: usize> Digest<H, N>
+where
+    H: Hasher<N>,
+{
+    pub fn new() -> Self {
+        Self { hasher: H::new() }
+    }
+
+    pub fn topology(&mut self, topology: &Topology) {
+        for level in 0..topology.height() {
+            self.u64(topology.descendants(level));
+        }
+    }
+
+    pub fn node(&mut self, node: &Node<H, N>) {
+        self.u64(node.pos.0);
+        self.u64(node.pos.1);
+        self.hasher.update(&node.key);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.hasher.update(&n.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Key<N> {
+        self.hasher.finish()
+    }
+}
+
+/// Computes the digest of a serialized `Khf` without materializing its root list.
+///
+/// The fields visited here must match the order of the serialized fields of `Khf`.
+pub(crate) struct PersistedDigest<H, const N: usize> {
+    pd: PhantomData<H>,
+}
+
+impl<H, const N: usize> PersistedDigest<H, N> {
+    pub fn new() -> Self {
+        Self { pd: PhantomData }
+    }
+}
+
+impl<'de, H, const N: usize> DeserializeSeed<'de> for PersistedDigest<H, N>
+where
+    H: Hasher<N>,
+{
+    type Value = Key<N>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        const FIELDS: &[&str] = &[
+            "topology",
+            "appending_root",
+            "roots",
+            "keys",
+            "epoch",
+            "deleted",
+            "leases",
+            "history",
+        ];
+        deserializer.deserialize_struct("Khf", FIELDS, self)
+    }
+}
+
+impl<'de, H, const N: usize> Visitor<'de> for PersistedDigest<H, N>
+where
+    H: Hasher<N>,
+{
+    type Value = Key<N>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a persisted Khf")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut digest = Digest::<H, N>::new();
+
+        let topology: Topology = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        digest.topology(&topology);
+
+        let appending_root: Node<H, N> = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+        digest.node(&appending_root);
+
+        seq.next_element_seed(RootsDigest {
+            digest: &mut digest,
+        })?
+        .ok_or_else(|| A::Error::invalid_length(2, &self))?;
+
+        let keys: u64 = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(3, &self))?;
+        digest.u64(keys);
+
+        let epoch: u64 = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(4, &self))?;
+        digest.u64(epoch);
+
+        let deleted: BTreeSet<u64> = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(5, &self))?;
+        digest.u64(deleted.len() as u64);
+        for key in deleted {
+            digest.u64(key);
+        }
+
+        let leases: Vec<(Range<u64>, u64)> = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(6, &self))?;
+        digest.u64(leases.len() as u64);
+        for (range, expires) in leases {
+            digest.u64(range.start);
+            digest.u64(range.end);
+            digest.u64(expires);
+        }
+
+        let history: Vec<(u64, u64, Vec<Node<H, N>>)> = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(7, &self))?;
+        for (epoch, keys, roots) in history {
+            digest.u64(epoch);
+            digest.u64(keys);
+            digest.u64(roots.len() as u64);
+            for root in &roots {
+                digest.node(root);
+            }
+        }
+
+        Ok(digest.finish())
+    }
+}
+
+/// Feeds a serialized root list into a digest one root at a time.
+struct RootsDigest<'a, H, const N: usize> {
+    digest: &'a mut Digest<H, N>,
+}
+
+impl<'a, 'de, H, const N: usize> DeserializeSeed<'de> for RootsDigest<'a, H, N>
+where
+    H: Hasher<N>,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'a, 'de, H, const N: usize> Visitor<'de> for RootsDigest<'a, H, N>
+where
+    H: Hasher<N>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of roots")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while let Some(root) = seq.next_element::<Node<H, N>>()? {
+            self.digest.node(&root);
+        }
+        Ok(())
+    }
+}
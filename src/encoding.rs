@@ -0,0 +1,154 @@
+//! Encodings for rendering keys and digests as text.
+//!
+//! Decoding runs in constant time with respect to the encoded bytes, and every buffer holding
+//! key material is zeroized when dropped.
+
+use crate::error::Error;
+use zeroize::Zeroizing;
+
+/// Encodes bytes as lowercase hex.
+pub fn encode_hex(bytes: &[u8]) -> Zeroizing<String> {
+    let mut encoded = Zeroizing::new(String::with_capacity(bytes.len() * 2));
+    for byte in bytes {
+        encoded.push(encode_nibble(byte >> 4) as char);
+        encoded.push(encode_nibble(byte & 0xf) as char);
+    }
+    encoded
+}
+
+/// Decodes hex, accepting either case.
+pub fn decode_hex(encoded: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 2 != 0 {
+        return Err(Error::InvalidEncoding);
+    }
+
+    let mut decoded = Zeroizing::new(Vec::with_capacity(encoded.len() / 2));
+    let mut invalid = 0;
+    for pair in encoded.chunks(2) {
+        let (hi, lo) = (decode_nibble(pair[0]), decode_nibble(pair[1]));
+        invalid |= hi | lo;
+        decoded.push(((hi << 4) | (lo & 0xf)) as u8);
+    }
+
+    if invalid < 0 {
+        return Err(Error::InvalidEncoding);
+    }
+    Ok(decoded)
+}
+
+/// Encodes bytes as padded base64 with the standard alphabet.
+pub fn encode_base64(bytes: &[u8]) -> Zeroizing<String> {
+    let mut encoded = Zeroizing::new(String::with_capacity(bytes.len().div_ceil(3) * 4));
+    for chunk in bytes.chunks(3) {
+        let mut group = [0; 4];
+        group[1..=chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes(group);
+
+        for i in 0..=chunk.len() {
+            encoded.push(encode_sextet((bits >> (18 - 6 * i)) as u8 & 0x3f) as char);
+        }
+        for _ in chunk.len()..3 {
+            encoded.push('=');
+        }
+    }
+    encoded
+}
+
+/// Decodes padded base64 with the standard alphabet.
+pub fn decode_base64(encoded: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 4 != 0 {
+        return Err(Error::InvalidEncoding);
+    }
+
+    // The amount of padding only reveals the length of the decoded bytes.
+    let unpadded = encoded
+        .strip_suffix(b"==")
+        .or_else(|| encoded.strip_suffix(b"="))
+        .unwrap_or(encoded);
+
+    let mut decoded = Zeroizing::new(Vec::with_capacity(unpadded.len() * 3 / 4));
+    let mut invalid = 0;
+    for chunk in unpadded.chunks(4) {
+        let mut bits = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            let sextet = decode_sextet(*c);
+            invalid |= sextet;
+            bits |= (sextet as u32 & 0x3f) << (18 - 6 * i);
+        }
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+
+    if invalid < 0 {
+        return Err(Error::InvalidEncoding);
+    }
+    Ok(decoded)
+}
+
+// Encodes a nibble as a lowercase hex digit without branching on it.
+fn encode_nibble(nibble: u8) -> u8 {
+    let nibble = nibble as i16;
+    (nibble + 0x30 + (((9 - nibble) >> 8) & (0x61 - 0x3a))) as u8
+}
+
+// Decodes a hex digit without branching on it, returning -1 if it's invalid.
+fn decode_nibble(c: u8) -> i16 {
+    let c = c as i16;
+    let mut nibble = -1;
+    nibble += (((0x2f - c) & (c - 0x3a)) >> 8) & (c - 0x2f);
+    nibble += (((0x40 - c) & (c - 0x47)) >> 8) & (c - 0x36);
+    nibble += (((0x60 - c) & (c - 0x67)) >> 8) & (c - 0x56);
+    nibble
+}
+
+// Encodes six bits as a base64 character without branching on them.
+fn encode_sextet(sextet: u8) -> u8 {
+    let sextet = sextet as i16;
+    let mut c = sextet + 0x41;
+    c += ((25 - sextet) >> 8) & 6;
+    c += ((51 - sextet) >> 8) & -75;
+    c += ((61 - sextet) >> 8) & -15;
+    c += ((62 - sextet) >> 8) & 3;
+    c as u8
+}
+
+// Decodes a base64 character without branching on it, returning -1 if it's invalid.
+fn decode_sextet(c: u8) -> i16 {
+    let c = c as i16;
+    let mut sextet = -1;
+    sextet += (((0x40 - c) & (c - 0x5b)) >> 8) & (c - 0x40);
+    sextet += (((0x60 - c) & (c - 0x7b)) >> 8) & (c - 0x46);
+    sextet += (((0x2f - c) & (c - 0x3a)) >> 8) & (c + 5);
+    sextet += (((0x2a - c) & (c - 0x2c)) >> 8) & 63;
+    sextet += (((0x2e - c) & (c - 0x30)) >> 8) & 64;
+    sextet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            let bytes = &bytes[..len];
+            assert_eq!(*decode_hex(&encode_hex(bytes))?, bytes);
+            assert_eq!(*decode_base64(&encode_base64(bytes))?, bytes);
+        }
+
+        assert_eq!(*encode_hex(&[0x01, 0xab, 0xff]), "01abff");
+        assert_eq!(*decode_hex("01ABff")?, [0x01, 0xab, 0xff]);
+        assert_eq!(*encode_base64(b"khf"), "a2hm");
+        assert_eq!(*encode_base64(&[0xfb, 0xff]), "+/8=");
+
+        assert!(decode_hex("0g").is_err());
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_base64("a2h").is_err());
+        assert!(decode_base64("a2h*").is_err());
+
+        Ok(())
+    }
+}
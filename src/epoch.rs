@@ -0,0 +1,274 @@
+use crate::{
+    aliases::Key,
+    error::Error,
+    khf::{Consolidation, EpochOp, Khf, Receipt},
+    lease::Lease,
+    overlay::Overlay,
+    report::Report,
+};
+use hasher::Hasher;
+use kms::KeyManagementScheme;
+use rand::{CryptoRng, RngCore};
+use std::ops::Range;
+
+/// A guard over the open epoch of a `Khf`, obtained with `Khf::open_epoch()`, through which the
+/// epoch's operations are performed in a well-defined order. Committing consumes the guard, and
+/// truncating turns it into a `ClosingEpoch` that can only be committed, so hazards like deriving a
+/// key after it was truncated in the same epoch don't compile.
+///
+/// The `KeyManagementScheme` implementation of `Khf` remains available for existing callers.
+pub struct OpenEpoch<'a, H, const N: usize> {
+    khf: &'a mut Khf<H, N>,
+}
+
+/// An epoch of a `Khf` that was closed, e.g., by truncating it, which can only be committed.
+pub struct ClosingEpoch<'a, H, const N: usize> {
+    khf: &'a mut Khf<H, N>,
+}
+
+impl<'a, H, const N: usize> OpenEpoch<'a, H, N>
+where
+    H: Hasher<N>,
+{
+    pub(crate) fn new(khf: &'a mut Khf<H, N>) -> Self {
+        Self { khf }
+    }
+
+    /// Returns the `Khf` the epoch belongs to.
+    pub fn khf(&self) -> &Khf<H, N> {
+        self.khf
+    }
+
+    /// Derives a key.
+    pub fn derive(&mut self, key: u64) -> Result<Key<N>, Error> {
+        self.khf.derive(key)
+    }
+
+    /// Updates a key, returning its current value.
+    pub fn update(&mut self, key: u64) -> Result<Key<N>, Error> {
+        self.khf.update(key)
+    }
+
+    /// Deletes a key.
+    pub fn delete(&mut self, key: u64) -> Result<(), Error> {
+        self.khf.delete(key)
+    }
+
+    /// Appends a number of keys, returning their IDs.
+    pub fn append(&mut self, n: u64) -> Range<u64> {
+        self.khf.append(n)
+    }
+
+    /// Consolidates the `Khf` and returns the affected keys. Pinned keys are never affected.
+    pub fn consolidate(
+        &mut self,
+        mechanism: Consolidation,
+        rng: impl RngCore + CryptoRng,
+    ) -> Vec<u64> {
+        self.khf.consolidate_keys(mechanism, rng)
+    }
+
+    /// Consolidates the `Khf` like `consolidate()`, but reports the affected keys compactly.
+    pub fn consolidate_report(
+        &mut self,
+        mechanism: Consolidation,
+        rng: impl RngCore + CryptoRng,
+    ) -> Report {
+        self.khf.consolidate_report(mechanism, rng)
+    }
+
+    /// Fragments the roots covering a range of keys into roots of a given level.
+    ///
+    /// Panics if `level` isn't between 1 and the height of the `Khf`'s topology (exclusive).
+    pub fn fragment(&mut self, range: Range<u64>, level: u64) {
+        self.khf.fragment(range, level)
+    }
+
+    /// Pins a range of keys so that consolidation leaves their roots untouched.
+    pub fn pin(&mut self, range: Range<u64>) {
+        self.khf.pin(range)
+    }
+
+    /// Unpins a range of keys, allowing consolidation to replace their roots again.
+    pub fn unpin(&mut self, range: Range<u64>) {
+        self.khf.unpin(range)
+    }
+
+    /// Regenerates the appending root with a new RNG, returning `true` if it was regenerated.
+    pub fn reseed(&mut self, rng: impl RngCore + CryptoRng) -> bool {
+        self.khf.reseed(rng)
+    }
+
+    /// Leases a range of keys for a number of epochs, erroring if the range extends past the keys
+    /// in flight.
+    pub fn lease(&mut self, range: Range<u64>, ttl_epochs: u64) -> Result<Lease<H, N>, Error> {
+        self.khf.lease(range, ttl_epochs)
+    }
+
+    /// Transfers a range of committed keys to another `Khf`, returning a receipt.
+    pub fn transfer(
+        &mut self,
+        range: Range<u64>,
+        other: &mut Khf<H, N>,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Receipt<N>, Error> {
+        self.khf.transfer(range, other, rng)
+    }
+
+    /// Appends the keyspace of another `Khf`, returning the IDs its keys are remapped to.
+    pub fn merge(&mut self, other: Khf<H, N>) -> Result<Range<u64>, Error> {
+        self.khf.merge(other)
+    }
+
+    /// Splits off the keys from `at` onwards into a new `Khf`, leaving the keys before `at`.
+    pub fn split_off(
+        &mut self,
+        at: u64,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Khf<H, N>, Error> {
+        let (left, right) = self.khf.clone().split(at, rng)?;
+        *self.khf = left;
+        Ok(right)
+    }
+
+    /// Applies the changes recorded by an overlay.
+    pub(crate) fn merge_overlay(&mut self, overlay: Overlay) -> Result<(), Error> {
+        self.khf.merge_overlay(overlay)
+    }
+
+    /// Truncates the `Khf` to a number of keys, closing the epoch to any further operations.
+    pub fn truncate(self, keys: u64) -> ClosingEpoch<'a, H, N> {
+        self.khf.truncate_keys(keys);
+        self.close()
+    }
+
+    /// Truncates the `Khf` like `truncate()`, but errors if it doesn't currently cover the
+    /// specified number of keys. Also returns the keys that will be dropped at the commit.
+    pub fn try_truncate(self, keys: u64) -> Result<(ClosingEpoch<'a, H, N>, Vec<u64>), Error> {
+        let dropped = self.khf.try_truncate(keys)?;
+        Ok((self.close(), dropped))
+    }
+
+    /// Closes the epoch to any further operations, e.g., to commit it with a `ClosingEpoch`
+    /// method.
+    pub fn close(self) -> ClosingEpoch<'a, H, N> {
+        ClosingEpoch { khf: self.khf }
+    }
+
+    /// Commits the epoch, returning the updated keys.
+    pub fn commit(self, rng: impl RngCore + CryptoRng) -> Result<Vec<(u64, Key<N>)>, Error> {
+        self.khf.commit(rng)
+    }
+
+    /// Rotates every key by updating them all and committing, returning their pre-rotation
+    /// values.
+    pub fn rekey_all(self, rng: impl RngCore + CryptoRng) -> Result<Vec<(u64, Key<N>)>, Error> {
+        self.khf.rekey_all(rng)
+    }
+
+    /// Replays a journal of epochs as a single commit, returning the keys revoked in each epoch.
+    pub fn replay_epochs<E, I>(
+        self,
+        epochs: E,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<Vec<u64>>, Error>
+    where
+        E: IntoIterator<Item = I>,
+        I: IntoIterator<Item = EpochOp>,
+    {
+        self.khf.replay_epochs(epochs, rng)
+    }
+}
+
+impl<'a, H, const N: usize> ClosingEpoch<'a, H, N>
+where
+    H: Hasher<N>,
+{
+    /// Returns the `Khf` the epoch belongs to.
+    pub fn khf(&self) -> &Khf<H, N> {
+        self.khf
+    }
+
+    /// Commits the epoch, returning the updated keys.
+    pub fn commit(self, rng: impl RngCore + CryptoRng) -> Result<Vec<(u64, Key<N>)>, Error> {
+        self.khf.commit(rng)
+    }
+
+    /// Commits the epoch, invoking a callback with each updated key and its pre-commit value.
+    pub fn commit_with<F>(self, rng: impl RngCore + CryptoRng, f: F) -> Result<(), Error>
+    where
+        F: FnMut(u64, Key<N>),
+    {
+        self.khf.commit_with(rng, f)
+    }
+
+    /// Commits the epoch, returning each updated key with its pre-commit and post-commit values.
+    pub fn commit_full(
+        self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(u64, Key<N>, Key<N>)>, Error> {
+        self.khf.commit_full(rng)
+    }
+
+    /// Commits the epoch, reporting the revoked keys compactly.
+    pub fn commit_report(self, rng: impl RngCore + CryptoRng) -> Result<Report, Error> {
+        self.khf.commit_report(rng)
+    }
+
+    /// Commits the epoch like `commit()`, but returns `Ok(None)` and leaves the epoch open if the
+    /// entropy source fails.
+    pub fn try_commit(
+        self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Option<Vec<(u64, Key<N>)>>, Error> {
+        self.khf.try_commit(rng)
+    }
+
+    /// Commits the epoch of a deterministic `Khf`, deriving the next roots from the master secret.
+    pub fn commit_with_master(self, master: &Key<N>) -> Result<Vec<(u64, Key<N>)>, Error> {
+        self.khf.commit_with_master(master)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use rand::prelude::*;
+
+    #[test]
+    fn lifecycle() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+
+        let mut epoch = khf.open_epoch();
+        assert_eq!(epoch.append(8), 0..8);
+        let key = epoch.update(3)?;
+        assert_eq!(epoch.commit(thread_rng())?, vec![(3, key)]);
+
+        let mut epoch = khf.open_epoch();
+        epoch.update(6)?;
+        let epoch = epoch.truncate(4);
+        assert_eq!(epoch.khf().in_flight_len(), 4);
+        assert!(epoch.commit(thread_rng())?.is_empty());
+        assert_eq!(khf.len(), 4);
+
+        assert!(khf.open_epoch().try_truncate(5).is_err());
+        let (epoch, dropped) = khf.open_epoch().try_truncate(2)?;
+        assert_eq!(dropped, vec![2, 3]);
+        epoch.commit(thread_rng())?;
+        assert_eq!(khf.len(), 2);
+
+        // Every mutation goes through the guard, including whole-forest ones.
+        let mut epoch = khf.open_epoch();
+        epoch.append(6);
+        let other = epoch.split_off(4, thread_rng())?;
+        assert_eq!(epoch.khf().in_flight_len(), 4);
+        assert_eq!(epoch.merge(other)?, 4..12);
+        epoch.update(1)?;
+        let report = epoch.close().commit_report(thread_rng())?;
+        assert_eq!(report.affected().collect::<Vec<_>>(), [1]);
+
+        Ok(())
+    }
+}
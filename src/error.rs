@@ -1,6 +1,8 @@
+use crate::khf2d::Layout;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("io error")]
     Io,
@@ -8,6 +10,60 @@ pub enum Error {
     #[error(transparent)]
     Serde(#[from] bincode::Error),
 
+    #[error("key {0} is out of range")]
+    OutOfRange(u64),
+
+    #[error("key {key} is out of range ({max} keys)")]
+    KeyOutOfRange { key: u64, max: u64 },
+
+    #[error("id {id:?} doesn't fit in {layout:?}")]
+    IdOutOfRange { id: (u64, u64), layout: Layout },
+
+    #[error("key {0} is deleted")]
+    Deleted(u64),
+
+    #[error("key {0} is truncated")]
+    Truncated(u64),
+
+    #[error("no root covers key {0}")]
+    NoCoverage(u64),
+
+    #[error("invalid topology")]
+    InvalidTopology,
+
+    #[error("invalid encoding")]
+    InvalidEncoding,
+
+    #[error("quota exceeded deriving key {0}")]
+    QuotaExceeded(u64),
+
+    #[error("cross-checked derivation of key {0} mismatched")]
+    CrossCheckMismatch(u64),
+
+    #[error("epoch {0} is unavailable")]
+    UnavailableEpoch(u64),
+
+    #[error("overlay of epoch {0} is stale")]
+    StaleOverlay(u64),
+
+    #[error("incompatible topology")]
+    IncompatibleTopology,
+
+    #[error("invalid delegation")]
+    InvalidDelegation,
+
+    #[error("khf isn't consolidated")]
+    NotConsolidated,
+
+    #[error("khf is read-only")]
+    ReadOnly,
+
+    #[error("persisted record is corrupt")]
+    Corrupt,
+
+    #[error("invalid fixture: {0}")]
+    InvalidFixture(String),
+
     #[error("unknown error")]
     Unknown,
 }
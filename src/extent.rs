@@ -0,0 +1,215 @@
+use crate::{aliases::Key, error::Error, khf::Khf, map::deserialize_khf};
+use hasher::Hasher;
+use kms::KeyManagementScheme;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+};
+
+/// A range of an address space mapped to a single key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    /// The first offset of the extent.
+    pub start: u64,
+    /// The offset just past the end of the extent.
+    pub end: u64,
+    /// The ID of the key the extent is mapped to.
+    pub key: u64,
+}
+
+/// Maps variable-length extents of an address space (e.g., the blocks of a file) to keys of a
+/// `Khf`, so that each extent needs one key rather than one key per block.
+///
+/// Writing to part of an extent splits it, mapping the written range to a fresh key, and writing
+/// over several extents merges them into one. Keys that no longer map to any extent are updated,
+/// and are reused for new extents once the update is committed. The extents are persisted along
+/// with the `Khf` when the `ExtentMap` is serialized.
+#[derive(Deserialize, Serialize)]
+#[serde(bound(serialize = "Khf<H, N>: Serialize"))]
+#[serde(bound(deserialize = "H: Hasher<N>, Khf<H, N>: Deserialize<'de>"))]
+pub struct ExtentMap<H, const N: usize> {
+    #[serde(deserialize_with = "deserialize_khf")]
+    khf: Khf<H, N>,
+    // Maps the start of each extent to its end and key.
+    extents: BTreeMap<u64, (u64, u64)>,
+    // The number of extents mapped to each key.
+    refs: HashMap<u64, usize>,
+    // Keys released since the last commit.
+    released: Vec<u64>,
+    // Keys that have been rotated since they were released, so they can be reused.
+    free: Vec<u64>,
+}
+
+impl<H, const N: usize> ExtentMap<H, N>
+where
+    H: Hasher<N>,
+{
+    /// Constructs a new, empty `ExtentMap` allocating keys from a `Khf`.
+    pub fn new(khf: Khf<H, N>) -> Self {
+        Self {
+            khf,
+            extents: BTreeMap::new(),
+            refs: HashMap::new(),
+            released: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Returns the `Khf` that keys are allocated from.
+    pub fn khf(&self) -> &Khf<H, N> {
+        &self.khf
+    }
+
+    /// Returns the extents in order of their offsets.
+    pub fn extents(&self) -> impl Iterator<Item = Extent> + '_ {
+        self.extents.iter().map(|(start, (end, key))| Extent {
+            start: *start,
+            end: *end,
+            key: *key,
+        })
+    }
+
+    /// Returns the extent covering an offset, if there is one.
+    pub fn extent(&self, offset: u64) -> Option<Extent> {
+        self.extents
+            .range(..=offset)
+            .next_back()
+            .filter(|(_, (end, _))| offset < *end)
+            .map(|(start, (end, key))| Extent {
+                start: *start,
+                end: *end,
+                key: *key,
+            })
+    }
+
+    /// Derives the key of the extent covering an offset, if there is one.
+    pub fn derive(&mut self, offset: u64) -> Result<Option<(Extent, Key<N>)>, Error> {
+        match self.extent(offset) {
+            Some(extent) => Ok(Some((extent, self.khf.derive(extent.key)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Maps a range to a fresh key, splitting or merging the extents it overlaps, and returns the
+    /// new extent along with its key.
+    ///
+    /// Panics if the range is empty.
+    pub fn write(&mut self, range: Range<u64>) -> Result<(Extent, Key<N>), Error> {
+        assert!(!range.is_empty(), "cannot map an empty range");
+        self.remove(range.clone())?;
+
+        let key = match self.free.pop() {
+            Some(key) => key,
+            None => self.khf.append(1).start,
+        };
+        self.extents.insert(range.start, (range.end, key));
+        *self.refs.entry(key).or_default() += 1;
+
+        let extent = Extent {
+            start: range.start,
+            end: range.end,
+            key,
+        };
+        Ok((extent, self.khf.derive(key)?))
+    }
+
+    /// Unmaps a range, splitting the extents at its edges.
+    pub fn remove(&mut self, range: Range<u64>) -> Result<(), Error> {
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        self.split_at(range.start);
+        self.split_at(range.end);
+
+        let starts: Vec<u64> = self.extents.range(range).map(|(start, _)| *start).collect();
+        for start in starts {
+            let (_, key) = self.extents.remove(&start).unwrap();
+            self.release(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits the underlying `Khf`, returning the keys that were updated along with their
+    /// pre-commit values.
+    pub fn commit(&mut self, rng: impl RngCore + CryptoRng) -> Result<Vec<(u64, Key<N>)>, Error> {
+        let updated = self.khf.commit(rng)?;
+        self.free.append(&mut self.released);
+        Ok(updated)
+    }
+
+    // Splits the extent covering an offset so that an extent starts at it.
+    fn split_at(&mut self, offset: u64) {
+        if let Some(extent) = self.extent(offset).filter(|extent| extent.start < offset) {
+            self.extents.insert(extent.start, (offset, extent.key));
+            self.extents.insert(offset, (extent.end, extent.key));
+            *self.refs.entry(extent.key).or_default() += 1;
+        }
+    }
+
+    // Drops a reference to a key, updating it once no extents are mapped to it.
+    fn release(&mut self, key: u64) -> Result<(), Error> {
+        let refs = self.refs.get_mut(&key).unwrap();
+        *refs -= 1;
+        if *refs == 0 {
+            self.refs.remove(&key);
+            self.khf.update(key)?;
+            self.released.push(key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use rand::prelude::*;
+
+    #[test]
+    fn split_and_merge() -> Result<()> {
+        let mut rng = thread_rng();
+        let khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        let mut map = ExtentMap::new(khf);
+
+        let (whole, key) = map.write(0..4096)?;
+        assert_eq!(map.derive(100)?, Some((whole, key)));
+        assert_eq!(map.derive(4096)?, None);
+
+        // Writing into the middle of an extent splits it.
+        let (middle, middle_key) = map.write(1024..2048)?;
+        assert_ne!(middle.key, whole.key);
+        assert_ne!(middle_key, key);
+        assert_eq!(map.extents().count(), 3);
+        assert_eq!(map.derive(0)?.unwrap().1, key);
+        assert_eq!(map.derive(3000)?.unwrap().1, key);
+
+        // Writing over every extent merges them, releasing the old keys.
+        let (merged, _) = map.write(0..4096)?;
+        assert_eq!(map.extents().collect::<Vec<_>>(), vec![merged]);
+        let updated = map.commit(&mut rng)?;
+        assert_eq!(updated.len(), 2);
+
+        // Released keys are reused once they've been rotated.
+        let (reused, reused_key) = map.write(8192..8200)?;
+        assert!(reused.key == whole.key || reused.key == middle.key);
+        assert_ne!(reused_key, key);
+        assert_ne!(reused_key, middle_key);
+
+        // The extents survive persistence, and new extents get fresh keys.
+        map.commit(&mut rng)?;
+        let extents: Vec<_> = map.extents().collect();
+        let mut map: ExtentMap<Sha3_256, SHA3_256_MD_SIZE> =
+            bincode::deserialize(&bincode::serialize(&map)?)?;
+        assert_eq!(map.extents().collect::<Vec<_>>(), extents);
+        assert_eq!(map.derive(8192)?, Some((reused, reused_key)));
+        let (fresh, _) = map.write(16384..16400)?;
+        assert!(extents.iter().all(|extent| extent.key != fresh.key));
+
+        Ok(())
+    }
+}
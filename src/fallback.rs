@@ -0,0 +1,140 @@
+use hasher::Hasher;
+use std::sync::OnceLock;
+
+/// A hasher that may or may not be usable on the running machine (e.g., one that relies on SHA
+/// extensions or a hardware-accelerated library).
+pub trait Accelerated<const N: usize>: Hasher<N> {
+    /// Returns `true` if the hasher can be used on the running machine. `Fallback` hashers only
+    /// probe once, caching the result in `availability()`.
+    fn probe() -> bool;
+
+    /// Returns where `Fallback` hashers cache the result of `probe()`. This should be a `static`
+    /// specific to the implementation, which rules out one declared in a generic implementation.
+    fn availability() -> &'static OnceLock<bool>;
+}
+
+/// A hasher that prefers an accelerated hasher `A`, falling back to a portable hasher `F` when `A`
+/// isn't available at runtime. Both hashers must compute the same hash function, so keys derived
+/// by a `Khf` using a `Fallback` hasher don't depend on the machine they're derived on.
+pub enum Fallback<A, F> {
+    Accelerated(A),
+    Fallback(F),
+}
+
+impl<A, F> Fallback<A, F> {
+    /// Returns `true` if the accelerated hasher is in use.
+    pub fn is_accelerated(&self) -> bool {
+        matches!(self, Self::Accelerated(_))
+    }
+}
+
+impl<A, F, const N: usize> Hasher<N> for Fallback<A, F>
+where
+    A: Accelerated<N>,
+    F: Hasher<N>,
+{
+    fn new() -> Self {
+        if *A::availability().get_or_init(A::probe) {
+            Self::Accelerated(A::new())
+        } else {
+            Self::Fallback(F::new())
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Accelerated(hasher) => hasher.update(data),
+            Self::Fallback(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> [u8; N] {
+        match self {
+            Self::Accelerated(hasher) => hasher.finish(),
+            Self::Fallback(hasher) => hasher.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::khf::Khf;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use kms::KeyManagementScheme;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const N: usize = SHA3_256_MD_SIZE;
+
+    // Stand-ins for accelerated hashers, counting how often they're probed.
+    struct Available(Sha3_256);
+    struct Unavailable(Sha3_256);
+
+    static PROBES: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+    macro_rules! stand_in {
+        ($name:ident, $available:literal, $probes:expr) => {
+            impl Hasher<N> for $name {
+                fn new() -> Self {
+                    assert!($available, "unavailable hasher constructed");
+                    Self(Sha3_256::new())
+                }
+
+                fn update(&mut self, data: &[u8]) {
+                    self.0.update(data)
+                }
+
+                fn finish(self) -> [u8; N] {
+                    self.0.finish()
+                }
+            }
+
+            impl Accelerated<N> for $name {
+                fn probe() -> bool {
+                    $probes.fetch_add(1, Ordering::Relaxed);
+                    $available
+                }
+
+                fn availability() -> &'static OnceLock<bool> {
+                    static AVAILABILITY: OnceLock<bool> = OnceLock::new();
+                    &AVAILABILITY
+                }
+            }
+        };
+    }
+
+    stand_in!(Available, true, PROBES[0]);
+    stand_in!(Unavailable, false, PROBES[1]);
+
+    // Derives some keys from a fixed master key.
+    fn derive<H: Hasher<N>>() -> Result<Vec<[u8; N]>> {
+        let mut khf = Khf::<H, N>::from_master(&[4, 4], &[7; N]);
+        Ok((0..40)
+            .map(|key| khf.derive(key))
+            .collect::<Result<_, _>>()?)
+    }
+
+    #[test]
+    fn selection() -> Result<()> {
+        assert!(Fallback::<Available, Sha3_256>::new().is_accelerated());
+        assert!(!Fallback::<Unavailable, Sha3_256>::new().is_accelerated());
+
+        // Either branch derives the same keys, and availability is only probed once.
+        let keys = derive::<Sha3_256>()?;
+        assert_eq!(derive::<Fallback<Available, Sha3_256>>()?, keys);
+        assert_eq!(derive::<Fallback<Unavailable, Sha3_256>>()?, keys);
+        assert!(PROBES
+            .iter()
+            .all(|probes| probes.load(Ordering::Relaxed) == 1));
+
+        #[cfg(feature = "openssl")]
+        {
+            use crate::hashers::OpensslSha3_256;
+            assert!(Fallback::<OpensslSha3_256, Sha3_256>::new().is_accelerated());
+            assert_eq!(derive::<Fallback<OpensslSha3_256, Sha3_256>>()?, keys);
+        }
+
+        Ok(())
+    }
+}
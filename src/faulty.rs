@@ -0,0 +1,167 @@
+use crate::{error::Error, khf::Khf};
+use bincode::Options;
+use hasher::Hasher;
+use std::collections::VecDeque;
+
+/// A fault to inject into a persist to a `FaultyStore`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The store is truncated, but only the first `n` bytes of the new record are written.
+    PartialWrite(usize),
+    /// Only the first `n` bytes of the new record are written over the previous record, leaving a
+    /// record torn between the two.
+    TornRecord(usize),
+    /// The persist fails without touching the store.
+    Io,
+}
+
+/// An in-memory store for a persisted `Khf` that injects faults into persists and loads, for
+/// testing crash consistency.
+///
+/// A record holds a serialized `Khf` followed by its digest, so that a load can tell a partial or
+/// torn record apart from a consistent one.
+#[derive(Clone, Debug, Default)]
+pub struct FaultyStore {
+    record: Vec<u8>,
+    faults: VecDeque<Fault>,
+    failing_loads: usize,
+}
+
+impl FaultyStore {
+    /// Constructs a new, empty `FaultyStore`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a fault to inject into an upcoming persist. Faults are injected in the order they
+    /// were queued, one per persist.
+    pub fn inject(&mut self, fault: Fault) {
+        self.faults.push_back(fault);
+    }
+
+    /// Makes the next `count` loads fail with an IO error.
+    pub fn fail_loads(&mut self, count: usize) {
+        self.failing_loads += count;
+    }
+
+    /// Returns the raw bytes of the stored record.
+    pub fn record(&self) -> &[u8] {
+        &self.record
+    }
+
+    /// Persists a `Khf`, replacing the stored record. Returns an IO error if a fault was injected.
+    pub fn persist<H, const N: usize>(&mut self, khf: &Khf<H, N>) -> Result<(), Error>
+    where
+        H: Hasher<N>,
+    {
+        let mut record = bincode::serialize(khf)?;
+        record.extend_from_slice(&khf.digest());
+
+        match self.faults.pop_front() {
+            None => {
+                self.record = record;
+                return Ok(());
+            }
+            Some(Fault::PartialWrite(n)) => {
+                record.truncate(n);
+                self.record = record;
+            }
+            Some(Fault::TornRecord(n)) if n >= record.len() => {
+                self.record = record;
+            }
+            Some(Fault::TornRecord(n)) => {
+                if self.record.len() < n {
+                    self.record.resize(n, 0);
+                }
+                self.record[..n].copy_from_slice(&record[..n]);
+            }
+            Some(Fault::Io) => {}
+        }
+
+        Err(Error::Io)
+    }
+
+    /// Loads the persisted `Khf`, failing if the stored record is partial or torn.
+    pub fn load<H, const N: usize>(&mut self) -> Result<Khf<H, N>, Error>
+    where
+        H: Hasher<N>,
+    {
+        if self.failing_loads > 0 {
+            self.failing_loads -= 1;
+            return Err(Error::Io);
+        }
+
+        let split = self.record.len().checked_sub(N).ok_or(Error::Corrupt)?;
+        let (persisted, digest) = self.record.split_at(split);
+
+        // Trailing bytes are rejected, since they can only be left by a torn record.
+        let mut khf: Khf<H, N> = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .deserialize(persisted)
+            .map_err(|_| Error::Corrupt)?;
+        if khf.digest()[..] != digest[..] {
+            return Err(Error::Corrupt);
+        }
+
+        khf.lock_keys();
+        Ok(khf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use kms::KeyManagementScheme;
+    use rand::prelude::*;
+
+    type Khf256 = Khf<Sha3_256, SHA3_256_MD_SIZE>;
+
+    #[test]
+    fn crash_consistency() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf256::new(&[4, 4, 4, 4], &mut rng);
+        let mut store = FaultyStore::new();
+        store.persist(&khf)?;
+
+        for _ in 0..200 {
+            let pre = khf.digest();
+            for _ in 0..rng.gen_range(1..10) {
+                khf.update(rng.gen_range(0..500))?;
+            }
+            khf.commit(&mut rng)?;
+            let post = khf.digest();
+
+            let len = bincode::serialized_size(&khf)? as usize + SHA3_256_MD_SIZE;
+            let fault = match rng.gen_range(0..4) {
+                0 => Some(Fault::PartialWrite(rng.gen_range(0..=len))),
+                1 => Some(Fault::TornRecord(rng.gen_range(0..=len))),
+                2 => Some(Fault::Io),
+                _ => None,
+            };
+            if let Some(fault) = fault {
+                store.inject(fault);
+            }
+            assert_eq!(store.persist(&khf).is_ok(), fault.is_none());
+
+            // A load must either see a consistent state or fail cleanly.
+            match store.load::<Sha3_256, SHA3_256_MD_SIZE>() {
+                Ok(loaded) => assert!(loaded.digest() == pre || loaded.digest() == post),
+                Err(err) => assert!(matches!(err, Error::Corrupt)),
+            }
+
+            // Recover by persisting again.
+            store.persist(&khf)?;
+            assert_eq!(store.load::<Sha3_256, SHA3_256_MD_SIZE>()?.digest(), post);
+        }
+
+        store.fail_loads(1);
+        assert!(matches!(
+            store.load::<Sha3_256, SHA3_256_MD_SIZE>(),
+            Err(Error::Io)
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{aliases::Key, error::Error, khf::Khf};
+use hasher::Hasher;
+use kms::KeyManagementScheme;
+use rand::{CryptoRng, RngCore};
+use std::collections::{BTreeSet, HashMap};
+
+/// Storage for the per-object `Khf`s of a `Forest`.
+///
+/// Each object's `Khf` is stored serialized, and must be encrypted with the key protecting it,
+/// which is derived from the master `Khf`. Rotating that key in the master `Khf` is what makes
+/// previously stored versions of an object's `Khf`, and thus its revoked keys, unrecoverable.
+pub trait ObjectStore<const N: usize> {
+    /// Loads and decrypts the stored `Khf` of an object, or returns `None` if there isn't one.
+    fn load(&mut self, object: u64, key: &Key<N>) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Encrypts and stores the `Khf` of an object, replacing the previously stored one.
+    fn store(&mut self, object: u64, key: &Key<N>, bytes: &[u8]) -> Result<(), Error>;
+
+    /// Removes the stored `Khf` of an object.
+    fn remove(&mut self, object: u64) -> Result<(), Error>;
+}
+
+/// A master `Khf` protecting a `Khf` per object, e.g., per file.
+///
+/// Object `Khf`s are loaded from an `ObjectStore` on first use. Committing the `Forest` commits
+/// every modified object `Khf`, rotates the keys protecting them in the master `Khf`, commits the
+/// master `Khf`, and stores the object `Khf`s under their new keys. The master `Khf` itself must be
+/// persisted by the caller after each commit.
+///
+/// If storing an object `Khf` fails, the commit errors, and the objects that weren't stored stay
+/// loaded and modified. Retrying the commit stores them, and reports the updated keys of the failed
+/// commit too.
+pub struct Forest<S, H, const N: usize> {
+    master: Khf<H, N>,
+    fanouts: Vec<u64>,
+    store: S,
+    // The object `Khf`s that are loaded.
+    objects: HashMap<u64, Khf<H, N>>,
+    // Loaded objects that need to be committed and stored.
+    dirty: BTreeSet<u64>,
+    // Objects removed since the last commit.
+    removed: BTreeSet<u64>,
+    // Updated keys committed by a commit that failed to store every object.
+    unreported: Vec<((u64, u64), Key<N>)>,
+}
+
+impl<S, H, const N: usize> Forest<S, H, N>
+where
+    S: ObjectStore<N>,
+    H: Hasher<N>,
+{
+    /// Constructs a `Forest` from a master `Khf` and the store holding the object `Khf`s. Objects
+    /// without a stored `Khf` get a new one with the given fanout list.
+    pub fn new(mut master: Khf<H, N>, fanouts: &[u64], store: S) -> Self {
+        master.restore_in_flight_keys();
+        Self {
+            master,
+            fanouts: fanouts.to_vec(),
+            store,
+            objects: HashMap::new(),
+            dirty: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            unreported: Vec::new(),
+        }
+    }
+
+    /// Returns the master `Khf`, e.g., to persist it after a commit.
+    pub fn master(&self) -> &Khf<H, N> {
+        &self.master
+    }
+
+    /// Returns the store holding the object `Khf`s.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Consumes the `Forest`, returning the master `Khf` and the store. Uncommitted changes to
+    /// object `Khf`s are lost.
+    pub fn into_parts(self) -> (Khf<H, N>, S) {
+        (self.master, self.store)
+    }
+
+    /// Returns the number of loaded object `Khf`s.
+    pub fn loaded(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Returns the `Khf` of an object, loading it if needed.
+    pub fn object(&mut self, object: u64) -> Result<&Khf<H, N>, Error> {
+        self.load(object).map(|khf| &*khf)
+    }
+
+    /// Returns the `Khf` of an object mutably, loading it if needed. The object is committed and
+    /// stored at the next commit.
+    pub fn object_mut(&mut self, object: u64) -> Result<&mut Khf<H, N>, Error> {
+        self.load(object)?;
+        self.dirty.insert(object);
+        Ok(self.objects.get_mut(&object).unwrap())
+    }
+
+    /// Unloads the `Khf` of an object if it has no uncommitted changes. Returns `true` if it was
+    /// unloaded.
+    pub fn unload(&mut self, object: u64) -> bool {
+        !self.dirty.contains(&object) && self.objects.remove(&object).is_some()
+    }
+
+    /// Removes an object, revoking every one of its keys at the next commit by rotating the key
+    /// protecting its `Khf`.
+    pub fn remove(&mut self, object: u64) -> Result<(), Error> {
+        self.master.update(object)?;
+        self.objects.remove(&object);
+        self.dirty.remove(&object);
+        self.removed.insert(object);
+        Ok(())
+    }
+
+    // Returns the `Khf` of an object, loading it from the store, or creating it if it was never
+    // stored (or was removed since the last commit).
+    fn load(&mut self, object: u64) -> Result<&mut Khf<H, N>, Error> {
+        if !self.objects.contains_key(&object) {
+            let key = self.master.derive(object)?;
+            let stored = if self.removed.remove(&object) {
+                None
+            } else {
+                self.store.load(object, &key)?
+            };
+
+            let khf = match stored {
+                Some(bytes) => {
+                    let mut khf: Khf<H, N> = bincode::deserialize(&bytes)?;
+                    khf.restore_in_flight_keys();
+                    khf
+                }
+                None => {
+                    // The initial roots are derived from the key protecting the object, which
+                    // is as secret as an RNG's output would be.
+                    self.dirty.insert(object);
+                    Khf::from_master(&self.fanouts, &key)
+                }
+            };
+            self.objects.insert(object, khf);
+        }
+
+        Ok(self.objects.get_mut(&object).unwrap())
+    }
+}
+
+impl<S, H, const N: usize> KeyManagementScheme for Forest<S, H, N>
+where
+    S: ObjectStore<N>,
+    H: Hasher<N>,
+{
+    /// Keys have the same size as the hash digest size.
+    type Key = Key<N>;
+    /// Keys are identified with `(object_id, key_id)` pairs.
+    type KeyId = (u64, u64);
+    /// Bespoke error type.
+    type Error = Error;
+
+    fn derive(&mut self, (object, key): Self::KeyId) -> Result<Self::Key, Self::Error> {
+        let khf = self.load(object)?;
+        let value = khf.derive(key)?;
+        if khf.in_flight_len() != khf.len() {
+            self.dirty.insert(object);
+        }
+        Ok(value)
+    }
+
+    fn update(&mut self, (object, key): Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.object_mut(object)?.update(key)
+    }
+
+    /// Commits the modified object `Khf`s, reporting their updated keys, and then the master `Khf`.
+    fn commit(
+        &mut self,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Self::Key)>, Self::Error> {
+        let res = &mut self.unreported;
+        for &object in &self.dirty {
+            let khf = self.objects.get_mut(&object).unwrap();
+            khf.commit_with(&mut rng, |key, value| res.push(((object, key), value)))?;
+            self.master.update(object)?;
+        }
+
+        self.master.commit(&mut rng)?;
+
+        // The stored versions of dirty objects can't be decrypted anymore, so they stay dirty
+        // until they're stored under their new keys.
+        while let Some(&object) = self.dirty.first() {
+            let key = self.master.derive(object)?;
+            let bytes = bincode::serialize(&self.objects[&object])?;
+            self.store.store(object, &key, &bytes)?;
+            self.dirty.remove(&object);
+        }
+        while let Some(&object) = self.removed.first() {
+            self.store.remove(object)?;
+            self.removed.remove(&object);
+        }
+
+        Ok(std::mem::take(&mut self.unreported))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use rand::prelude::*;
+
+    const N: usize = SHA3_256_MD_SIZE;
+
+    // Stands in for encryption by storing the key alongside the `Khf` and checking it on load.
+    // Accessing the object in the second field fails.
+    #[derive(Default)]
+    struct Store(HashMap<u64, (Key<N>, Vec<u8>)>, Option<u64>);
+
+    impl ObjectStore<N> for Store {
+        fn load(&mut self, object: u64, key: &Key<N>) -> Result<Option<Vec<u8>>, Error> {
+            if self.1 == Some(object) {
+                return Err(Error::Io);
+            }
+            match self.0.get(&object) {
+                Some((stored, bytes)) if stored == key => Ok(Some(bytes.clone())),
+                Some(_) => Err(Error::Corrupt),
+                None => Ok(None),
+            }
+        }
+
+        fn store(&mut self, object: u64, key: &Key<N>, bytes: &[u8]) -> Result<(), Error> {
+            if self.1 == Some(object) {
+                return Err(Error::Io);
+            }
+            self.0.insert(object, (*key, bytes.to_vec()));
+            Ok(())
+        }
+
+        fn remove(&mut self, object: u64) -> Result<(), Error> {
+            self.0.remove(&object);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cascade() -> Result<()> {
+        let master = Khf::<Sha3_256, N>::new(&[4, 4], thread_rng());
+        let mut forest = Forest::new(master, &[4, 4], Store::default());
+        let key = forest.derive((1, 5))?;
+        forest.derive((2, 3))?;
+        forest.commit(thread_rng())?;
+        let protecting = forest.store().0[&2].0;
+
+        forest.update((2, 3))?;
+        let updated = forest.commit(thread_rng())?;
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].0, (2, 3));
+        assert_ne!(forest.store().0[&2].0, protecting);
+
+        // Object `Khf`s are loaded lazily after a restart.
+        let (master, store) = forest.into_parts();
+        let master: Khf<Sha3_256, N> = bincode::deserialize(&bincode::serialize(&master)?)?;
+        let mut forest = Forest::new(master, &[4, 4], store);
+        assert_eq!(forest.loaded(), 0);
+        assert_eq!(forest.derive((1, 5))?, key);
+        assert_eq!(forest.loaded(), 1);
+        assert!(forest.unload(1));
+
+        forest.remove(1)?;
+        forest.commit(thread_rng())?;
+        assert!(!forest.store().0.contains_key(&1));
+        assert_ne!(forest.derive((1, 5))?, key);
+
+        Ok(())
+    }
+
+    #[test]
+    fn failing_store() -> Result<()> {
+        let master = Khf::<Sha3_256, N>::new(&[4, 4], thread_rng());
+        let mut forest = Forest::new(master, &[4, 4], Store::default());
+        forest.derive((1, 5))?;
+        forest.derive((2, 3))?;
+        forest.commit(thread_rng())?;
+
+        // A failed load doesn't leave the object to be committed.
+        forest.store.1 = Some(3);
+        assert!(forest.update((3, 0)).is_err());
+        forest.commit(thread_rng())?;
+
+        // Objects that failed to be stored are stored by the next commit.
+        forest.store.1 = Some(2);
+        forest.update((1, 5))?;
+        forest.update((2, 3))?;
+        assert!(forest.commit(thread_rng()).is_err());
+        forest.store.1 = None;
+        let mut updated = forest.commit(thread_rng())?;
+        updated.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            updated.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [(1, 5), (2, 3)]
+        );
+
+        let keys = [forest.derive((1, 5))?, forest.derive((2, 3))?];
+        assert_ne!(keys, [updated[0].1, updated[1].1]);
+
+        let (master, store) = forest.into_parts();
+        let mut forest = Forest::new(master, &[4, 4], store);
+        assert_eq!([forest.derive((1, 5))?, forest.derive((2, 3))?], keys);
+
+        Ok(())
+    }
+}
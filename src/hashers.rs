@@ -0,0 +1,69 @@
+#[cfg(feature = "openssl")]
+use crate::fallback::Accelerated;
+#[cfg(feature = "openssl")]
+use hasher::sha3::SHA3_256_MD_SIZE;
+use hasher::Hasher;
+#[cfg(feature = "openssl")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "openssl")]
+use std::sync::OnceLock;
+
+/// The digest size of `Blake3`.
+#[cfg(feature = "blake3")]
+pub const BLAKE3_MD_SIZE: usize = 32;
+
+/// A BLAKE3 hasher.
+#[cfg(feature = "blake3")]
+pub struct Blake3(blake3::Hasher);
+
+#[cfg(feature = "blake3")]
+impl Hasher<BLAKE3_MD_SIZE> for Blake3 {
+    fn new() -> Self {
+        Self(blake3::Hasher::new())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self) -> [u8; BLAKE3_MD_SIZE] {
+        self.0.finalize().into()
+    }
+}
+
+/// A SHA3-256 hasher backed by OpenSSL, whose assembly implementations use the CPU's SHA3
+/// extensions where it has them. Meant to be wrapped in a `Fallback` hasher, since OpenSSL builds
+/// may not support SHA3-256.
+#[cfg(feature = "openssl")]
+pub struct OpensslSha3_256(openssl::hash::Hasher);
+
+#[cfg(feature = "openssl")]
+impl Hasher<SHA3_256_MD_SIZE> for OpensslSha3_256 {
+    fn new() -> Self {
+        Self(
+            openssl::hash::Hasher::new(MessageDigest::sha3_256()).expect("SHA3-256 is unsupported"),
+        )
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data).expect("hashing failed");
+    }
+
+    fn finish(mut self) -> [u8; SHA3_256_MD_SIZE] {
+        let mut digest = [0; SHA3_256_MD_SIZE];
+        digest.copy_from_slice(&self.0.finish().expect("hashing failed"));
+        digest
+    }
+}
+
+#[cfg(feature = "openssl")]
+impl Accelerated<SHA3_256_MD_SIZE> for OpensslSha3_256 {
+    fn probe() -> bool {
+        openssl::hash::Hasher::new(MessageDigest::sha3_256()).is_ok()
+    }
+
+    fn availability() -> &'static OnceLock<bool> {
+        static AVAILABILITY: OnceLock<bool> = OnceLock::new();
+        &AVAILABILITY
+    }
+}
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::Add};
+
+/// An epoch of a `Khf`, i.e., the number of commits it has gone through.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(transparent)]
+pub struct Epoch(pub u64);
+
+/// The ID of a key in a `Khf`.
+///
+/// The `KeyManagementScheme` implementations keep identifying keys with raw `u64`s, so a `KeyId`
+/// converts to and from one freely. APIs that take both a key and an epoch take the typed forms so
+/// the two can't be swapped.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(transparent)]
+pub struct KeyId(pub u64);
+
+macro_rules! raw_conversions {
+    ($ty:ident) => {
+        impl From<u64> for $ty {
+            fn from(raw: u64) -> Self {
+                Self(raw)
+            }
+        }
+
+        impl From<$ty> for u64 {
+            fn from(id: $ty) -> Self {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+raw_conversions!(Epoch);
+raw_conversions!(KeyId);
+
+impl Add<u64> for Epoch {
+    type Output = Self;
+
+    /// Returns the epoch a number of commits later.
+    fn add(self, commits: u64) -> Self {
+        Self(self.0 + commits)
+    }
+}
@@ -0,0 +1,116 @@
+use crate::aliases::Key;
+use hasher::Hasher;
+use rand::{CryptoRng, RngCore};
+use std::marker::PhantomData;
+use zeroize::Zeroize;
+
+/// Domain separation for the blocks generated by a `KdfRng`.
+const DOMAIN: &[u8] = b"khf kdf rng v1";
+
+/// Domain separation for the blocks of an expanded key.
+const EXPAND_DOMAIN: &[u8] = b"khf expand v1";
+
+/// Expands (or truncates) a key to `M` bytes, by hashing it along with the output length and a
+/// block counter, in the manner of HKDF-expand. Every output length yields unrelated keys.
+pub(crate) fn expand<H, const N: usize, const M: usize>(key: &Key<N>) -> [u8; M]
+where
+    H: Hasher<N>,
+{
+    let mut out = [0; M];
+    for (counter, chunk) in out.chunks_mut(N).enumerate() {
+        let mut hasher = H::new();
+        hasher.update(EXPAND_DOMAIN);
+        hasher.update(key);
+        hasher.update(&(M as u64).to_le_bytes());
+        hasher.update(&(counter as u64).to_le_bytes());
+        let mut block = hasher.finish();
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        block.zeroize();
+    }
+    out
+}
+
+/// A deterministic RNG that derives its output from a master secret and an epoch, by hashing them
+/// along with a block counter. Using it for every operation that consumes randomness makes a `Khf`
+/// reproducible from the master secret and a log of its epochs, rather than from stored roots.
+pub struct KdfRng<H, const N: usize> {
+    master: Key<N>,
+    epoch: u64,
+    counter: u64,
+    block: Key<N>,
+    used: usize,
+    pd: PhantomData<H>,
+}
+
+impl<H, const N: usize> KdfRng<H, N>
+where
+    H: Hasher<N>,
+{
+    /// Constructs the RNG for an epoch.
+    pub fn new(master: &Key<N>, epoch: u64) -> Self {
+        Self {
+            master: *master,
+            epoch,
+            counter: 0,
+            block: [0; N],
+            used: N,
+            pd: PhantomData,
+        }
+    }
+
+    // Generates the next block of output.
+    fn refill(&mut self) {
+        let mut hasher = H::new();
+        hasher.update(DOMAIN);
+        hasher.update(&self.master);
+        hasher.update(&self.epoch.to_le_bytes());
+        hasher.update(&self.counter.to_le_bytes());
+        self.block = hasher.finish();
+        self.counter += 1;
+        self.used = 0;
+    }
+}
+
+impl<H, const N: usize> RngCore for KdfRng<H, N>
+where
+    H: Hasher<N>,
+{
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut filled = 0;
+        while filled < dest.len() {
+            if self.used == N {
+                self.refill();
+            }
+            let n = (N - self.used).min(dest.len() - filled);
+            dest[filled..filled + n].copy_from_slice(&self.block[self.used..self.used + n]);
+            self.used += n;
+            filled += n;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl<H, const N: usize> CryptoRng for KdfRng<H, N> where H: Hasher<N> {}
+
+impl<H, const N: usize> Drop for KdfRng<H, N> {
+    fn drop(&mut self) {
+        self.master.zeroize();
+        self.block.zeroize();
+    }
+}
@@ -1,26 +1,79 @@
+#[cfg(feature = "mlock")]
+use crate::secure::{LockedRegion, LockedRegions};
 use crate::{
     aliases::{Key, Pos},
+    cache::{Cache, CachePolicy, CacheStats},
+    ct,
+    digest::{Digest, PersistedDigest},
+    epoch::OpenEpoch,
     error::Error,
-    node::Node,
+    ids::{Epoch, KeyId},
+    kdf::{self, KdfRng},
+    lease::Lease,
+    node::{self, Node, NodeExt, Tree},
+    overlay::Overlay,
+    ranges::RangeSet,
+    report::Report,
+    table::{self, Header, Recovery, Table},
     topology::Topology,
 };
+use bincode::Options;
 use hasher::Hasher;
 use kms::KeyManagementScheme;
 use rand::{CryptoRng, RngCore};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
-    fmt,
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::{self, Write},
+    io::Read,
+    mem,
+    ops::Range,
+    sync::Arc,
 };
+use zeroize::Zeroize;
 
 /// The default level for roots created when mutating a `Khf`.
 const DEFAULT_ROOT_LEVEL: u64 = 1;
 
+// Used to restore the root level of a deserialized `Khf`.
+fn default_root_level() -> u64 {
+    DEFAULT_ROOT_LEVEL
+}
+
+// Derives the key at a position from a root's position and key, with a particular hasher.
+type Derivation<const N: usize> = fn(&Topology, Pos, &Key<N>, Pos) -> Key<N>;
+
+// A root list, shared by clones and history snapshots until one of them modifies it.
+type Roots<H, const N: usize> = Arc<Vec<Node<H, N>>>;
+
+fn derive_with_hasher<G, const N: usize>(
+    topology: &Topology,
+    root_pos: Pos,
+    root_key: &Key<N>,
+    pos: Pos,
+) -> Key<N>
+where
+    G: Hasher<N>,
+{
+    Node::<G, N>::with_pos(root_pos, *root_key).derive(topology, pos)
+}
+
+/// The header of a `Khf` fixture.
+const FIXTURE_HEADER: &str = "khf-fixture v1";
+
+/// Domain separation for keys derived with a context.
+const CONTEXT_DOMAIN: &[u8] = b"khf context v1";
+
 /// A keyed hash forest (`Khf`) is a data structure for secure key management built around keyed
 /// hash trees (`Kht`s). As a secure key management scheme, a `Khf` is not only capable of deriving
 /// keys, but also updating keys such that they cannot be rederived post-update. Updating a key is
 /// synonymous to revoking a key.
+///
+/// A `Khf` never stores an RNG. Every operation that consumes randomness takes one as an argument,
+/// so the source of entropy can be swapped at any time (e.g., once a hardware RNG is available).
 #[derive(Deserialize, Serialize)]
 pub struct Khf<H, const N: usize> {
     // The topology of a `Khf`.
@@ -29,7 +82,7 @@ pub struct Khf<H, const N: usize> {
     // Root that appended keys are derived from.
     #[serde(bound(serialize = "Node<H, N>: Serialize"))]
     #[serde(bound(deserialize = "Node<H, N>: Deserialize<'de>"))]
-    appending_root: Node<H, N>,
+    appending_root: Box<Node<H, N>>,
 
     // The number of keys in flight.
     #[serde(skip)]
@@ -39,21 +92,82 @@ pub struct Khf<H, const N: usize> {
 
     // Tracks updated keys.
     #[serde(skip)]
-    updated_keys: HashSet<u64>,
+    updated_keys: RangeSet,
     #[serde(skip)]
     updated_keys_dirty: bool,
 
+    // The fewest keys in flight since the last commit, if the `Khf` was truncated.
+    #[serde(skip)]
+    truncated_keys: Option<u64>,
+
     // The list of roots.
     #[serde(bound(serialize = "Node<H, N>: Serialize"))]
     #[serde(bound(deserialize = "Node<H, N>: Deserialize<'de>"))]
-    roots: Vec<Node<H, N>>,
+    roots: Roots<H, N>,
 
     // The number of keys a `Khf` currently provides.
     keys: u64,
 
+    // The number of commits the `Khf` has gone through.
+    epoch: u64,
+
+    // Tombstones for deleted keys.
+    deleted: BTreeSet<u64>,
+
+    // Leased ranges of keys, along with the epochs they expire at.
+    leases: Vec<(Range<u64>, u64)>,
+
+    // The root lists of recently committed epochs, as `(epoch, keys, roots)`, oldest first.
+    #[serde(bound(serialize = "Node<H, N>: Serialize"))]
+    #[serde(bound(deserialize = "Node<H, N>: Deserialize<'de>"))]
+    history: VecDeque<(u64, u64, Roots<H, N>)>,
+
+    // The number of past epochs to retain root lists for.
+    #[serde(skip)]
+    retained_epochs: usize,
+
+    // Sorted, disjoint ranges of keys whose roots are left untouched by consolidation.
+    #[serde(skip)]
+    pinned: Vec<Range<u64>>,
+
+    // Whether deriving keys out of range is an error instead of an implicit append.
+    #[serde(skip)]
+    strict: bool,
+
+    // Whether the `Khf` was imported from a delegation, and can only derive keys.
+    #[serde(skip)]
+    read_only: bool,
+
+    // Whether the last attempt to commit was deferred for lack of entropy.
+    #[serde(skip)]
+    degraded: bool,
+
+    // The level of roots created when fragmenting the `Khf`.
+    #[serde(skip, default = "default_root_level")]
+    root_level: u64,
+
+    // The strategy for looking up the root covering a key.
+    #[serde(skip)]
+    lookup: RootLookup,
+
+    // The limit on derivations per epoch, and the derivations in each range this epoch.
+    #[serde(skip)]
+    quota: Option<Quota>,
+    #[serde(skip)]
+    derivations: HashMap<u64, u64>,
+
+    // The derivation with an independent hasher that derived keys are cross-checked against.
+    #[serde(skip)]
+    cross_check: Option<Derivation<N>>,
+
     // Holds subnodes computed between commits
     #[serde(skip)]
-    cache: HashMap<Pos, Key<N>>,
+    cache: Cache<N>,
+
+    // The memory regions holding the root lists and appending root, if locked
+    #[cfg(feature = "mlock")]
+    #[serde(skip)]
+    locked: LockedRegions,
 }
 
 impl<H, const N: usize> Clone for Khf<H, N> {
@@ -65,10 +179,29 @@ impl<H, const N: usize> Clone for Khf<H, N> {
             in_flight_keys_dirty: self.in_flight_keys_dirty,
             updated_keys: self.updated_keys.clone(),
             updated_keys_dirty: self.updated_keys_dirty,
+            truncated_keys: self.truncated_keys,
+            // The root list is shared until either `Khf` modifies it.
             roots: self.roots.clone(),
             keys: self.keys,
+            epoch: self.epoch,
+            deleted: self.deleted.clone(),
+            leases: self.leases.clone(),
+            history: self.history.clone(),
+            retained_epochs: self.retained_epochs,
+            pinned: self.pinned.clone(),
+            strict: self.strict,
+            read_only: self.read_only,
+            degraded: self.degraded,
+            root_level: self.root_level,
+            lookup: self.lookup,
+            quota: self.quota,
+            cross_check: self.cross_check,
+            derivations: self.derivations.clone(),
             cache: self.cache.clone(),
+            #[cfg(feature = "mlock")]
+            locked: LockedRegions::default(),
         }
+        .locked()
     }
 }
 
@@ -78,573 +211,4318 @@ pub enum Consolidation {
     Full,
     /// Consolidate a `Khf` to roots of a certain level.
     Leveled { level: u64 },
-    /// Consolidate roots corresponding to a range of keys in a `Khf`.
+    /// Consolidate roots corresponding to a range of keys in a `Khf`. Roots straddling the
+    /// boundaries of the range are split, so only keys in the range are affected.
     Ranged { start: u64, end: u64 },
-    /// Consolidate roots to a certain level corresponding to a range of keys in a `Khf`.
+    /// Consolidate roots to a certain level corresponding to a range of keys in a `Khf`. Roots
+    /// straddling the boundaries of the range are split, so only keys in the range are affected.
+    /// Use `Khf::align_range()` to consolidate to roots of exactly the given level.
     RangedLeveled { level: u64, start: u64, end: u64 },
 }
 
-impl<H, const N: usize> Khf<H, N>
-where
-    H: Hasher<N>,
-{
-    /// Constructs a new `Khf`.
-    pub fn new(fanouts: &[u64], mut rng: impl RngCore + CryptoRng) -> Self {
-        Self {
-            topology: Topology::new(fanouts),
-            appending_root: Node::with_rng(&mut rng),
-            in_flight_keys: 0,
-            in_flight_keys_dirty: false,
-            updated_keys: HashSet::new(),
-            updated_keys_dirty: false,
-            roots: vec![Node::with_rng(&mut rng)],
-            keys: 0,
-            cache: HashMap::new(),
-        }
+/// Statistics describing the shape of a `Khf`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KhfStats {
+    /// The number of roots at each level, indexed by level.
+    pub roots_per_level: Vec<u64>,
+    /// The total number of roots.
+    pub roots: u64,
+    /// The contiguous ranges of keys covered by the roots.
+    pub covered: Vec<(u64, u64)>,
+    /// The number of updated keys pending the next commit.
+    pub updated_keys: u64,
+    /// The number of keys cached since the last commit.
+    pub cached_keys: usize,
+}
+
+/// A receipt for a transfer of keys between `Khf`s, which identifies the transferred roots by
+/// their positions and commits to their keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Receipt<const N: usize> {
+    range: Range<u64>,
+    positions: Vec<Pos>,
+    digest: Key<N>,
+}
+
+impl<const N: usize> Receipt<N> {
+    /// The range of transferred keys.
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
     }
 
-    /// Returns the number of roots in the `Khf`'s root list.
-    pub fn fragmentation(&self) -> u64 {
-        self.roots.len() as u64
+    /// The positions of the transferred roots.
+    pub fn positions(&self) -> &[Pos] {
+        &self.positions
     }
 
-    /// Returns `true` if the `Khf` is consolidated.
-    pub fn is_consolidated(&self) -> bool {
-        self.roots.len() == 1 && self.roots[0].pos == (0, 0)
+    /// The digest committing to the transferred range and roots.
+    pub fn digest(&self) -> &Key<N> {
+        &self.digest
     }
+}
 
-    /// The keys that have been updated since the last epoch
-    pub fn updated_keys(&self) -> &HashSet<u64> {
-        &self.updated_keys
+/// A limit on the number of key derivations per epoch within each range of keys, as a
+/// defense-in-depth control when deriving keys is exposed to semi-trusted components.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    /// The number of keys in each range. Ranges start at multiples of it.
+    pub range: u64,
+    /// The number of derivations allowed within each range per epoch.
+    pub derivations: u64,
+}
+
+/// A strategy for looking up the root covering a key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RootLookup {
+    /// Binary search over the roots.
+    #[default]
+    Binary,
+    /// Interpolation search over the roots, which can outperform binary search when roots cover
+    /// similarly sized ranges of keys.
+    Interpolation,
+}
+
+/// The state of a key in a `Khf`, which determines whether its value survives the next commit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyState {
+    /// The key was committed and hasn't been touched since, so its value survives.
+    Committed,
+    /// The key was appended since the last commit, and its value survives.
+    AppendedUncommitted,
+    /// The key was updated since the last commit, so its value doesn't survive.
+    UpdatedUncommitted,
+    /// The key was truncated since the last commit, so it won't exist after the next commit.
+    Truncated,
+    /// The key was deleted, and can't be derived until it is truncated and appended again.
+    Deleted,
+    /// The key doesn't exist, and won't exist after the next commit.
+    Unallocated,
+}
+
+/// An operation performed on a `Khf` during an epoch, as recorded in a journal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EpochOp {
+    /// A key was updated.
+    Update(u64),
+    /// A number of keys were appended.
+    Append(u64),
+    /// The `Khf` was truncated to a number of keys.
+    Truncate(u64),
+}
+
+/// A builder for configuring a `Khf` before constructing it.
+#[derive(Clone, Debug)]
+pub struct KhfBuilder {
+    fanouts: Vec<u64>,
+    root_level: u64,
+    cache_policy: CachePolicy,
+    strict: bool,
+    lookup: RootLookup,
+    retained_epochs: usize,
+}
+
+impl Default for KhfBuilder {
+    fn default() -> Self {
+        Self {
+            fanouts: Topology::default().fanouts(),
+            root_level: DEFAULT_ROOT_LEVEL,
+            cache_policy: CachePolicy::default(),
+            strict: false,
+            lookup: RootLookup::default(),
+            retained_epochs: 0,
+        }
     }
+}
 
-    /// The keys that have been updated since the last epoch
-    pub fn updated_keys_mut(&mut self) -> &mut HashSet<u64> {
-        &mut self.updated_keys
+impl KhfBuilder {
+    /// Constructs a new `KhfBuilder` with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// Marks updated keys as clean (i.e., has been persisted).
-    /// Returns whether or not the updated keys were dirty before cleaning.
-    pub fn fetch_clean_updated_keys(&mut self) -> bool {
-        let res = self.updated_keys_dirty;
-        self.updated_keys_dirty = false;
-        res
+    /// Sets the fanout list of the topology (`[4, 4, 4, 4]` by default).
+    pub fn fanouts(mut self, fanouts: &[u64]) -> Self {
+        self.fanouts = fanouts.to_vec();
+        self
     }
 
-    /// Consolidates the `Khf` and returns the affected keys.
-    pub fn consolidate(
-        &mut self,
-        mechanism: Consolidation,
-        rng: impl RngCore + CryptoRng,
-    ) -> Vec<u64> {
-        match mechanism {
-            Consolidation::Full => self.consolidate_full(rng),
-            Consolidation::Leveled { level } => self.consolidate_leveled(level, rng),
-            Consolidation::Ranged { start, end } => self.consolidate_ranged(start, end, rng),
-            Consolidation::RangedLeveled { level, start, end } => {
-                self.consolidate_ranged_leveled(level, start, end, rng)
-            }
-        }
+    /// Sets the level of roots created when fragmenting the `Khf` (1 by default). Deeper levels
+    /// limit the keys affected by sparse updates at the cost of more roots.
+    pub fn root_level(mut self, level: u64) -> Self {
+        self.root_level = level;
+        self
     }
 
-    // Consolidates back into a single root.
-    fn consolidate_full(&mut self, rng: impl RngCore + CryptoRng) -> Vec<u64> {
-        self.consolidate_leveled(0, rng)
+    /// Sets the policy used to size the cache of keys derived between commits.
+    pub fn cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.cache_policy = policy;
+        self
     }
 
-    // Consolidates to roots of a certain level.
-    fn consolidate_leveled(&mut self, level: u64, mut rng: impl RngCore + CryptoRng) -> Vec<u64> {
-        let affected = (0..self.keys).into_iter().collect();
+    /// Sets whether the `Khf` is strict about appends (see `Khf::with_strict_appends()`).
+    pub fn strict_appends(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
 
-        let node = Node::with_rng(&mut rng);
-        self.replace_keys(level, 0, self.keys, node);
+    /// Sets the strategy used to look up the root covering a key.
+    pub fn root_lookup(mut self, lookup: RootLookup) -> Self {
+        self.lookup = lookup;
+        self
+    }
 
-        // Unmark keys as updated and update the whole range of keys.
-        self.updated_keys.clear();
-        self.updated_keys_dirty = true;
+    /// Sets the number of past epochs whose keys remain derivable (none by default).
+    pub fn retained_epochs(mut self, epochs: usize) -> Self {
+        self.retained_epochs = epochs;
+        self
+    }
 
-        affected
+    /// Constructs the `Khf`. The RNG is only used during construction, since a `Khf` never stores
+    /// one.
+    ///
+    /// Panics if the fanouts are invalid, or if the root level isn't between 1 and the height of
+    /// the topology (exclusive).
+    pub fn build<H, const N: usize>(self, rng: impl RngCore + CryptoRng) -> Khf<H, N>
+    where
+        H: Hasher<N>,
+    {
+        let topology = Topology::new(&self.fanouts);
+        self.build_with(topology, rng)
     }
 
-    // Consolidates the roots for a range of keys.
-    fn consolidate_ranged(
-        &mut self,
-        start: u64,
-        end: u64,
+    /// Constructs the `Khf` like `build()`, but errors with `Error::InvalidTopology` instead of
+    /// panicking if the fanouts or root level are invalid.
+    pub fn try_build<H, const N: usize>(
+        self,
         rng: impl RngCore + CryptoRng,
-    ) -> Vec<u64> {
-        self.consolidate_ranged_leveled(DEFAULT_ROOT_LEVEL, start, end, rng)
+    ) -> Result<Khf<H, N>, Error>
+    where
+        H: Hasher<N>,
+    {
+        let topology = Topology::try_new(&self.fanouts).ok_or(Error::InvalidTopology)?;
+        if self.root_level == 0 || self.root_level >= topology.height() {
+            return Err(Error::InvalidTopology);
+        }
+        Ok(self.build_with(topology, rng))
     }
 
-    // Consolidates the roots for a range of keys to roots of a certain level.
-    fn consolidate_ranged_leveled(
-        &mut self,
-        level: u64,
-        start: u64,
-        end: u64,
-        mut rng: impl RngCore + CryptoRng,
-    ) -> Vec<u64> {
-        let affected = (start..end).into_iter().collect();
+    // Constructs the `Khf` with an already validated topology.
+    fn build_with<H, const N: usize>(
+        self,
+        topology: Topology,
+        rng: impl RngCore + CryptoRng,
+    ) -> Khf<H, N>
+    where
+        H: Hasher<N>,
+    {
+        let mut khf = Khf::with_topology(topology, rng).with_strict_appends(self.strict);
+        khf.set_root_level(self.root_level);
+        khf.lookup = self.lookup;
+        khf.retained_epochs = self.retained_epochs;
+        khf.cache.set_policy(self.cache_policy);
+        khf
+    }
+}
 
-        // Update the range of keys.
-        let node = Node::with_rng(&mut rng);
-        self.replace_keys(level, start, end, node);
+impl<H, const N: usize> Khf<H, N>
+where
+    H: Hasher<N>,
+{
+    /// Returns a builder for configuring a `Khf`.
+    pub fn builder() -> KhfBuilder {
+        KhfBuilder::new()
+    }
 
-        // The consolidated range of keys shouldn't be considered as updated.
-        for key in &affected {
-            self.updated_keys.remove(key);
-            self.updated_keys_dirty = true;
-        }
+    /// Constructs a new `Khf`.
+    ///
+    /// Panics if the fanouts are invalid (see `try_new()`).
+    pub fn new(fanouts: &[u64], rng: impl RngCore + CryptoRng) -> Self {
+        Self::with_topology(Topology::new(fanouts), rng)
+    }
 
-        affected
+    /// Constructs a new `Khf`, erroring with `Error::InvalidTopology` if the fanout list is empty,
+    /// has a fanout less than 2, or puts more keys under a top-level node than fit in a `u64`.
+    pub fn try_new(fanouts: &[u64], rng: impl RngCore + CryptoRng) -> Result<Self, Error> {
+        let topology = Topology::try_new(fanouts).ok_or(Error::InvalidTopology)?;
+        Ok(Self::with_topology(topology, rng))
     }
 
-    /// Truncates the `Khf` so it only covers a specified number of keys.
-    pub fn truncate(&mut self, keys: u64) {
-        self.in_flight_keys = keys;
-        self.in_flight_keys_dirty = true;
+    /// Constructs a new `Khf` with the default topology (a fanout list of `[4, 4, 4, 4]`).
+    pub fn with_default_topology(rng: impl RngCore + CryptoRng) -> Self {
+        Self::with_topology(Topology::default(), rng)
     }
 
-    /// Derives a key.
-    fn derive_key(&mut self, key: u64) -> Key<N> {
-        let pos = self.topology.leaf_position(key);
+    /// Constructs a deterministic `Khf`, whose roots are all derived from a master secret instead
+    /// of an RNG, so that it can be rebuilt with `rebuild()` rather than persisted. Its commits must
+    /// go through `ClosingEpoch::commit_with_master()`.
+    pub fn from_master(fanouts: &[u64], master: &Key<N>) -> Self {
+        Self::new(fanouts, KdfRng::<H, N>::new(master, 0))
+    }
 
-        // Derive the key from the appending root if it should be appended.
-        if key >= self.keys {
-            self.in_flight_keys = self.in_flight_keys.max(key + 1);
-            self.in_flight_keys_dirty = true;
-            return self
-                .appending_root
-                .derive_and_cache(&self.topology, pos, &mut self.cache);
+    /// Commits a deterministic `Khf`, deriving the roots of the next epoch from the master secret.
+    pub(crate) fn commit_with_master(
+        &mut self,
+        master: &Key<N>,
+    ) -> Result<Vec<(u64, Key<N>)>, Error> {
+        self.commit(KdfRng::<H, N>::new(master, self.epoch + 1))
+    }
+
+    /// Imports nodes exported with `delegate()` as a read-only `Khf` with the delegating `Khf`'s
+    /// topology. It derives exactly the delegated keys, erroring with `Error::OutOfRange` for any
+    /// other key, while updating keys or committing errors with `Error::ReadOnly`. Like other
+    /// configuration, read-only mode isn't persisted, so the delegation should be stored instead of
+    /// the `Khf`.
+    pub fn from_delegation(topology: Topology, mut nodes: Vec<Node<H, N>>) -> Result<Self, Error> {
+        nodes.sort_by_key(|node| topology.start(node.pos));
+        let mut end = 0;
+        for node in &nodes {
+            if node.pos.0 >= topology.height() || topology.start(node.pos) < end {
+                return Err(Error::InvalidDelegation);
+            }
+            end = topology.end(node.pos);
+        }
+        if nodes.is_empty() {
+            return Err(Error::InvalidDelegation);
         }
 
-        // Binary search for the index of the root covering the key.
-        let index = self
-            .roots
-            .binary_search_by(|root| {
-                if self.topology.is_ancestor(root.pos, pos) {
-                    Ordering::Equal
-                } else if self.topology.end(root.pos) <= self.topology.start(pos) {
-                    Ordering::Less
-                } else {
-                    Ordering::Greater
-                }
-            })
-            .unwrap();
+        // Nothing is ever derived from the appending root, since nothing is derivable past the
+        // delegated keys, so it doesn't need to be random.
+        let mut khf = Self::with_topology(topology, KdfRng::<H, N>::new(&[0; N], 0));
+        khf.roots = Arc::new(nodes);
+        khf.keys = end;
+        khf.in_flight_keys = end;
+        khf.strict = true;
+        khf.read_only = true;
+        khf.lock_keys();
+        Ok(khf)
+    }
 
-        self.roots[index].derive_and_cache(&self.topology, pos, &mut self.cache)
+    // Constructs an empty `Khf` whose consolidated and appending roots are both a given root, so
+    // that it derives and appends the keys of the tree below the root.
+    pub(crate) fn from_root(topology: Topology, root: Node<H, N>) -> Self {
+        let mut khf = Self::with_topology(topology, KdfRng::<H, N>::new(&[0; N], 0));
+        *khf.appending_root = root.clone();
+        khf.roots = Arc::new(vec![root]);
+        khf.lock_keys();
+        khf
     }
 
-    fn derive_key_immutable(&self, key: u64) -> Key<N> {
-        let pos = self.topology.leaf_position(key);
+    // Returns the root of a consolidated `Khf`.
+    pub(crate) fn consolidated_root(&self) -> Option<&Node<H, N>> {
+        self.is_consolidated().then(|| &self.roots[0])
+    }
 
-        if let Some(key) = self.cache.get(&pos) {
-            return *key;
-        }
+    /// Returns `true` if the `Khf` was imported from a delegation and can only derive keys.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-        // Derive the key from the appending root if it should be appended.
-        if key >= self.keys {
-            return self
-                .appending_root
-                .derive_cached(&self.topology, pos, &self.cache);
+    /// Rebuilds a deterministic `Khf` from its master secret and a log of the operations performed
+    /// in each of its epochs. Operations that consume randomness besides commits (e.g.,
+    /// consolidation) can't be logged, so they shouldn't be used on a deterministic `Khf`.
+    pub fn rebuild<E, I>(fanouts: &[u64], master: &Key<N>, epochs: E) -> Result<Self, Error>
+    where
+        E: IntoIterator<Item = I>,
+        I: IntoIterator<Item = EpochOp>,
+    {
+        let mut khf = Self::from_master(fanouts, master);
+        for epoch in epochs {
+            for op in epoch {
+                match op {
+                    EpochOp::Update(key) => {
+                        khf.update(key)?;
+                    }
+                    EpochOp::Append(n) => {
+                        khf.append(n);
+                    }
+                    EpochOp::Truncate(n) => khf.truncate_keys(n),
+                }
+            }
+            khf.commit_with_master(master)?;
         }
+        Ok(khf)
+    }
 
-        // Binary search for the index of the root covering the key.
-        let index = self
-            .roots
-            .binary_search_by(|root| {
-                if self.topology.is_ancestor(root.pos, pos) {
-                    Ordering::Equal
-                } else if self.topology.end(root.pos) <= self.topology.start(pos) {
-                    Ordering::Less
-                } else {
-                    Ordering::Greater
-                }
-            })
-            .unwrap();
+    // Constructs a new `Khf` with a given topology.
+    fn with_topology(topology: Topology, mut rng: impl RngCore + CryptoRng) -> Self {
+        Self {
+            topology,
+            appending_root: Box::new(Node::with_rng(&mut rng)),
+            in_flight_keys: 0,
+            in_flight_keys_dirty: false,
+            updated_keys: RangeSet::new(),
+            updated_keys_dirty: false,
+            truncated_keys: None,
+            roots: Arc::new(vec![Node::with_rng(&mut rng)]),
+            keys: 0,
+            epoch: 0,
+            deleted: BTreeSet::new(),
+            leases: Vec::new(),
+            history: VecDeque::new(),
+            retained_epochs: 0,
+            pinned: Vec::new(),
+            strict: false,
+            read_only: false,
+            degraded: false,
+            root_level: DEFAULT_ROOT_LEVEL,
+            lookup: RootLookup::default(),
+            quota: None,
+            derivations: HashMap::new(),
+            cross_check: None,
+            cache: Cache::new(),
+            #[cfg(feature = "mlock")]
+            locked: LockedRegions::default(),
+        }
+        .locked()
+    }
 
-        self.roots[index].derive_cached(&self.topology, pos, &self.cache)
+    /// Sets whether the `Khf` is strict about appends. A strict `Khf` only appends keys through
+    /// `append()`, and errors when deriving or updating keys out of range. Strictness isn't
+    /// persisted, so it must be set again after loading a `Khf`.
+    pub fn with_strict_appends(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
-    fn updated_key_ranges(&self) -> Vec<(u64, u64)> {
-        if self.updated_keys.is_empty() {
-            return Vec::new();
-        }
+    /// Returns the level of roots created when fragmenting the `Khf`.
+    pub fn root_level(&self) -> u64 {
+        self.root_level
+    }
 
-        let mut ranges = Vec::new();
-        let mut first = true;
-        let mut start = 0;
-        let mut prev = 0;
-        let mut leaves = 1;
-
-        for leaf in itertools::sorted(self.updated_keys.iter()) {
-            if first {
-                first = false;
-                start = *leaf;
-            } else if *leaf == prev + 1 {
-                leaves += 1;
-            } else {
-                ranges.push((start, start + leaves));
-                leaves = 1;
-                start = *leaf;
-            }
-            prev = *leaf;
-        }
+    /// Sets the level of roots created when fragmenting the `Khf` during commits and ranged
+    /// consolidation. Deeper levels limit the keys affected by sparse updates at the cost of more
+    /// roots. The root level isn't persisted, so it must be set again after loading a `Khf`.
+    ///
+    /// Panics if `level` isn't between 1 and the height of the `Khf`'s topology (exclusive).
+    pub fn set_root_level(&mut self, level: u64) {
+        assert!(
+            0 < level && level < self.topology.height(),
+            "root level {level} is out of range"
+        );
+        self.root_level = level;
+    }
 
-        ranges.push((start, start + leaves));
-        ranges
+    /// Returns the number of past epochs whose keys remain derivable.
+    pub fn retained_epochs(&self) -> usize {
+        self.retained_epochs
     }
 
-    /// Replaces a range of keys with keys derived from a given root.
-    fn replace_keys(&mut self, level: u64, start: u64, end: u64, root: Node<H, N>) {
-        // Level 0 means consolidating to a single root.
-        if level == 0 {
-            self.roots = vec![root];
-            return;
-        }
+    /// Sets the number of past epochs whose keys remain derivable with `derive_at_epoch()`. The
+    /// root lists of epochs beyond this window are zeroized as they are dropped. Like other
+    /// configuration, this isn't persisted, though the retained root lists are.
+    pub fn set_retained_epochs(&mut self, epochs: usize) {
+        self.retained_epochs = epochs;
+        self.trim_history();
+        self.lock_keys();
+    }
 
-        // Fragment the forest to cover all the keys.
-        if self.is_consolidated() {
-            self.roots =
-                self.roots[0].coverage(&self.topology, level, 0, self.in_flight_keys.max(end));
+    /// Derives a key as of a committed epoch, which must be the current epoch or one of the
+    /// retained past epochs.
+    pub fn derive_at_epoch(&self, key: KeyId, epoch: Epoch) -> Result<Key<N>, Error> {
+        let (key, epoch) = (key.0, epoch.0);
+        if epoch == self.epoch {
+            if key >= self.keys {
+                return Err(Error::KeyOutOfRange {
+                    key,
+                    max: self.keys,
+                });
+            }
+            let root = &self.roots[self.root_index(key).ok_or(Error::OutOfRange(key))?];
+            let pos = self.topology.leaf_position(key);
+            return Ok(root.derive_cached(&self.topology, pos, &self.cache));
         }
 
-        // We need to create a new set of roots and store updated roots.
-        let mut roots = Vec::new();
-        let mut updated = Vec::new();
-
-        // Find the first root affected by the update.
-        let update_start = self
-            .roots
+        let (_, keys, roots) = self
+            .history
             .iter()
-            .position(|root| start < self.topology.end(root.pos))
-            .unwrap_or(self.roots.len() - 1);
-        let update_root = &self.roots[update_start];
-        if self.topology.start(update_root.pos) != start {
-            updated.append(&mut update_root.coverage(
-                &self.topology,
-                level,
-                self.topology.start(update_root.pos),
-                start,
-            ));
+            .find(|(past, _, _)| *past == epoch)
+            .ok_or(Error::UnavailableEpoch(epoch))?;
+        if key >= *keys {
+            return Err(Error::KeyOutOfRange { key, max: *keys });
         }
 
-        // Save roots before the first root affected by the update.
-        roots.extend(&mut self.roots.drain(..update_start));
+        let index = Self::find_root(&self.topology, roots, key).ok_or(Error::OutOfRange(key))?;
+        Ok(roots[index].derive(&self.topology, self.topology.leaf_position(key)))
+    }
 
-        // Add replacement roots derived from the given root.
-        updated.append(&mut root.coverage(&self.topology, level, start, end));
+    // Drops the root lists of epochs beyond the retention window (zeroizing their keys, as with
+    // any dropped node).
+    fn trim_history(&mut self) {
+        let excess = self.history.len().saturating_sub(self.retained_epochs);
+        self.history.drain(..excess);
+    }
 
-        // Find the last root affected by the update.
-        let mut update_end = self.roots.len();
-        if end < self.topology.end(self.roots[self.roots.len() - 1].pos) {
-            update_end = self
-                .roots
+    /// Zeroizes and clears the cache of derived keys, e.g., before the `Khf` sits idle. Commits
+    /// already purge the cache.
+    pub fn purge_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Estimates the heap memory held by the `Khf`, in bytes, counting its roots, the root lists
+    /// of retained epochs, the cache, and the sets tracking keys. Root lists shared with clones
+    /// are counted in full.
+    pub fn memory_usage(&self) -> usize {
+        let roots = |roots: &Vec<Node<H, N>>| roots.capacity() * mem::size_of::<Node<H, N>>();
+        mem::size_of::<Node<H, N>>()
+            + roots(&self.roots)
+            + self
+                .history
                 .iter()
-                .position(|root| end <= self.topology.end(root.pos))
-                .unwrap_or(self.roots.len())
-                + 1;
-            let update_root = &self.roots[update_end - 1];
-            if self.topology.end(update_root.pos) != end {
-                updated.append(&mut update_root.coverage(
-                    &self.topology,
-                    level,
-                    end,
-                    self.topology.end(update_root.pos),
-                ));
+                .map(|(_, _, history)| roots(history))
+                .sum::<usize>()
+            + self.cache.memory_usage()
+            + self.updated_keys.memory_usage()
+            + self.deleted.len() * mem::size_of::<u64>()
+            + self.leases.capacity() * mem::size_of::<(Range<u64>, u64)>()
+            + self.pinned.capacity() * mem::size_of::<Range<u64>>()
+            + self.derivations.capacity() * mem::size_of::<(u64, u64)>()
+    }
+
+    /// Shrinks the internal containers of the `Khf` to fit their contents, e.g., after a commit.
+    /// Root lists shared with clones are left as they are.
+    pub fn compact_memory(&mut self) {
+        if let Some(roots) = Arc::get_mut(&mut self.roots) {
+            roots.shrink_to_fit();
+        }
+        for (_, _, roots) in &mut self.history {
+            if let Some(roots) = Arc::get_mut(roots) {
+                roots.shrink_to_fit();
             }
         }
+        self.history.shrink_to_fit();
+        self.cache.shrink_to_fit();
+        self.leases.shrink_to_fit();
+        self.pinned.shrink_to_fit();
+        self.derivations.shrink_to_fit();
 
-        // Save the updated roots and add any remaining roots.
-        roots.append(&mut updated);
-        roots.extend(&mut self.roots.drain(update_end..));
-        self.roots = roots;
+        // The root list may have been reallocated, so it needs to be locked again.
+        self.lock_keys();
     }
-}
 
-impl<H, const N: usize> KeyManagementScheme for Khf<H, N>
-where
-    H: Hasher<N>,
-{
-    /// Keys have the same size as the hash digest size.
-    type Key = Key<N>;
-    /// Keys are uniquely identified with `u64`s.
-    type KeyId = u64;
-    /// Bespoke error type.
-    type Error = Error;
+    /// Returns the strategy used to look up the root covering a key.
+    pub fn root_lookup(&self) -> RootLookup {
+        self.lookup
+    }
 
-    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
-        let pos = self.topology.leaf_position(key);
+    /// Sets the strategy used to look up the root covering a key.
+    pub fn set_root_lookup(&mut self, lookup: RootLookup) {
+        self.lookup = lookup;
+    }
 
-        if let Some(k) = self.cache.get(&pos) {
-            Ok(*k)
-        } else {
-            Ok(self.derive_key(key))
-        }
+    /// Returns the derivation quota, if there is one.
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota
     }
 
-    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
-        self.updated_keys.insert(key);
-        self.updated_keys_dirty = true;
-        self.derive(key)
+    /// Sets or removes the derivation quota. Derivations beyond the quota error until the next
+    /// commit, which resets the count of derivations.
+    ///
+    /// Panics if the quota's range is empty.
+    pub fn set_quota(&mut self, quota: Option<Quota>) {
+        assert!(
+            quota.is_none_or(|quota| quota.range > 0),
+            "quota range is empty"
+        );
+        self.quota = quota;
     }
 
-    fn commit(
-        &mut self,
-        mut rng: impl RngCore + CryptoRng,
-    ) -> Result<Vec<(Self::KeyId, Self::Key)>, Self::Error> {
-        // We're effectively getting rid of the tree, so consolidate to a new root.
-        let res = if self.in_flight_keys == 0 {
-            let res = self
-                .updated_keys
-                .iter()
-                .map(|block| (*block, self.derive_key_immutable(*block)))
-                .collect();
+    /// Cross-checks every key derived through `derive()` or `update()` against a derivation from
+    /// its root with an independent implementation `G` of the hash function (e.g., a pure-Rust
+    /// implementation when `H` is accelerated), erroring on a mismatch. This more than doubles the
+    /// cost of deriving keys, but catches faulty accelerators or miscompiles before wrong keys are
+    /// used. Like other configuration, this isn't persisted.
+    pub fn enable_cross_check<G>(&mut self)
+    where
+        G: Hasher<N>,
+    {
+        self.cross_check = Some(derive_with_hasher::<G, N>);
+    }
 
-            let node = Node::with_rng(&mut rng);
-            self.replace_keys(0, 0, 0, node);
+    /// Stops cross-checking derived keys.
+    pub fn disable_cross_check(&mut self) {
+        self.cross_check = None;
+    }
 
-            res
+    /// Returns `true` if derived keys are cross-checked.
+    pub fn is_cross_checked(&self) -> bool {
+        self.cross_check.is_some()
+    }
+
+    // Errors if a derived key doesn't match its derivation with the cross-checking hasher.
+    fn check_cross(&self, key: u64, value: &Key<N>) -> Result<(), Error> {
+        let Some(derive) = self.cross_check else {
+            return Ok(());
+        };
+
+        let root = if key >= self.keys {
+            &*self.appending_root
+        } else {
+            let index = self.root_index(key).ok_or(Error::OutOfRange(key))?;
+            &self.roots[index]
+        };
+        let expected = derive(
+            &self.topology,
+            root.pos,
+            &root.key,
+            self.topology.leaf_position(key),
+        );
+
+        if ct::eq(&expected, value) {
+            Ok(())
+        } else {
+            Err(Error::CrossCheckMismatch(key))
+        }
+    }
+
+    // Errors if deriving a key would exceed the quota.
+    fn check_quota(&self, key: u64) -> Result<(), Error> {
+        if let Some(quota) = self.quota {
+            let derivations = self.derivations.get(&(key / quota.range));
+            if derivations.is_some_and(|derivations| *derivations >= quota.derivations) {
+                return Err(Error::QuotaExceeded(key));
+            }
+        }
+        Ok(())
+    }
+
+    // Counts a successful derivation of a key against the quota.
+    fn charge_quota(&mut self, key: u64) {
+        if let Some(quota) = self.quota {
+            *self.derivations.entry(key / quota.range).or_default() += 1;
+        }
+    }
+
+    /// Returns the policy used to size the cache of keys derived between commits.
+    pub fn cache_policy(&self) -> CachePolicy {
+        self.cache.policy()
+    }
+
+    /// Sets the policy used to size the cache of keys derived between commits.
+    pub fn set_cache_policy(&mut self, policy: CachePolicy) {
+        self.cache.set_policy(policy);
+    }
+
+    /// Returns statistics about the cache of keys derived between commits. The hit and miss
+    /// counters cover the current epoch, and are reset at each commit.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Resets the hit and miss counters of the cache, e.g., to measure a single workload.
+    pub fn reset_cache_stats(&mut self) {
+        self.cache.reset_stats();
+    }
+
+    /// Returns `true` if deriving a key won't hash, i.e., the key is cached. The roots of a `Khf`
+    /// are always held in memory, so hashing down from a root is the only slow path.
+    pub fn is_resident(&self, key: u64) -> bool {
+        self.cache.peek(&self.topology.leaf_position(key)).is_some()
+    }
+
+    /// Derives and caches the keys in a range ahead of time, so they can be derived without
+    /// hashing until the next commit, e.g., from an IO completion context or ahead of a read
+    /// burst. Nodes shared by the paths to the keys are only derived once. Deleted keys and keys
+    /// out of range are skipped. Returns `true` if every other key in the range is now resident,
+    /// which a bounded cache may not allow.
+    pub fn ensure_resident(&mut self, range: Range<u64>) -> bool {
+        let end = range.end.min(self.keys.max(self.in_flight_keys));
+        let mut resident = true;
+        for key in range.start..end {
+            if !self.deleted.contains(&key) && !self.is_resident(key) {
+                resident &= self.derive_and_cache_key(key).is_ok() && self.is_resident(key);
+            }
+        }
+        resident
+    }
+
+    /// Derives the keys in a range in parallel on the rayon thread pool, e.g., for a full-device
+    /// scrub. The range is partitioned by covering root, and large roots by their subtrees, so no
+    /// path is derived by more than one task. Unlike `derive()`, this doesn't append keys or cache
+    /// the derived keys. Errors if any key in the range is deleted or out of range.
+    #[cfg(feature = "parallel")]
+    pub fn derive_range_par(&self, range: Range<u64>) -> Result<Vec<Key<N>>, Error> {
+        let max = self.keys.max(self.in_flight_keys);
+        if range.end > max {
+            return Err(Error::KeyOutOfRange {
+                key: range.end - 1,
+                max,
+            });
+        }
+        for key in range.clone() {
+            self.check_derivable(key)?;
+        }
+
+        // Roots are split into the subtrees of the highest level with enough of them in the range
+        // to keep every thread busy.
+        let len = range.end.saturating_sub(range.start);
+        let tasks = 4 * rayon::current_num_threads() as u64;
+        let width = (1..self.topology.height())
+            .map(|level| self.topology.descendants(level))
+            .find(|width| len / width >= tasks)
+            .unwrap_or(1);
+
+        let mut keys = vec![[0; N]; len as usize];
+        let mut chunks = Vec::new();
+        let (mut start, mut rest) = (range.start, keys.as_mut_slice());
+        while start < range.end {
+            // Appended keys all fall under the appending root.
+            let root_end = if start < self.keys {
+                self.root_index(start)
+                    .map_or(start + 1, |index| self.root_range(&self.roots[index]).1)
+            } else {
+                range.end
+            };
+            let end = root_end.min((start / width + 1) * width).min(range.end);
+            let (chunk, tail) = rest.split_at_mut((end - start) as usize);
+            chunks.push((start, chunk));
+            (start, rest) = (end, tail);
+        }
+
+        chunks
+            .into_par_iter()
+            .try_for_each(|(start, out)| -> Result<(), Error> {
+                // Each task caches the nodes along its run of adjacent paths.
+                let mut cache = Cache::new();
+                for (key, out) in (start..).zip(out) {
+                    *out = self.derive_key_detached(key, &mut cache)?;
+                }
+                Ok(())
+            })?;
+
+        Ok(keys)
+    }
+
+    /// Returns the number of keys the `Khf` provided as of the last commit.
+    pub fn len(&self) -> u64 {
+        self.keys
+    }
+
+    /// Returns `true` if the `Khf` provided no keys as of the last commit.
+    pub fn is_empty(&self) -> bool {
+        self.keys == 0
+    }
+
+    /// Returns the number of keys the `Khf` will provide after the next commit.
+    pub fn in_flight_len(&self) -> u64 {
+        self.in_flight_keys
+    }
+
+    /// Returns the maximum number of keys the `Khf`'s topology supports.
+    pub fn max_keys(&self) -> u64 {
+        self.topology.max_leaves()
+    }
+
+    /// Regenerates the appending root with a new RNG, e.g., after switching to a better source of
+    /// entropy. This is only possible if no keys have been appended since the last commit, since
+    /// those keys are derived from the appending root. Returns `true` if the root was regenerated.
+    pub(crate) fn reseed(&mut self, mut rng: impl RngCore + CryptoRng) -> bool {
+        if self.in_flight_keys > self.keys {
+            return false;
+        }
+        *self.appending_root = Node::with_rng(&mut rng);
+        true
+    }
+
+    /// Produces a deterministic textual fixture describing the shape of the `Khf`: its topology,
+    /// key count, and root positions. Root keys are replaced with placeholders, so fixtures can be
+    /// committed as golden files without leaking key material.
+    pub fn snapshot_fixture(&self) -> String {
+        let mut fixture = String::new();
+        writeln!(fixture, "{FIXTURE_HEADER}").unwrap();
+        writeln!(
+            fixture,
+            "fanouts {}",
+            itertools::join(self.topology.fanouts(), " ")
+        )
+        .unwrap();
+        writeln!(fixture, "keys {}", self.keys).unwrap();
+        writeln!(fixture, "epoch {}", self.epoch).unwrap();
+        for root in self.roots.iter() {
+            writeln!(fixture, "root {} {} <key>", root.pos.0, root.pos.1).unwrap();
+        }
+        fixture
+    }
+
+    /// Constructs a `Khf` from a fixture produced by `snapshot_fixture()`, with fresh random keys
+    /// for each of the roots.
+    pub fn from_fixture(fixture: &str, mut rng: impl RngCore + CryptoRng) -> Result<Self, Error> {
+        fn invalid(line: &str) -> Error {
+            Error::InvalidFixture(line.into())
+        }
+
+        fn parse(line: &str, field: Option<&str>) -> Result<u64, Error> {
+            field
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(|| invalid(line))
+        }
+
+        let mut lines = fixture.lines();
+        if lines.next() != Some(FIXTURE_HEADER) {
+            return Err(Error::InvalidFixture("missing header".into()));
+        }
+
+        let mut khf: Option<Self> = None;
+        let mut roots = Vec::new();
+
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), &mut khf) {
+                (Some("fanouts"), None) => {
+                    let fanouts = fields
+                        .map(|fanout| parse(line, Some(fanout)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    khf = Some(Self::try_new(&fanouts, &mut rng).map_err(|_| invalid(line))?);
+                }
+                (Some("keys"), Some(khf)) => {
+                    khf.keys = parse(line, fields.next())?;
+                    khf.in_flight_keys = khf.keys;
+                }
+                (Some("epoch"), Some(khf)) => {
+                    khf.epoch = parse(line, fields.next())?;
+                }
+                (Some("root"), Some(khf)) => {
+                    let pos = (parse(line, fields.next())?, parse(line, fields.next())?);
+                    let addressable = if pos.0 == 0 {
+                        pos.1 == 0
+                    } else {
+                        pos.0 < khf.topology.height()
+                            && pos.1 < khf.topology.max_leaves() / khf.topology.descendants(pos.0)
+                    };
+                    if !addressable {
+                        return Err(invalid(line));
+                    }
+                    roots.push((
+                        line,
+                        Node::with_pos(pos, Node::<H, N>::with_rng(&mut rng).key),
+                    ));
+                }
+                _ => return Err(invalid(line)),
+            }
+        }
+
+        let mut khf = khf.ok_or_else(|| Error::InvalidFixture("missing fanouts".into()))?;
+        if !roots.is_empty() {
+            // Roots must follow each other from the first key, with none past the last key, and a
+            // consolidated root must be the only root.
+            let mut end = 0;
+            for (line, root) in &roots {
+                let (start, root_end) = khf.root_range(root);
+                if start != end
+                    || (end > 0 && start >= khf.keys)
+                    || (root.pos == (0, 0) && roots.len() > 1)
+                {
+                    return Err(invalid(line));
+                }
+                end = root_end;
+            }
+            khf.roots = Arc::new(roots.into_iter().map(|(_, root)| root).collect());
+            khf.check_coverage()
+                .map_err(|_| Error::InvalidFixture("roots don't cover every key".into()))?;
+        }
+        khf.lock_keys();
+        Ok(khf)
+    }
+
+    /// Derives an independent key from a key and a context (e.g., `b"data"`, `b"mac"`, or
+    /// `b"iv"`), so that a single key ID yields as many keys as needed. Keys derived with
+    /// different contexts are unrelated to each other and to the key itself, and are rotated
+    /// along with it.
+    pub fn derive_with_context(&mut self, key: u64, context: &[u8]) -> Result<Key<N>, Error> {
+        let value = self.derive(key)?;
+        let mut hasher = H::new();
+        hasher.update(CONTEXT_DOMAIN);
+        hasher.update(&value);
+        hasher.update(&(context.len() as u64).to_le_bytes());
+        hasher.update(context);
+        Ok(hasher.finish())
+    }
+
+    /// Derives a key of `M` bytes, which may differ from the digest size (e.g., a 512-bit XTS key
+    /// with SHA3-256), by expanding or truncating the derived key in the manner of HKDF-expand.
+    pub fn derive_sized<const M: usize>(&mut self, key: u64) -> Result<[u8; M], Error> {
+        let mut value = self.derive(key)?;
+        let sized = kdf::expand::<H, N, M>(&value);
+        value.zeroize();
+        Ok(sized)
+    }
+
+    /// Returns `true` if a key matches the key currently derived for its ID, comparing them in
+    /// constant time. Keys that are out of range, deleted, or covered by no root never match.
+    pub fn verify(&self, key_id: u64, key: &Key<N>) -> bool {
+        if key_id >= self.keys.max(self.in_flight_keys) || self.deleted.contains(&key_id) {
+            return false;
+        }
+
+        self.derive_key_immutable(key_id)
+            .is_ok_and(|derived| ct::eq(&derived, key))
+    }
+
+    /// Returns the sorted IDs of the keys whose derived values differ between two forests with the
+    /// same topology. Keys that only one of the forests provides, or that only one of them has
+    /// deleted, are considered to differ. Subtrees shared by both forests aren't derived.
+    pub fn diff(&self, other: &Self) -> Result<Vec<u64>, Error> {
+        self.check_topology(&other.topology)?;
+
+        let end = self
+            .keys
+            .max(self.in_flight_keys)
+            .max(other.keys.max(other.in_flight_keys));
+
+        let mut diff: Vec<u64> = self
+            .deleted
+            .symmetric_difference(&other.deleted)
+            .copied()
+            .collect();
+
+        let mut key = 0;
+        while key < end {
+            // Keys derived from the same root in both forests can't differ.
+            if let (Some((root, root_end)), Some((other_root, other_root_end))) =
+                (self.covering_root(key), other.covering_root(key))
+            {
+                if root.pos == other_root.pos && ct::eq(&root.key, &other_root.key) {
+                    key = root_end.min(other_root_end);
+                    continue;
+                }
+            }
+
+            let deleted = self.deleted.contains(&key) || other.deleted.contains(&key);
+            let differs = match (self.derive_existing(key)?, other.derive_existing(key)?) {
+                (Some(a), Some(b)) => !ct::eq(&a, &b),
+                (a, b) => a.is_some() != b.is_some(),
+            };
+            if !deleted && differs {
+                diff.push(key);
+            }
+            key += 1;
+        }
+
+        diff.sort_unstable();
+        Ok(diff)
+    }
+
+    // Returns the root a key is derived from, along with the end of the keys it covers.
+    fn covering_root(&self, key: u64) -> Option<(&Node<H, N>, u64)> {
+        if key >= self.keys {
+            return (key < self.in_flight_keys)
+                .then_some((&*self.appending_root, self.in_flight_keys));
+        }
+        let root = &self.roots[self.root_index(key)?];
+        Some((root, self.topology.end(root.pos).min(self.keys)))
+    }
+
+    // Derives a key without appending it, if the `Khf` provides it.
+    fn derive_existing(&self, key: u64) -> Result<Option<Key<N>>, Error> {
+        (key < self.keys.max(self.in_flight_keys))
+            .then(|| self.derive_key_immutable(key))
+            .transpose()
+    }
+
+    /// Returns the state of a key.
+    pub fn key_state(&self, key: u64) -> KeyState {
+        if key < self.keys && key >= self.in_flight_keys {
+            KeyState::Truncated
+        } else if self.deleted.contains(&key) {
+            KeyState::Deleted
+        } else if self.updated_keys.contains(key) {
+            KeyState::UpdatedUncommitted
+        } else if key < self.keys {
+            KeyState::Committed
+        } else if key < self.in_flight_keys {
+            KeyState::AppendedUncommitted
+        } else {
+            KeyState::Unallocated
+        }
+    }
+
+    /// Returns the number of roots in the `Khf`'s root list.
+    pub fn fragmentation(&self) -> u64 {
+        self.roots.len() as u64
+    }
+
+    /// Returns the topology of the `Khf`.
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Errors with `Error::IncompatibleTopology` unless a topology is compatible with the `Khf`'s,
+    /// e.g., before importing a delegation or syncing with a replica, since nodes derived under a
+    /// different topology would silently derive the wrong keys.
+    pub fn check_topology(&self, topology: &Topology) -> Result<(), Error> {
+        if self.topology.compatible_with(topology) {
+            Ok(())
+        } else {
+            Err(Error::IncompatibleTopology)
+        }
+    }
+
+    /// Returns the current epoch, i.e., the number of commits the `Khf` has gone through.
+    pub fn epoch(&self) -> Epoch {
+        Epoch(self.epoch)
+    }
+
+    /// Computes the digest of the `Khf`'s persisted state (its topology, roots, and key count).
+    /// The digest can be stored elsewhere (e.g., in a signed superblock) to cross-check a
+    /// persisted `Khf` with `verify_persisted()`.
+    pub fn digest(&self) -> Key<N> {
+        let mut digest = Digest::<H, N>::new();
+        digest.topology(&self.topology);
+        digest.node(&self.appending_root);
+        for root in self.roots.iter() {
+            digest.node(root);
+        }
+        digest.u64(self.keys);
+        digest.u64(self.epoch);
+        digest.u64(self.deleted.len() as u64);
+        for key in &self.deleted {
+            digest.u64(*key);
+        }
+        digest.u64(self.leases.len() as u64);
+        for (range, expires) in &self.leases {
+            digest.u64(range.start);
+            digest.u64(range.end);
+            digest.u64(*expires);
+        }
+        for (epoch, keys, roots) in &self.history {
+            digest.u64(*epoch);
+            digest.u64(*keys);
+            digest.u64(roots.len() as u64);
+            for root in roots.iter() {
+                digest.node(root);
+            }
+        }
+        digest.finish()
+    }
+
+    /// Checks that a `Khf` persisted with `bincode` has the expected digest. The persisted root
+    /// list is streamed, so the `Khf` is never fully loaded into memory.
+    pub fn verify_persisted(reader: impl Read, expected: &Key<N>) -> Result<bool, Error> {
+        let digest = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .deserialize_from_seed(PersistedDigest::<H, N>::new(), reader)?;
+        Ok(&digest == expected)
+    }
+
+    /// Persists the `Khf` as a root table, in which each root is checksummed on its own, so that a
+    /// partially corrupt table can still be recovered with `recover_table()`.
+    pub fn persist_table(&self, writer: impl std::io::Write) -> Result<(), Error> {
+        let header = Header {
+            topology: self.topology.clone(),
+            appending_root: (*self.appending_root).clone(),
+            keys: self.keys,
+            epoch: self.epoch,
+            deleted: self.deleted.clone(),
+            leases: self.leases.clone(),
+            roots: self.roots.len() as u64,
+            record_len: bincode::serialized_size(&*self.appending_root)?,
+        };
+        table::write(writer, &header, &self.roots)
+    }
+
+    /// Loads a `Khf` from a root table, failing if any part of it is corrupt.
+    pub fn load_table(reader: impl Read, rng: impl RngCore + CryptoRng) -> Result<Self, Error> {
+        let (khf, recovery) = Self::recover_table(reader, rng)?;
+        if recovery.corrupt_roots > 0 {
+            return Err(Error::Corrupt);
+        }
+        Ok(khf)
+    }
+
+    /// Loads a `Khf` from a root table, recovering every valid root instead of failing if some are
+    /// corrupt. Keys that can't be recovered are derived from fresh roots and marked as updated, so
+    /// they are rotated at the next commit. Fails if the table's header is corrupt.
+    pub fn recover_table(
+        reader: impl Read,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<(Self, Recovery), Error> {
+        let Table {
+            header,
+            roots: records,
+        } = table::read(reader)?;
+
+        let mut khf = Self::with_topology(header.topology, &mut rng);
+        *khf.appending_root = header.appending_root;
+        khf.keys = header.keys;
+        khf.in_flight_keys = header.keys;
+        khf.epoch = header.epoch;
+        khf.deleted = header.deleted;
+        khf.leases = header.leases;
+
+        let mut recovery = Recovery {
+            roots: records.len() as u64,
+            ..Default::default()
+        };
+
+        // Keep the valid roots that follow each other, and fill the gaps between them with fresh
+        // roots.
+        let mut roots = Vec::with_capacity(records.len());
+        let mut next = 0;
+        let single = records.len() == 1;
+        for record in records {
+            let root = record.filter(|root| {
+                if root.pos == (0, 0) {
+                    // A consolidated root covers every committed key, so it must be the only root.
+                    single
+                } else {
+                    0 < root.pos.0
+                        && root.pos.0 < khf.topology.height()
+                        && root.pos.1
+                            < khf.topology.max_leaves() / khf.topology.descendants(root.pos.0)
+                        && next <= khf.topology.start(root.pos)
+                }
+            });
+            let Some(root) = root else {
+                recovery.corrupt_roots += 1;
+                continue;
+            };
+
+            let (start, end) = khf.root_range(&root);
+            let start = start.min(khf.keys);
+            if next < start {
+                roots.append(&mut khf.fresh_roots(next..start, &mut rng));
+                recovery.lost.push(next..start);
+            }
+            next = end;
+            roots.push(root);
+        }
+        if next < khf.keys || roots.is_empty() {
+            roots.append(&mut khf.fresh_roots(next..khf.keys, &mut rng));
+            if next < khf.keys {
+                recovery.lost.push(next..khf.keys);
+            }
+        }
+        khf.roots = Arc::new(roots);
+
+        for range in &recovery.lost {
+            khf.updated_keys.insert_range(range.clone());
+            khf.updated_keys_dirty = true;
+        }
+
+        khf.lock_keys();
+        Ok((khf, recovery))
+    }
+
+    // Returns fresh roots covering a range of keys, or a fresh consolidated root if it's empty.
+    fn fresh_roots(&self, keys: Range<u64>, mut rng: impl RngCore + CryptoRng) -> Vec<Node<H, N>> {
+        let root = Node::with_rng(&mut rng);
+        if keys.is_empty() {
+            vec![root]
+        } else {
+            root.coverage(&self.topology, self.root_level, keys.start, keys.end)
+        }
+    }
+
+    /// Returns statistics describing the shape of the `Khf`.
+    pub fn stats(&self) -> KhfStats {
+        let mut stats = KhfStats {
+            roots_per_level: vec![0; self.topology.height() as usize],
+            roots: self.roots.len() as u64,
+            updated_keys: self.updated_keys.len(),
+            cached_keys: self.cache.len(),
+            ..Default::default()
+        };
+
+        for root in self.roots.iter() {
+            stats.roots_per_level[root.pos.0 as usize] += 1;
+
+            let (start, end) = self.root_range(root);
+
+            match stats.covered.last_mut() {
+                Some((_, prev_end)) if *prev_end == start => *prev_end = end,
+                _ => stats.covered.push((start, end)),
+            }
+        }
+
+        stats
+    }
+
+    /// Returns an iterator over the roots of the `Khf` as `(level, offset, start, end)` tuples,
+    /// where `start..end` is the range of keys covered by the root. Root keys are redacted.
+    pub fn roots(&self) -> impl Iterator<Item = (u64, u64, u64, u64)> + '_ {
+        self.roots.iter().map(|root| {
+            let (start, end) = self.root_range(root);
+            (root.pos.0, root.pos.1, start, end)
+        })
+    }
+
+    /// Returns a view of the `Khf` that draws the trees of its roots with their keys redacted. By
+    /// default every leaf is drawn, so bound it with `max_depth()` or `range()` for wide topologies.
+    pub fn display(&self) -> Tree<'_, H, N> {
+        Tree::new(&self.roots, &self.topology, false)
+    }
+
+    /// Returns a view of the `Khf` like `display()`, but with every key. Only use this when
+    /// debugging.
+    pub fn dump_unredacted(&self) -> Tree<'_, H, N> {
+        Tree::new(&self.roots, &self.topology, true)
+    }
+
+    /// Draws the roots of the `Khf` as a Graphviz DOT graph, e.g., to visualize fragmentation.
+    /// Each root is labeled with its position, the range of keys it covers, and a fingerprint of
+    /// its key that doesn't reveal the key.
+    pub fn to_dot(&self) -> String {
+        node::to_dot(
+            "khf",
+            self.roots.iter().map(|root| {
+                let (start, end) = self.root_range(root);
+                (root, start..end)
+            }),
+        )
+    }
+
+    /// Returns an iterator over the roots of the `Khf` like `roots()`, but with each root's key.
+    pub fn roots_unredacted(&self) -> impl Iterator<Item = (u64, u64, u64, u64, Key<N>)> + '_ {
+        self.roots()
+            .zip(self.roots.iter())
+            .map(|((level, offset, start, end), root)| (level, offset, start, end, root.key))
+    }
+
+    // Returns the range of keys covered by a root.
+    fn root_range(&self, root: &Node<H, N>) -> (u64, u64) {
+        // A consolidated root covers every committed key.
+        if root.pos == (0, 0) {
+            (0, self.keys)
+        } else {
+            self.topology.range(root.pos)
+        }
+    }
+
+    // Considers every committed key in flight, which a deserialized `Khf` doesn't know about.
+    pub(crate) fn restore_in_flight_keys(&mut self) {
+        self.in_flight_keys = self.in_flight_keys.max(self.keys);
+    }
+
+    /// Returns `true` if the `Khf` is consolidated.
+    pub fn is_consolidated(&self) -> bool {
+        self.roots.len() == 1 && self.roots[0].pos == (0, 0)
+    }
+
+    /// Returns `true` if the key has been updated since the last epoch.
+    pub fn is_updated(&self, key: u64) -> bool {
+        self.updated_keys.contains(key)
+    }
+
+    /// Deletes a key. Unlike an update, which revokes the key's current value, deletion marks the
+    /// key as permanently removed: its value is revoked at the next commit, and deriving or
+    /// updating it errors until it is truncated and appended again.
+    pub(crate) fn delete(&mut self, key: u64) -> Result<(), Error> {
+        let max = self.keys.max(self.in_flight_keys);
+        if key >= max {
+            return Err(Error::KeyOutOfRange { key, max });
+        }
+        self.updated_keys.insert(key);
+        self.updated_keys_dirty = true;
+        self.deleted.insert(key);
+        Ok(())
+    }
+
+    /// Returns `true` if the key has been deleted.
+    pub fn is_deleted(&self, key: u64) -> bool {
+        self.deleted.contains(&key)
+    }
+
+    /// The keys that have been updated since the last epoch
+    pub fn updated_keys(&self) -> &RangeSet {
+        &self.updated_keys
+    }
+
+    /// The keys that have been updated since the last epoch
+    pub fn updated_keys_mut(&mut self) -> &mut RangeSet {
+        &mut self.updated_keys
+    }
+
+    /// Marks updated keys as clean (i.e., has been persisted).
+    /// Returns whether or not the updated keys were dirty before cleaning.
+    pub fn fetch_clean_updated_keys(&mut self) -> bool {
+        let res = self.updated_keys_dirty;
+        self.updated_keys_dirty = false;
+        res
+    }
+
+    /// Pins a range of keys so that consolidation leaves their roots untouched, splitting the roots
+    /// around them as needed. Pinned keys can still be updated, and pins aren't persisted.
+    pub(crate) fn pin(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        // Absorb any pinned ranges that overlap or touch the new one.
+        let (mut start, mut end) = (range.start, range.end);
+        self.pinned.retain(|pinned| {
+            if pinned.end < start || end < pinned.start {
+                return true;
+            }
+            start = start.min(pinned.start);
+            end = end.max(pinned.end);
+            false
+        });
+
+        let index = self.pinned.partition_point(|pinned| pinned.start < start);
+        self.pinned.insert(index, start..end);
+    }
+
+    /// Unpins a range of keys, allowing consolidation to replace their roots again.
+    pub(crate) fn unpin(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let mut pinned = Vec::with_capacity(self.pinned.len() + 1);
+        for pin in self.pinned.drain(..) {
+            if pin.start < range.start {
+                pinned.push(pin.start..pin.end.min(range.start));
+            }
+            if range.end < pin.end {
+                pinned.push(pin.start.max(range.end)..pin.end);
+            }
+        }
+        self.pinned = pinned;
+    }
+
+    /// Returns `true` if the key is pinned.
+    pub fn is_pinned(&self, key: u64) -> bool {
+        let index = self.pinned.partition_point(|pinned| pinned.end <= key);
+        self.pinned
+            .get(index)
+            .is_some_and(|pinned| pinned.contains(&key))
+    }
+
+    // Returns the sub-ranges of a range of keys that aren't pinned.
+    fn unpinned(&self, start: u64, end: u64) -> Vec<Range<u64>> {
+        let mut ranges = Vec::new();
+        let mut next = start;
+        for pinned in &self.pinned {
+            if end <= pinned.start {
+                break;
+            }
+            if next < pinned.start {
+                ranges.push(next..pinned.start);
+            }
+            next = next.max(pinned.end);
+        }
+        if next < end {
+            ranges.push(next..end);
+        }
+        ranges
+    }
+
+    /// Consolidates the `Khf` and returns the affected keys. Pinned keys are never affected.
+    pub(crate) fn consolidate_keys(
+        &mut self,
+        mechanism: Consolidation,
+        rng: impl RngCore + CryptoRng,
+    ) -> Vec<u64> {
+        self.consolidate_ranges(mechanism, rng)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Consolidates the `Khf` and returns the affected keys. Pinned keys are never affected.
+    #[deprecated(note = "use the guard returned by `Khf::open_epoch()`")]
+    pub fn consolidate(
+        &mut self,
+        mechanism: Consolidation,
+        rng: impl RngCore + CryptoRng,
+    ) -> Vec<u64> {
+        self.consolidate_keys(mechanism, rng)
+    }
+
+    /// Consolidates the `Khf` like `consolidate_keys()`, but reports the affected keys compactly.
+    pub(crate) fn consolidate_report(
+        &mut self,
+        mechanism: Consolidation,
+        rng: impl RngCore + CryptoRng,
+    ) -> Report {
+        let keys = 0..self.keys;
+        Report::new(keys, self.consolidate_ranges(mechanism, rng))
+    }
+
+    // Consolidates the `Khf` and returns the sorted, disjoint ranges of affected keys.
+    fn consolidate_ranges(
+        &mut self,
+        mechanism: Consolidation,
+        rng: impl RngCore + CryptoRng,
+    ) -> Vec<Range<u64>> {
+        let affected = match mechanism {
+            Consolidation::Full => self.consolidate_full(rng),
+            Consolidation::Leveled { level } => self.consolidate_leveled(level, rng),
+            Consolidation::Ranged { start, end } => self.consolidate_ranged(start, end, rng),
+            Consolidation::RangedLeveled { level, start, end } => {
+                self.consolidate_ranged_leveled(level, start, end, rng)
+            }
+        };
+        self.lock_keys();
+        affected
+    }
+
+    /// Returns the positions of the minimal set of roots of a certain level covering a range of
+    /// keys, i.e., the roots ranged consolidation would produce, without mutating the `Khf`.
+    ///
+    /// Panics if `level` isn't between 1 and the height of the `Khf`'s topology (exclusive).
+    pub fn plan_coverage(&self, level: u64, start: u64, end: u64) -> Vec<Pos> {
+        self.topology.coverage(level, start, end).collect()
+    }
+
+    /// Aligns a range of keys to the boundaries of roots of a certain level. Ranged consolidation
+    /// of an aligned range produces only roots of that level, whereas an unaligned range also
+    /// produces finer roots at its edges.
+    pub fn align_range(&self, range: Range<u64>, level: u64) -> Range<u64> {
+        if level == 0 {
+            return 0..self.keys.max(range.end);
+        }
+        let leaves = self.topology.descendants(level);
+        let start = range.start - range.start % leaves;
+        let end = range.end.div_ceil(leaves).saturating_mul(leaves);
+        start..end
+    }
+
+    // Consolidates back into a single root.
+    fn consolidate_full(&mut self, rng: impl RngCore + CryptoRng) -> Vec<Range<u64>> {
+        self.consolidate_leveled(0, rng)
+    }
+
+    // Consolidates to roots of a certain level.
+    fn consolidate_leveled(
+        &mut self,
+        level: u64,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Vec<Range<u64>> {
+        // A single root would change pinned keys, so consolidate around them as coarsely as
+        // possible instead.
+        if !self.pinned.is_empty() {
+            return self.consolidate_ranged_leveled(level.max(1), 0, self.keys, rng);
+        }
+
+        let affected = std::iter::once(0..self.keys).collect();
+
+        let node = Node::with_rng(&mut rng);
+        self.replace_keys(level, 0, self.keys, node);
+
+        // Unmark keys as updated and update the whole range of keys.
+        self.updated_keys.clear();
+        self.updated_keys_dirty = true;
+
+        affected
+    }
+
+    // Consolidates the roots for a range of keys.
+    fn consolidate_ranged(
+        &mut self,
+        start: u64,
+        end: u64,
+        rng: impl RngCore + CryptoRng,
+    ) -> Vec<Range<u64>> {
+        self.consolidate_ranged_leveled(self.root_level, start, end, rng)
+    }
+
+    // Consolidates the roots for a range of keys to roots of a certain level.
+    fn consolidate_ranged_leveled(
+        &mut self,
+        level: u64,
+        start: u64,
+        end: u64,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Vec<Range<u64>> {
+        // Update the range of keys, skipping over pinned keys.
+        let affected = self.unpinned(start, end);
+        for range in &affected {
+            let node = Node::with_rng(&mut rng);
+            self.replace_keys(level, range.start, range.end, node);
+        }
+
+        // The consolidated range of keys shouldn't be considered as updated.
+        for range in &affected {
+            self.updated_keys.remove_range(range.clone());
+            self.updated_keys_dirty = true;
+        }
+
+        affected
+    }
+
+    /// Commits the `Khf`, invoking a callback with each updated key and its pre-commit value.
+    /// This avoids collecting the updated keys when they can be consumed directly.
+    pub(crate) fn commit_with<F>(
+        &mut self,
+        mut rng: impl RngCore + CryptoRng,
+        mut f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, Key<N>),
+    {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_coverage()?;
+
+        // Retain the root list of the epoch this commit ends.
+        if self.retained_epochs > 0 {
+            self.history
+                .push_back((self.epoch, self.keys, self.roots.clone()));
+            self.trim_history();
+        }
+
+        // Rotate the keys of leases that expire with this commit.
+        let epoch = self.epoch + 1;
+        for (range, _) in self.leases.iter().filter(|(_, expires)| *expires <= epoch) {
+            self.updated_keys
+                .insert_range(range.start..range.end.min(self.in_flight_keys));
+            self.updated_keys_dirty = true;
+        }
+        self.leases.retain(|(_, expires)| *expires > epoch);
+
+        // We're effectively getting rid of the tree, so consolidate to a new root.
+        if self.in_flight_keys == 0 {
+            self.report_updated_keys(&mut f)?;
+
+            let node = Node::with_rng(&mut rng);
+            self.replace_keys(0, 0, 0, node);
+        }
+        // We need to append keys.
+        else if self.in_flight_keys >= self.keys {
+            // If we've updated every single key since the last commit, we can consolidate
+            // everything to a new root.
+            if self.updated_keys.len() == self.in_flight_keys {
+                self.report_updated_keys(&mut f)?;
+
+                let node = Node::with_rng(&mut rng);
+                self.replace_keys(0, 0, 0, node);
+            }
+            // Otherwise, we need to fragment in appended keys and then updated keys.
+            else {
+                self.report_updated_keys(&mut f)?;
+
+                // Fragment in the appended keys.
+                self.replace_keys(
+                    self.root_level,
+                    self.keys,
+                    self.in_flight_keys,
+                    (*self.appending_root).clone(),
+                );
+
+                // Fragment in updated keys.
+                self.replace_updated_keys(&mut rng);
+            }
+        }
+        // We need to truncate keys.
+        else {
+            // We can forget about updated keys that have been truncated.
+            self.updated_keys.truncate(self.in_flight_keys);
+
+            // If we've touched every key post-truncation, we can just consolidate to a new root.
+            if self.updated_keys.len() == self.in_flight_keys {
+                self.report_updated_keys(&mut f)?;
+
+                let node = Node::with_rng(&mut rng);
+                self.replace_keys(0, 0, 0, node);
+            }
+            // Otherwise, we'll need to actually truncate something.
+            else {
+                self.report_updated_keys(&mut f)?;
+                self.truncate_roots(self.in_flight_keys)?;
+
+                // Fragment in updated keys.
+                self.replace_updated_keys(&mut rng);
+            }
+        }
+
+        // Clear out our cache, its statistics, and the derivations charged against the quota.
+        self.cache.clear();
+        self.cache.reset_stats();
+        self.derivations.clear();
+
+        // Get a new appending root, and update our known number of keys.
+        *self.appending_root = Node::with_rng(&mut rng);
+        self.keys = self.in_flight_keys;
+        self.truncated_keys = None;
+        self.epoch += 1;
+
+        // Truncated keys are no longer deleted, since they can be appended again.
+        self.deleted.retain(|key| *key < self.keys);
+
+        // The root list may have been reallocated, so it needs to be locked again.
+        self.lock_keys();
+
+        // Clear out the updated keys.
+        self.updated_keys.clear();
+        self.updated_keys_dirty = true;
+
+        Ok(())
+    }
+
+    /// Opens the current epoch, returning a guard through which its operations are performed.
+    pub fn open_epoch(&mut self) -> OpenEpoch<'_, H, N> {
+        OpenEpoch::new(self)
+    }
+
+    /// Commits the `Khf` like `commit()`, but with an entropy source that may report insufficient
+    /// entropy through `RngCore::try_fill_bytes()` (e.g., early in boot on embedded devices). The
+    /// source is read once, for a seed that the commit's randomness is expanded from. If that
+    /// fails, the commit is deferred instead of blocking or panicking: the epoch stays open with
+    /// every update pending, the `Khf` is flagged as degraded until a commit succeeds, and
+    /// `Ok(None)` is returned.
+    pub(crate) fn try_commit(
+        &mut self,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Option<Vec<(u64, Key<N>)>>, Error> {
+        let mut seed = [0; N];
+        if rng.try_fill_bytes(&mut seed).is_err() {
+            self.degraded = true;
+            return Ok(None);
+        }
+
+        let res = self.commit(KdfRng::<H, N>::new(&seed, self.epoch + 1));
+        seed.zeroize();
+        let res = res?;
+        self.degraded = false;
+        Ok(Some(res))
+    }
+
+    /// Returns `true` if the last commit was deferred by `ClosingEpoch::try_commit()` for lack of
+    /// entropy, in which case updated keys haven't been revoked yet.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Commits the `Khf`, reporting the keys revoked by the commit compactly instead of enumerating
+    /// them along with their values.
+    pub(crate) fn commit_report(&mut self, rng: impl RngCore + CryptoRng) -> Result<Report, Error> {
+        let mut revoked = Vec::new();
+        self.commit_with(rng, |key, _| revoked.push(key))?;
+        Ok(Report::from_keys(0..self.keys, revoked))
+    }
+
+    /// Rotates every key (e.g., for master key rotation) by updating them all and committing,
+    /// which consolidates the `Khf` to a fresh root. Returns every key along with its
+    /// pre-rotation value, sorted by key. Keys deleted in earlier epochs are rotated without being
+    /// returned.
+    pub(crate) fn rekey_all(
+        &mut self,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(u64, Key<N>)>, Error> {
+        let mut next = 0;
+        for key in self.deleted.range(..self.in_flight_keys) {
+            self.updated_keys.insert_range(next..*key);
+            next = key + 1;
+        }
+        self.updated_keys.insert_range(next..self.in_flight_keys);
+        self.updated_keys_dirty = true;
+
+        let mut old = Vec::new();
+        self.commit_with(&mut rng, |key, value| old.push((key, value)))?;
+
+        // Deleted keys keep the commit from consolidating on its own.
+        if !self.is_consolidated() {
+            self.replace_keys(0, 0, 0, Node::with_rng(&mut rng));
+            self.lock_keys();
+        }
+
+        old.sort_unstable_by_key(|(key, _)| *key);
+        Ok(old)
+    }
+
+    /// Commits the `Khf`, returning each updated key along with its pre-commit and post-commit
+    /// values, i.e., the keys needed to decrypt and re-encrypt data.
+    pub(crate) fn commit_full(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(u64, Key<N>, Key<N>)>, Error> {
+        let mut old = Vec::new();
+        self.commit_with(rng, |key, value| old.push((key, value)))?;
+        old.into_iter()
+            .map(|(key, value)| Ok((key, value, self.derive_key_immutable(key)?)))
+            .collect()
+    }
+
+    /// Replays a journal of epochs, each a list of operations, as a single commit. Returns the keys
+    /// revoked in each epoch, i.e., the keys that committing each epoch individually would have
+    /// reported. The epoch is advanced once for each replayed epoch. The `Khf` is left unchanged if
+    /// the replay errors, e.g., with `Error::ReadOnly` for a delegated `Khf`.
+    pub(crate) fn replay_epochs<E, I>(
+        &mut self,
+        epochs: E,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<Vec<u64>>, Error>
+    where
+        E: IntoIterator<Item = I>,
+        I: IntoIterator<Item = EpochOp>,
+    {
+        // Check what the commit would reject before touching any pending state.
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_coverage()?;
+
+        let mut keys = self.in_flight_keys;
+        let mut min_keys = keys;
+        let mut updated = self.updated_keys.clone();
+        let mut epoch_updated = self.updated_keys.clone();
+        let mut revoked = Vec::new();
+
+        for epoch in epochs {
+            for op in epoch {
+                match op {
+                    EpochOp::Update(key) => {
+                        if self.strict && key >= keys {
+                            return Err(Error::KeyOutOfRange { key, max: keys });
+                        }
+                        epoch_updated.insert(key);
+                        keys = keys.max(key + 1);
+                    }
+                    EpochOp::Append(n) => {
+                        keys += n;
+                    }
+                    EpochOp::Truncate(n) => {
+                        keys = n;
+                        min_keys = min_keys.min(n);
+                    }
+                }
+            }
+
+            // Updated keys that are truncated by the end of the epoch aren't revoked.
+            epoch_updated.truncate(keys);
+            revoked.push(epoch_updated.iter().collect());
+            updated.append(&mut epoch_updated);
+        }
+
+        if revoked.is_empty() {
+            return Ok(revoked);
+        }
+
+        // Keys that were truncated and then re-appended must be derived from fresh roots, so they
+        // are treated as updated along with the keys updated in any epoch.
+        updated.truncate(keys);
+        updated.insert_range(min_keys..keys.min(self.keys));
+
+        self.updated_keys = updated;
+        self.updated_keys_dirty = true;
+        self.in_flight_keys = keys;
+        self.in_flight_keys_dirty = true;
+        self.commit_with(rng, |_, _| {})?;
+        self.epoch += revoked.len() as u64 - 1;
+
+        Ok(revoked)
+    }
+
+    // Reports each updated key and its current value.
+    fn report_updated_keys(&self, f: &mut impl FnMut(u64, Key<N>)) -> Result<(), Error> {
+        for key in self.updated_keys.iter() {
+            f(key, self.derive_key_immutable(key)?);
+        }
+        Ok(())
+    }
+
+    /// Fragments the roots covering a range of keys down to roots of a certain level without
+    /// changing any keys. This is the inverse of consolidation, and is useful for limiting the
+    /// keys affected by scattered updates to the range.
+    ///
+    /// Panics if `level` isn't between 1 and the height of the `Khf`'s topology (exclusive).
+    pub(crate) fn fragment(&mut self, range: Range<u64>, level: u64) {
+        assert!(
+            0 < level && level < self.topology.height(),
+            "fragment level {level} is out of range"
+        );
+
+        // Only committed keys are covered by the root list.
+        let start = range.start;
+        let end = range.end.min(self.keys);
+        if start >= end {
+            return;
+        }
+
+        if self.is_consolidated() {
+            self.roots =
+                Arc::new(self.roots[0].coverage(&self.topology, self.root_level, 0, self.keys));
+        }
+
+        let mut roots = Vec::with_capacity(self.roots.len());
+        for root in Arc::unwrap_or_clone(mem::take(&mut self.roots)) {
+            let (root_start, root_end) = self.topology.range(root.pos);
+
+            // Roots outside the range or at a fine enough level don't need to be split.
+            if root_end <= start || end <= root_start || root.pos.0 >= level {
+                roots.push(root);
+                continue;
+            }
+
+            // Split the root as little as possible outside of the range.
+            let (inner_start, inner_end) = (start.max(root_start), end.min(root_end));
+            let outer_level = root.pos.0 + 1;
+            if root_start < inner_start {
+                roots.append(&mut root.coverage(
+                    &self.topology,
+                    outer_level,
+                    root_start,
+                    inner_start,
+                ));
+            }
+            roots.append(&mut root.coverage(&self.topology, level, inner_start, inner_end));
+            if inner_end < root_end {
+                roots.append(&mut root.coverage(&self.topology, outer_level, inner_end, root_end));
+            }
+        }
+
+        self.roots = Arc::new(roots);
+        self.lock_keys();
+    }
+
+    /// Splits the `Khf` at a key into two independent forests, the first covering the keys before
+    /// `at` and the second covering the rest. Both forests keep the IDs of their keys, so the keys
+    /// before `at` in the second forest are replaced with fresh keys. Keys appended since the last
+    /// commit are fragmented into the root list, since the forests can't share an appending root.
+    /// Errors if the roots don't cover the keys.
+    pub(crate) fn split(
+        mut self,
+        at: u64,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<(Self, Self), Error> {
+        self.check_coverage()?;
+        if self.in_flight_keys > self.keys {
+            self.replace_keys(
+                self.root_level,
+                self.keys,
+                self.in_flight_keys,
+                (*self.appending_root).clone(),
+            );
+            self.keys = self.in_flight_keys;
+        }
+
+        let mut left = self.clone();
+        let mut right = self;
+
+        // The first forest forgets about the keys from `at` onwards.
+        if at == 0 {
+            left.replace_keys(0, 0, 0, Node::with_rng(&mut rng));
+        } else if at < left.keys {
+            left.truncate_roots(at)?;
+        }
+        left.keys = left.keys.min(at);
+        left.in_flight_keys = left.in_flight_keys.min(at);
+        left.updated_keys.truncate(at);
+        left.deleted.retain(|key| *key < at);
+
+        // The second forest gets fresh keys before `at`.
+        if at >= right.keys {
+            right.replace_keys(0, 0, 0, Node::with_rng(&mut rng));
+        } else if at > 0 {
+            right.replace_keys(right.root_level, 0, at, Node::with_rng(&mut rng));
+        }
+        right.updated_keys.remove_range(0..at);
+        right.deleted.retain(|key| *key >= at);
+
+        for khf in [&mut left, &mut right] {
+            *khf.appending_root = Node::with_rng(&mut rng);
+            khf.in_flight_keys_dirty = true;
+            khf.updated_keys_dirty = true;
+            khf.cache.clear();
+            khf.lock_keys();
+        }
+
+        Ok((left, right))
+    }
+
+    /// Appends the keyspace of another `Khf` after every key this one covers, returning the IDs
+    /// that its keys are remapped to. The merged keys keep their values, but since keys are bound
+    /// to their positions, each one gets its own root until it's consolidated. Errors if the
+    /// forests have different topologies or the merged keys wouldn't fit, or with
+    /// `Error::Truncated` if either forest has a truncation pending, since the merge would undo it.
+    pub(crate) fn merge(&mut self, other: Self) -> Result<Range<u64>, Error> {
+        self.check_topology(&other.topology)?;
+        for khf in [&*self, &other] {
+            if khf.in_flight_keys < khf.keys {
+                return Err(Error::Truncated(khf.in_flight_keys));
+            }
+        }
+
+        let start = self.keys.max(self.in_flight_keys);
+        let len = other.keys.max(other.in_flight_keys);
+        let end = start
+            .checked_add(len)
+            .filter(|end| *end <= self.topology.max_leaves())
+            .ok_or(Error::KeyOutOfRange {
+                key: start.saturating_add(len),
+                max: self.topology.max_leaves(),
+            })?;
+
+        let mut merged = other.leaf_roots(0..len, start)?;
+
+        // Appended keys are fragmented into the root list so the merged keys can follow them.
+        if self.in_flight_keys > self.keys {
+            self.replace_keys(
+                self.root_level,
+                self.keys,
+                self.in_flight_keys,
+                (*self.appending_root).clone(),
+            );
+        }
+        if self.is_consolidated() {
+            self.roots =
+                Arc::new(self.roots[0].coverage(&self.topology, self.root_level, 0, start));
+        }
+
+        Arc::make_mut(&mut self.roots).append(&mut merged);
+        for range in other.updated_keys.ranges() {
+            self.updated_keys
+                .insert_range(start + range.start..start + range.end);
+        }
+        self.deleted
+            .extend(other.deleted.iter().map(|key| start + key));
+        self.leases.extend(
+            other
+                .leases
+                .iter()
+                .map(|(range, expires)| (start + range.start..start + range.end, *expires)),
+        );
+
+        self.keys = end;
+        self.in_flight_keys = end;
+        self.in_flight_keys_dirty = true;
+        self.updated_keys_dirty = true;
+        self.cache.clear();
+        self.lock_keys();
+
+        Ok(start..end)
+    }
+
+    /// Extracts the keys in a range into a new `Khf`, with their IDs rebased to start at zero. As
+    /// with `merge()`, each extracted key gets its own root, and no other keys are shared. Deleted
+    /// keys stay deleted, and are replaced with fresh keys so they can't be recovered.
+    pub fn extract(
+        &self,
+        range: Range<u64>,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Self, Error> {
+        let mut khf =
+            Self::with_topology(self.topology.clone(), &mut rng).with_strict_appends(self.strict);
+        khf.lookup = self.lookup;
+        khf.root_level = self.root_level;
+        khf.cache.set_policy(self.cache.policy());
+
+        let start = range.start;
+        let end = range.end.min(self.keys.max(self.in_flight_keys));
+        if start >= end {
+            return Ok(khf);
+        }
+
+        khf.roots = Arc::new(self.leaf_roots(start..end, 0)?);
+        for key in self.deleted.range(start..end) {
+            let root = &mut Arc::make_mut(&mut khf.roots)[(key - start) as usize];
+            *root = Node::with_pos(root.pos, Node::<H, N>::with_rng(&mut rng).key);
+            khf.deleted.insert(key - start);
+        }
+
+        khf.keys = end - start;
+        khf.in_flight_keys = end - start;
+        khf.lock_keys();
+        Ok(khf)
+    }
+
+    /// Converts the `Khf` to a topology with different fanouts, keeping the IDs and current values
+    /// of its in-flight keys. Each key gets its own root, and every key is marked as updated, so
+    /// the next commit rotates the keys into the new shape and reports their old values, as with
+    /// `rekey_all()`. Deleted keys stay deleted, and are replaced with fresh keys so they can't be
+    /// recovered. Errors with `Error::InvalidTopology` if the fanouts are invalid.
+    pub fn retopologize(
+        &self,
+        fanouts: &[u64],
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Self, Error> {
+        let mut khf = Self::try_new(fanouts, &mut rng)?.with_strict_appends(self.strict);
+        khf.lookup = self.lookup;
+        khf.cache.set_policy(self.cache.policy());
+        khf.epoch = self.epoch;
+
+        let keys = self.in_flight_keys;
+        if keys == 0 {
+            return Ok(khf);
+        }
+        if keys > khf.topology.max_leaves() {
+            return Err(Error::KeyOutOfRange {
+                key: keys - 1,
+                max: khf.topology.max_leaves(),
+            });
+        }
+
+        khf.roots = Arc::new(
+            (0..keys)
+                .map(|key| {
+                    let value = if self.deleted.contains(&key) {
+                        Node::<H, N>::with_rng(&mut rng).key
+                    } else {
+                        self.derive_key_immutable(key)?
+                    };
+                    Ok(Node::with_pos(khf.topology.leaf_position(key), value))
+                })
+                .collect::<Result<_, Error>>()?,
+        );
+
+        khf.deleted = self.deleted.range(..keys).copied().collect();
+        let mut next = 0;
+        for key in &khf.deleted {
+            khf.updated_keys.insert_range(next..*key);
+            next = key + 1;
+        }
+        khf.updated_keys.insert_range(next..keys);
+        khf.updated_keys_dirty = true;
+
+        khf.keys = keys;
+        khf.in_flight_keys = keys;
+        khf.in_flight_keys_dirty = true;
+        khf.lock_keys();
+        Ok(khf)
+    }
+
+    // Returns a leaf root for each key in a range, relocated so the range starts at `to`.
+    fn leaf_roots(&self, keys: Range<u64>, to: u64) -> Result<Vec<Node<H, N>>, Error> {
+        let start = keys.start;
+        keys.map(|key| {
+            Ok(Node::with_pos(
+                self.topology.leaf_position(to + key - start),
+                self.derive_key_immutable(key)?,
+            ))
+        })
+        .collect()
+    }
+
+    /// Leases a range of keys for `ttl_epochs` epochs, returning the material needed to derive
+    /// them elsewhere. The leased keys are rotated by the commit that reaches the expiry epoch,
+    /// revoking the lease. Errors with `Error::KeyOutOfRange` if the range extends past the keys
+    /// in flight, as with `delegate()`.
+    pub(crate) fn lease(
+        &mut self,
+        range: Range<u64>,
+        ttl_epochs: u64,
+    ) -> Result<Lease<H, N>, Error> {
+        let in_flight = self.keys.max(self.in_flight_keys);
+        if range.end > in_flight {
+            return Err(Error::KeyOutOfRange {
+                key: range.end,
+                max: in_flight,
+            });
+        }
+
+        let start = range.start;
+        let end = range.end.max(start);
+        let committed = end.min(self.keys);
+
+        // Only export roots covering exactly the leased keys.
+        let mut roots = self.export_roots(DEFAULT_ROOT_LEVEL, start, committed);
+        if start.max(self.keys) < end {
+            roots.append(&mut self.appending_root.coverage(
+                &self.topology,
+                DEFAULT_ROOT_LEVEL,
+                start.max(self.keys),
+                end,
+            ));
+        }
+
+        // A lease too long to expire before the epoch overflows never expires.
+        let expires = self.epoch.saturating_add(ttl_epochs);
+        self.leases.push((start..end, expires));
+        Ok(Lease::new(
+            self.topology.clone(),
+            start..end,
+            expires,
+            roots,
+        ))
+    }
+
+    /// Delegates a range of keys, returning the minimal nodes no higher than a certain level that
+    /// cover exactly the range. Whoever holds the nodes can derive the delegated keys, and no
+    /// others, until they are updated. Unlike a lease, a delegation isn't tracked, so revoking it
+    /// means updating the delegated keys.
+    ///
+    /// Panics if `level` isn't between 1 and the height of the `Khf`'s topology (exclusive).
+    pub fn delegate(&self, range: Range<u64>, level: u64) -> Result<Vec<Node<H, N>>, Error> {
+        assert!(
+            0 < level && level < self.topology.height(),
+            "delegation level {level} is out of range"
+        );
+
+        let in_flight = self.keys.max(self.in_flight_keys);
+        if range.end > in_flight {
+            return Err(Error::KeyOutOfRange {
+                key: range.end,
+                max: in_flight,
+            });
+        }
+
+        let mut nodes = self.export_roots(level, range.start, range.end.min(self.keys));
+        if range.start.max(self.keys) < range.end {
+            nodes.append(&mut self.appending_root.coverage(
+                &self.topology,
+                level,
+                range.start.max(self.keys),
+                range.end,
+            ));
+        }
+        Ok(nodes)
+    }
+
+    // Returns the minimal roots no higher than a certain level covering exactly a range of
+    // committed keys.
+    fn export_roots(&self, level: u64, start: u64, end: u64) -> Vec<Node<H, N>> {
+        let mut roots = Vec::new();
+        if start >= end {
+            return roots;
+        }
+
+        if self.is_consolidated() {
+            roots = self.roots[0].coverage(&self.topology, level, start, end);
+        } else {
+            for root in self.roots.iter() {
+                let (root_start, root_end) = self.topology.range(root.pos);
+                if root_start < end && start < root_end {
+                    roots.append(&mut root.coverage(
+                        &self.topology,
+                        level,
+                        start.max(root_start),
+                        end.min(root_end),
+                    ));
+                }
+            }
+        }
+        roots
+    }
+
+    /// Transfers a range of committed keys to another `Khf` with the same topology, which takes
+    /// over deriving them. The minimal roots covering the range are spliced into the other `Khf`,
+    /// and the keys are marked as updated in this `Khf`, so they are rotated at its next commit.
+    /// Returns a receipt that either `Khf` can check with `verify_receipt()`.
+    pub(crate) fn transfer(
+        &mut self,
+        range: Range<u64>,
+        other: &mut Self,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<Receipt<N>, Error> {
+        self.check_topology(&other.topology)?;
+        if range.end > self.keys {
+            return Err(Error::KeyOutOfRange {
+                key: range.end,
+                max: self.keys,
+            });
+        }
+
+        let roots = self.export_roots(DEFAULT_ROOT_LEVEL, range.start, range.end);
+
+        // Make room for the range in the other `Khf`, fragmenting in its appended keys and giving
+        // fresh keys to any keys before the range that it doesn't provide yet.
+        if other.in_flight_keys > other.keys {
+            other.replace_keys(
+                other.root_level,
+                other.keys,
+                other.in_flight_keys,
+                (*other.appending_root).clone(),
+            );
+            other.keys = other.in_flight_keys;
+        }
+        if other.is_consolidated() {
+            other.roots =
+                Arc::new(other.roots[0].coverage(&other.topology, other.root_level, 0, other.keys));
+        }
+
+        // Splice the roots in place of the other `Khf`'s roots for the range, splitting the ones
+        // straddling its boundaries as little as possible.
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        for root in Arc::unwrap_or_clone(mem::take(&mut other.roots)) {
+            let (root_start, root_end) = other.topology.range(root.pos);
+            if root_end <= range.start {
+                before.push(root);
+            } else if range.end <= root_start {
+                after.push(root);
+            } else {
+                let level = root.pos.0 + 1;
+                if root_start < range.start {
+                    before.append(&mut root.coverage(
+                        &other.topology,
+                        level,
+                        root_start,
+                        range.start,
+                    ));
+                }
+                if range.end < root_end {
+                    after.append(&mut root.coverage(&other.topology, level, range.end, root_end));
+                }
+            }
+        }
+        if other.keys < range.start {
+            before.append(&mut Node::with_rng(&mut rng).coverage(
+                &other.topology,
+                other.root_level,
+                other.keys,
+                range.start,
+            ));
+        }
+        before.extend(roots.iter().cloned());
+        before.append(&mut after);
+        if before.is_empty() {
+            before.push(Node::with_rng(&mut rng));
+        }
+        other.roots = Arc::new(before);
+
+        other.keys = other.keys.max(range.end);
+        other.in_flight_keys = other.keys;
+        other.in_flight_keys_dirty = true;
+        other.updated_keys.remove_range(range.clone());
+        other.updated_keys_dirty = true;
+        other.deleted.retain(|key| !range.contains(key));
+        other
+            .deleted
+            .extend(self.deleted.range(range.clone()).copied());
+        other.cache.clear();
+        other.lock_keys();
+
+        self.updated_keys.insert_range(range.clone());
+        self.updated_keys_dirty = true;
+
+        let mut digest = Digest::<H, N>::new();
+        digest.u64(range.start);
+        digest.u64(range.end);
+        for root in &roots {
+            digest.node(root);
+        }
+
+        Ok(Receipt {
+            range,
+            positions: roots.iter().map(|root| root.pos).collect(),
+            digest: digest.finish(),
+        })
+    }
+
+    /// Returns `true` if the `Khf` derives the keys covered by a transfer receipt as they were
+    /// transferred.
+    pub fn verify_receipt(&self, receipt: &Receipt<N>) -> bool {
+        let mut digest = Digest::<H, N>::new();
+        digest.u64(receipt.range.start);
+        digest.u64(receipt.range.end);
+
+        let mut next = receipt.range.start;
+        for pos in &receipt.positions {
+            let (start, end) = self.topology.range(*pos);
+            if start != next || end > self.keys {
+                return false;
+            }
+            next = end;
+
+            let Some(index) = self.root_index(start) else {
+                return false;
+            };
+            let root = &self.roots[index];
+            if root.pos != *pos && !self.topology.is_ancestor(root.pos, *pos) {
+                return false;
+            }
+            digest.node(&Node::with_pos(*pos, root.derive(&self.topology, *pos)));
+        }
+
+        next == receipt.range.end && ct::eq(&digest.finish(), &receipt.digest)
+    }
+
+    /// Creates an `Overlay` for recording speculative updates and appends against the `Khf`,
+    /// without cloning it.
+    pub fn overlay(&self) -> Overlay {
+        Overlay::new(self.epoch, self.in_flight_keys)
+    }
+
+    // Errors if an overlay was created before the last commit or a truncation since.
+    pub(crate) fn check_overlay(&self, overlay: &Overlay) -> Result<(), Error> {
+        if overlay.epoch == self.epoch && overlay.base_keys <= self.in_flight_keys {
+            Ok(())
+        } else {
+            Err(Error::StaleOverlay(overlay.epoch))
+        }
+    }
+
+    // Applies the changes recorded by an overlay, after checking that all of them apply.
+    pub(crate) fn merge_overlay(&mut self, overlay: Overlay) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_overlay(&overlay)?;
+        let in_flight_keys = self.in_flight_keys.max(overlay.in_flight_keys);
+        for &key in &overlay.updated {
+            self.check_derivable_with(key, in_flight_keys)?;
+        }
+
+        if in_flight_keys != self.in_flight_keys {
+            self.in_flight_keys = in_flight_keys;
+            self.in_flight_keys_dirty = true;
+        }
+        if !overlay.updated.is_empty() {
+            self.updated_keys.extend(overlay.updated);
+            self.updated_keys_dirty = true;
+        }
+
+        Ok(())
+    }
+
+    // Returns the fewest keys in flight since the last commit, if the `Khf` was truncated.
+    pub(crate) fn truncated_len(&self) -> Option<u64> {
+        self.truncated_keys
+    }
+
+    /// Truncates the `Khf` like `truncate_keys()`, but errors if it doesn't currently cover the
+    /// specified number of keys. Returns the keys that will be dropped at the next commit.
+    pub(crate) fn try_truncate(&mut self, keys: u64) -> Result<Vec<u64>, Error> {
+        let current = self.in_flight_keys;
+        if keys > current {
+            return Err(Error::KeyOutOfRange {
+                key: keys,
+                max: current,
+            });
+        }
+        self.truncate_keys(keys);
+        Ok((keys..current).collect())
+    }
+
+    /// Appends `n` keys to the `Khf`, returning the range of key IDs assigned to them. Appended
+    /// keys are derived from the appending root until the next commit.
+    pub(crate) fn append(&mut self, n: u64) -> Range<u64> {
+        let start = self.in_flight_keys;
+        self.in_flight_keys += n;
+        self.in_flight_keys_dirty = true;
+        start..self.in_flight_keys
+    }
+
+    /// Truncates the `Khf` so it only covers a specified number of keys.
+    pub(crate) fn truncate_keys(&mut self, keys: u64) {
+        self.truncated_keys = Some(self.truncated_keys.map_or(keys, |min| min.min(keys)));
+        self.in_flight_keys = keys;
+        self.in_flight_keys_dirty = true;
+    }
+
+    /// Truncates the `Khf` so it only covers a specified number of keys.
+    #[deprecated(note = "use the guard returned by `Khf::open_epoch()`")]
+    pub fn truncate(&mut self, keys: u64) {
+        self.truncate_keys(keys);
+    }
+
+    // Errors if the key is deleted or would be implicitly appended by a strict `Khf`.
+    fn check_derivable(&self, key: u64) -> Result<(), Error> {
+        self.check_derivable_with(key, self.in_flight_keys)
+    }
+
+    // Checks whether a key is derivable with a certain number of keys in flight.
+    pub(crate) fn check_derivable_with(&self, key: u64, in_flight_keys: u64) -> Result<(), Error> {
+        if self.deleted.contains(&key) {
+            Err(Error::Deleted(key))
+        } else if key >= self.topology.max_leaves() {
+            // The topology can't address the key.
+            Err(Error::KeyOutOfRange {
+                key,
+                max: self.topology.max_leaves(),
+            })
+        } else if self.read_only && (key >= self.keys || self.root_index(key).is_none()) {
+            // Delegated keys may not be contiguous.
+            Err(Error::OutOfRange(key))
+        } else if self.strict && key >= self.keys.max(in_flight_keys) {
+            Err(Error::KeyOutOfRange {
+                key,
+                max: self.keys.max(in_flight_keys),
+            })
+        } else if (in_flight_keys..self.keys).contains(&key) {
+            Err(Error::Truncated(key))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Searches for the index of the root covering a committed key.
+    fn root_index(&self, key: u64) -> Option<usize> {
+        match self.lookup {
+            RootLookup::Binary => self.root_index_binary(key),
+            RootLookup::Interpolation => self.root_index_interpolation(key),
+        }
+    }
+
+    fn root_index_binary(&self, key: u64) -> Option<usize> {
+        Self::find_root(&self.topology, &self.roots, key)
+    }
+
+    // Binary searches a root list for the root covering a key.
+    fn find_root(topology: &Topology, roots: &[Node<H, N>], key: u64) -> Option<usize> {
+        let pos = topology.leaf_position(key);
+        roots
+            .binary_search_by(|root| {
+                if topology.is_ancestor(root.pos, pos) {
+                    Ordering::Equal
+                } else if topology.end(root.pos) <= topology.start(pos) {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            })
+            .ok()
+    }
+
+    fn root_index_interpolation(&self, key: u64) -> Option<usize> {
+        if self.is_consolidated() {
+            return Some(0);
+        }
+
+        let (mut lo, mut hi) = (0, self.roots.len().checked_sub(1)?);
+        while lo <= hi {
+            let lo_start = self.topology.start(self.roots[lo].pos);
+            let hi_end = self.topology.end(self.roots[hi].pos);
+            if key < lo_start || hi_end <= key {
+                return None;
+            }
+
+            // Guess the index assuming roots between `lo` and `hi` cover similar ranges.
+            let guess = (key - lo_start) as u128 * (hi - lo + 1) as u128;
+            let mid = (lo + (guess / (hi_end - lo_start) as u128) as usize).min(hi);
+
+            let (start, end) = self.topology.range(self.roots[mid].pos);
+            if key < start {
+                hi = mid - 1;
+            } else if end <= key {
+                lo = mid + 1;
+            } else {
+                return Some(mid);
+            }
+        }
+
+        None
+    }
+
+    /// Derives a key.
+    fn derive_key(&mut self, key: u64) -> Result<Key<N>, Error> {
+        if key >= self.keys {
+            self.in_flight_keys = self.in_flight_keys.max(key + 1);
+            self.in_flight_keys_dirty = true;
+        }
+        self.derive_and_cache_key(key)
+    }
+
+    // Derives a key without implicitly appending it, erroring if no root covers it (e.g., in a
+    // corrupt persisted `Khf`).
+    fn derive_and_cache_key(&mut self, key: u64) -> Result<Key<N>, Error> {
+        let pos = self.topology.leaf_position(key);
+
+        // Derive the key from the appending root if it should be appended.
+        if key >= self.keys {
+            return Ok(self
+                .appending_root
+                .derive_and_cache(&self.topology, pos, &mut self.cache));
+        }
+
+        let index = self.root_index(key).ok_or(Error::NoCoverage(key))?;
+
+        Ok(self.roots[index].derive_and_cache(&self.topology, pos, &mut self.cache))
+    }
+
+    // Derives a key without caching anything, erroring if no root covers it (e.g., in a corrupt
+    // persisted `Khf`).
+    pub(crate) fn derive_key_immutable(&self, key: u64) -> Result<Key<N>, Error> {
+        let pos = self.topology.leaf_position(key);
+
+        if let Some(key) = self.cache.peek(&pos) {
+            return Ok(key);
+        }
+
+        // Derive the key from the appending root if it should be appended.
+        if key >= self.keys {
+            return Ok(self
+                .appending_root
+                .derive_cached(&self.topology, pos, &self.cache));
+        }
+
+        let index = self.root_index(key).ok_or(Error::NoCoverage(key))?;
+
+        Ok(self.roots[index].derive_cached(&self.topology, pos, &self.cache))
+    }
+
+    // Derives a key, caching the nodes along its path in a cache other than the `Khf`'s own.
+    #[cfg(feature = "parallel")]
+    fn derive_key_detached(&self, key: u64, cache: &mut Cache<N>) -> Result<Key<N>, Error> {
+        let pos = self.topology.leaf_position(key);
+
+        if let Some(key) = self.cache.peek(&pos) {
+            return Ok(key);
+        }
+
+        // Derive the key from the appending root if it should be appended.
+        if key >= self.keys {
+            return Ok(self.appending_root.derive_with(&self.topology, pos, cache));
+        }
+
+        let index = self.root_index(key).ok_or(Error::NoCoverage(key))?;
+
+        Ok(self.roots[index].derive_with(&self.topology, pos, cache))
+    }
+
+    /// Returns the keys that have been updated since the last epoch as sorted, disjoint
+    /// `(start, end)` ranges.
+    pub fn updated_key_ranges(&self) -> Vec<(u64, u64)> {
+        self.updated_keys
+            .ranges()
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    // Errors if the roots don't cover the committed keys contiguously from the first key, as they
+    // always do unless the `Khf` was persisted corrupt or hand-edited.
+    fn check_coverage(&self) -> Result<(), Error> {
+        if self.is_consolidated() {
+            return Ok(());
+        }
+
+        let mut end = 0;
+        for root in self.roots.iter() {
+            if root.pos.0 >= self.topology.height() || self.topology.start(root.pos) != end {
+                return Err(Error::NoCoverage(end));
+            }
+            end = self.topology.end(root.pos);
+        }
+
+        if self.roots.is_empty() || end < self.keys {
+            Err(Error::NoCoverage(end))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Truncates the root list so it only covers a specified number of keys.
+    fn truncate_roots(&mut self, keys: u64) -> Result<(), Error> {
+        // If we're consolidated, we'll just truncate using the top level root.
+        if self.is_consolidated() {
+            self.roots = Arc::new(self.roots[0].coverage(&self.topology, self.root_level, 0, keys));
+        }
+        // Otherwise, we need to find the root that covers the last key and truncate it.
+        else {
+            let index = self
+                .roots
+                .iter()
+                .position(|root| self.topology.end(root.pos) > keys)
+                .ok_or(Error::NoCoverage(keys))?;
+            let start = self.topology.start(self.roots[index].pos);
+            let roots = Arc::make_mut(&mut self.roots);
+            let root = roots.drain(index..).next().unwrap();
+
+            roots.append(&mut root.coverage(&self.topology, self.root_level, start, keys));
+        }
+
+        Ok(())
+    }
+
+    /// Replaces a range of keys with keys derived from a given root.
+    fn replace_keys(&mut self, level: u64, start: u64, end: u64, root: Node<H, N>) {
+        // Level 0 means consolidating to a single root.
+        if level == 0 {
+            self.roots = Arc::new(vec![root]);
+            return;
+        }
+
+        // Fragment the forest to cover all the keys.
+        if self.is_consolidated() {
+            self.roots = Arc::new(self.roots[0].coverage(
+                &self.topology,
+                level,
+                0,
+                self.in_flight_keys.max(end),
+            ));
+        }
+
+        Self::replace_roots(
+            &self.topology,
+            Arc::make_mut(&mut self.roots),
+            level,
+            start,
+            end,
+            root,
+        );
+    }
+
+    // Replaces a range of keys covered by a (fragmented) list of roots with keys derived from a
+    // given root.
+    fn replace_roots(
+        topology: &Topology,
+        old_roots: &mut Vec<Node<H, N>>,
+        level: u64,
+        start: u64,
+        end: u64,
+        root: Node<H, N>,
+    ) {
+        // Find the first root affected by the update.
+        let update_start = old_roots
+            .iter()
+            .position(|root| start < topology.end(root.pos))
+            .unwrap_or(old_roots.len() - 1);
+
+        // Find the last root affected by the update.
+        let trailing = end < topology.end(old_roots[old_roots.len() - 1].pos);
+        let update_end = if trailing {
+            old_roots
+                .iter()
+                .position(|root| end <= topology.end(root.pos))
+                .unwrap_or(old_roots.len())
+                + 1
+        } else {
+            old_roots.len()
+        };
+
+        // The affected roots are replaced by roots covering whatever they cover outside the
+        // range, and roots derived from the given root covering the range.
+        let first = &old_roots[update_start];
+        let mut updated = if topology.start(first.pos) != start {
+            first.coverage(topology, level, topology.start(first.pos), start)
+        } else {
+            Vec::new()
+        };
+        updated.append(&mut root.coverage(topology, level, start, end));
+        let last = &old_roots[update_end - 1];
+        if trailing && topology.end(last.pos) != end {
+            updated.append(&mut last.coverage(topology, level, end, topology.end(last.pos)));
+        }
+
+        // Splice the replacement roots in place, shifting the roots after them at most once.
+        old_roots.splice(update_start..update_end, updated);
+    }
+
+    // Fragments in fresh roots for the ranges of updated keys. The fresh roots are drawn from the
+    // RNG in order before any are used, so the result doesn't depend on whether the ranges are
+    // replaced in parallel.
+    fn replace_updated_keys(&mut self, mut rng: impl RngCore + CryptoRng) {
+        #[allow(unused_mut)]
+        let mut updates: Vec<(u64, u64, Node<H, N>)> = self
+            .updated_key_ranges()
+            .into_iter()
+            .map(|(start, end)| (start, end, Node::with_rng(&mut rng)))
+            .collect();
+
+        #[cfg(feature = "parallel")]
+        if self.replace_keys_parallel(&mut updates) {
+            return;
+        }
+
+        for (start, end, root) in updates {
+            self.replace_keys(self.root_level, start, end, root);
+        }
+    }
+
+    // Replaces ranges of keys in parallel, one task per top-level subtree, if there's more than
+    // one subtree to work on. Returns `false` without replacing anything otherwise.
+    #[cfg(feature = "parallel")]
+    fn replace_keys_parallel(&mut self, updates: &mut Vec<(u64, u64, Node<H, N>)>) -> bool {
+        let width = self.topology.end((1, 0));
+        let subtree = |key: u64| key / width;
+        if updates
+            .first()
+            .zip(updates.last())
+            .is_none_or(|(first, last)| subtree(first.0) == subtree(last.1 - 1))
+        {
+            return false;
+        }
+
+        // Split ranges spanning several subtrees at the subtree boundaries. Replacement roots
+        // never span a boundary either, so each piece gets the roots it would get as a whole.
+        let mut pieces = Vec::with_capacity(updates.len());
+        for (start, end, root) in updates.drain(..) {
+            let mut piece = start;
+            while subtree(piece) != subtree(end - 1) {
+                let boundary = (subtree(piece) + 1) * width;
+                pieces.push((piece, boundary, root.clone()));
+                piece = boundary;
+            }
+            pieces.push((piece, end, root));
+        }
+        *updates = pieces;
+
+        if self.is_consolidated() {
+            self.roots = Arc::new(self.roots[0].coverage(
+                &self.topology,
+                self.root_level,
+                0,
+                self.in_flight_keys,
+            ));
+        }
+
+        // Shard the root list and the updates by top-level subtree. Fragmented roots are never
+        // higher than level 1, so each falls under a single subtree.
+        type Shard<H, const N: usize> = (Vec<Node<H, N>>, Vec<(u64, u64, Node<H, N>)>);
+        let mut shards: Vec<Shard<H, N>> = Vec::new();
+        let mut current = None;
+        for root in Arc::unwrap_or_clone(mem::take(&mut self.roots)) {
+            let index = subtree(self.topology.start(root.pos));
+            if current != Some(index) {
+                shards.push((Vec::new(), Vec::new()));
+                current = Some(index);
+            }
+            shards.last_mut().unwrap().0.push(root);
+        }
+        let mut shard = shards.iter_mut().peekable();
+        for update in updates.drain(..) {
+            while shard
+                .peek()
+                .is_some_and(|(roots, _)| self.topology.end(roots[roots.len() - 1].pos) <= update.0)
+            {
+                shard.next();
+            }
+            shard.peek_mut().unwrap().1.push(update);
+        }
+
+        // The shards are spread over the rayon thread pool, which bounds the threads however wide
+        // the top level is.
+        let (topology, level) = (&self.topology, self.root_level);
+        shards
+            .par_iter_mut()
+            .filter(|(_, updates)| !updates.is_empty())
+            .for_each(|(roots, updates)| {
+                for (start, end, root) in updates.drain(..) {
+                    Self::replace_roots(topology, roots, level, start, end, root);
+                }
+            });
+
+        self.roots = Arc::new(shards.into_iter().flat_map(|(roots, _)| roots).collect());
+        true
+    }
+}
+
+impl<H, const N: usize> KeyManagementScheme for Khf<H, N>
+where
+    H: Hasher<N>,
+{
+    /// Keys have the same size as the hash digest size.
+    type Key = Key<N>;
+    /// Keys are uniquely identified with `u64`s.
+    type KeyId = u64;
+    /// Bespoke error type.
+    type Error = Error;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.check_derivable(key)?;
+        self.check_quota(key)?;
+
+        let pos = self.topology.leaf_position(key);
+
+        let value = if let Some(k) = self.cache.get(&pos) {
+            k
+        } else {
+            self.derive_key(key)?
+        };
+        self.check_cross(key, &value)?;
+
+        // Only derivations that succeed count against the quota.
+        self.charge_quota(key);
+        Ok(value)
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_derivable(key)?;
+
+        self.updated_keys.insert(key);
+        self.updated_keys_dirty = true;
+        self.derive(key)
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Self::Key)>, Self::Error> {
+        let mut res = Vec::new();
+        self.commit_with(rng, |key, value| res.push((key, value)))?;
+        Ok(res)
+    }
+}
+
+impl<H, const N: usize> Khf<H, N> {
+    /// Locks the memory holding the root keys, the root lists of retained epochs, and the cache so
+    /// that it isn't swapped to disk. This happens automatically whenever the roots change, but
+    /// must be done manually after deserializing a `Khf`. Returns `true` if the memory is locked,
+    /// which requires the `mlock` feature.
+    pub fn lock_keys(&mut self) -> bool {
+        #[cfg(feature = "mlock")]
+        {
+            let regions = self.key_regions();
+            self.locked.relock(regions) && self.cache.lock()
+        }
+
+        #[cfg(not(feature = "mlock"))]
+        false
+    }
+
+    // Returns the `Khf` after locking its root keys.
+    fn locked(mut self) -> Self {
+        self.lock_keys();
+        self
+    }
+
+    // Returns the memory regions holding the appending root and the root lists of the current and
+    // retained epochs.
+    #[cfg(feature = "mlock")]
+    fn key_regions(&self) -> Vec<LockedRegion> {
+        let history = self.history.iter().map(|(_, _, roots)| roots);
+        [LockedRegion::of_box(&*self.appending_root)]
+            .into_iter()
+            .chain(
+                [&self.roots]
+                    .into_iter()
+                    .chain(history)
+                    .map(|roots| LockedRegion::of_vec(roots)),
+            )
+            .collect()
+    }
+}
+
+// Manually implemented to avoid leaking key material.
+impl<H, const N: usize> fmt::Debug for Khf<H, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Khf")
+            .field("topology", &self.topology)
+            .field("epoch", &self.epoch)
+            .field("keys", &self.keys)
+            .field("roots", &self.roots.len())
+            .field("in_flight_keys", &self.in_flight_keys)
+            .field("updated_keys", &self.updated_keys.len())
+            .field("cached_keys", &self.cache.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Summarizes the roots, drawing each one with the range of keys it covers instead of its tree.
+/// Use `display()` to draw the trees, and `dump_unredacted()` to include keys.
+impl<H, const N: usize> fmt::Display for Khf<H, N>
+where
+    H: Hasher<N>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display().max_depth(0).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    // use rand::rngs::ThreadRng;
+
+    // #[test]
+    // fn it_works() -> Result<()> {
+    //     let mut rng = ThreadRng::default();
+    //     let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2], &mut rng);
+
+    //     // We'll check that we can re-derive this after commit.
+    //     let key4 = khf.derive(4)?;
+
+    //     // Keys updated/derived during the same epoch should be the same.
+    //     let key5 = khf.derive(5)?;
+    //     let key5_updated = khf.update(5)?;
+    //     assert_eq!(key5, key5_updated);
+    //     assert_eq!(khf.commit(&mut rng)?, vec![(5, key5)]);
+
+    //     // Should still be able to derive old keys, but not updated keys.
+    //     let key4_rederived = khf.derive(4)?;
+    //     let key5_rederived = khf.derive(5)?;
+    //     assert_eq!(key4, key4_rederived);
+    //     assert_ne!(key5, key5_rederived);
+
+    //     // Truncating down to 2 keys won't change the value of keys derived before the next commit.
+    //     // It will also get rid of any prior updates to larger keys.
+    //     khf.update(5)?;
+    //     khf.truncate(2);
+    //     let key0 = khf.derive(0)?;
+    //     let key1 = khf.derive(1)?;
+    //     let key4_rederived_again = khf.derive(4)?;
+    //     let key5_rederived_again = khf.derive(5)?;
+    //     assert_eq!(key4_rederived_again, key4_rederived);
+    //     assert_eq!(key5_rederived_again, key5_rederived);
+
+    //     assert_eq!(khf.commit(&mut rng), vec![]);
+    //     let key0_rederived = khf.derive(0)?;
+    //     let key1_rederived = khf.derive(1)?;
+    //     let key4_rederived_yet_again = khf.derive(4)?;
+    //     let key5_rederived_yet_again = khf.derive(5)?;
+    //     assert_eq!(key0, key0_rederived);
+    //     assert_eq!(key1, key1_rederived);
+    //     assert_ne!(key4_rederived_yet_again, key4_rederived_again);
+    //     assert_ne!(key5_rederived_yet_again, key5_rederived_again);
+
+    //     Ok(())
+    // }
+
+    use rand::prelude::*;
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn random_commit() -> Result<()> {
+        fn all_keys(khf: &mut Khf<Sha3_256, SHA3_256_MD_SIZE>) -> Vec<[u8; SHA3_256_MD_SIZE]> {
+            (0..100).map(|i| khf.derive(i).unwrap()).collect()
+        }
+
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+
+        for _ in 0..10000 {
+            let old = all_keys(&mut khf);
+
+            let ks: HashSet<u64> = HashSet::from_iter((0..10).map(|_| {
+                let k = rng.gen_range(0..100);
+                khf.update(k).unwrap();
+                khf.commit(&mut rng).unwrap();
+                k
+            }));
+
+            let new = all_keys(&mut khf);
+
+            for (i, (o, n)) in old.iter().zip(&new).enumerate() {
+                if !ks.contains(&(i as u64)) {
+                    assert_eq!(o, n);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn caching() -> Result<()> {
+        let mut keys = HashMap::new();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], thread_rng());
+
+        for i in 0..10000 {
+            let key = khf.derive(i).unwrap();
+            keys.insert(i, key);
+        }
+
+        for i in 0..10000 {
+            let cached_key = khf.derive(i).unwrap();
+            let saved_key = keys.get(&i).unwrap();
+            assert_eq!(&cached_key, saved_key);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_display() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4; 8], thread_rng());
+        khf.append(1 << 16);
+        khf.commit(thread_rng())?;
+
+        // The summary only draws the roots, rather than every one of their leaves.
+        assert_eq!(khf.to_string(), "> (1, 0) 0..65536");
+        assert_eq!(
+            khf.display().max_depth(2).range(4..6).to_string(),
+            "> (1, 0)\n└─── (2, 0)\n     └─── (3, 0) 0..4096"
+        );
+        assert_eq!(khf.display().range(4..6).to_string().lines().count(), 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dot() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        khf.append(16);
+        khf.update(5)?;
+        khf.commit(thread_rng())?;
+
+        let dot = khf.to_dot();
+        assert!(dot.starts_with("digraph khf {") && dot.ends_with("}\n"));
+        assert_eq!(dot.matches(" -> ").count(), khf.roots.len());
+        assert!(dot.contains("(3, 5)\\n5..6\\n"));
+        let key = crate::encoding::encode_hex(&khf.roots[0].key);
+        assert!(!dot.contains(&key[..8]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_is_redacted() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], thread_rng());
+        khf.derive(10)?;
+        khf.commit(thread_rng())?;
+
+        let debug = format!("{khf:?}");
+        assert!(debug.contains("epoch: 1"));
+        let key = crate::encoding::encode_hex(&khf.roots[0].key);
+        assert!(!debug.contains(&*key));
+        assert!(!format!("{:?}", khf.roots[0]).contains(&*key));
+        assert!(format!("{:?}", khf.roots[0].dump_unredacted()).contains(&*key));
+        assert!(!khf.to_string().contains(&*key));
+        assert!(khf.dump_unredacted().to_string().contains(&*key));
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_full() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(63)?;
+        khf.commit(&mut rng)?;
+
+        let old = khf.update(5)?;
+        let res = khf.commit_full(&mut rng)?;
+        assert_eq!(res, vec![(5, old, khf.derive(5)?)]);
+        assert_ne!(old, khf.derive(5)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fragment() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(255)?;
+        khf.commit(&mut rng)?;
+
+        let old: Vec<_> = (0..256).map(|key| khf.derive(key).unwrap()).collect();
+
+        khf.fragment(17..30, 3);
+        khf.cache.clear();
+        assert!(khf.fragmentation() > 1);
+
+        let new: Vec<_> = (0..256).map(|key| khf.derive(key).unwrap()).collect();
+        assert_eq!(old, new);
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "fragment level 0 is out of range")]
+    fn fragment_invalid_level() {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.fragment(0..16, 0);
+    }
+
+    #[test]
+    fn append() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+
+        assert_eq!(khf.append(10), 0..10);
+        assert_eq!(khf.append(5), 10..15);
+
+        let key = khf.derive(14)?;
+        khf.commit(&mut rng)?;
+        assert_eq!(key, khf.derive(14)?);
+        assert_eq!(khf.append(1), 15..16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_persisted() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(100)?;
+        khf.commit(&mut rng)?;
+        khf.update(7)?;
+        khf.commit(&mut rng)?;
+
+        let digest = khf.digest();
+        let persisted = bincode::serialize(&khf)?;
+        assert!(Khf::<Sha3_256, SHA3_256_MD_SIZE>::verify_persisted(
+            &persisted[..],
+            &digest
+        )?);
+
+        khf.update(8)?;
+        khf.commit(&mut rng)?;
+        assert!(!Khf::<Sha3_256, SHA3_256_MD_SIZE>::verify_persisted(
+            &persisted[..],
+            &khf.digest()
+        )?);
+
+        // Tombstones can't pass for other state, so they can't be stripped from a persisted `Khf`.
+        let mut tombstoned = khf.clone();
+        tombstoned.deleted = BTreeSet::from([10, 20, 30]);
+        let mut leased = khf.clone();
+        leased.leases = vec![(10..20, 30)];
+        assert_ne!(tombstoned.digest(), leased.digest());
+        assert!(!Khf::<Sha3_256, SHA3_256_MD_SIZE>::verify_persisted(
+            &bincode::serialize(&leased)?[..],
+            &tombstoned.digest()
+        )?);
+
+        // Nor can leases pass for history.
+        let mut historical = khf.clone();
+        historical.history.push_back((3, 4, Arc::new(Vec::new())));
+        let mut leased = khf.clone();
+        leased.leases = vec![(3..4, 0)];
+        assert_ne!(historical.digest(), leased.digest());
+
+        Ok(())
+    }
+
+    #[test]
+    fn split() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(99)?;
+        khf.commit(&mut rng)?;
+        khf.update(20)?;
+        khf.update(50)?;
+        khf.append(10);
+
+        let keys = (0..110)
+            .map(|key| khf.derive(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (mut left, mut right) = khf.split(37, &mut rng)?;
+        assert_eq!(left.len(), 37);
+        assert_eq!(right.len(), 110);
+
+        for key in 0..37 {
+            assert_eq!(left.derive(key)?, keys[key as usize]);
+            assert_ne!(right.derive(key)?, keys[key as usize]);
+        }
+        for key in 37..110 {
+            assert_eq!(right.derive(key)?, keys[key as usize]);
+        }
+        assert!(left.is_updated(20) && !left.is_updated(50));
+        assert!(right.is_updated(50) && !right.is_updated(20));
+
+        // Keys appended after the split are independent.
+        assert_ne!(left.derive(37)?, keys[37]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        let mut other = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(29)?;
+        khf.commit(&mut rng)?;
+        other.derive(19)?;
+        other.commit(&mut rng)?;
+        other.update(5)?;
+
+        let keys = (0..30)
+            .map(|key| khf.derive(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let other_keys = (0..20)
+            .map(|key| other.derive(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(khf.merge(other)?, 30..50);
+        assert!(khf.is_updated(35));
+
+        for key in 0..30 {
+            assert_eq!(khf.derive(key)?, keys[key as usize]);
+        }
+        for key in 30..50 {
+            assert_eq!(khf.derive(key)?, other_keys[key as usize - 30]);
+        }
+
+        // Only the updated key changes after a commit.
+        khf.commit(&mut rng)?;
+        assert_ne!(khf.derive(35)?, other_keys[5]);
+        assert_eq!(khf.derive(36)?, other_keys[6]);
+
+        let other = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2], &mut rng);
+        assert!(matches!(khf.merge(other), Err(Error::IncompatibleTopology)));
+
+        // Merging doesn't undo a pending truncation.
+        khf.truncate_keys(40);
+        let mut other = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        other.append(5);
+        assert!(matches!(
+            khf.merge(other.clone()),
+            Err(Error::Truncated(40))
+        ));
+        assert_eq!(khf.in_flight_len(), 40);
+        khf.commit(&mut rng)?;
+        assert_eq!(khf.merge(other)?, 40..45);
+
+        Ok(())
+    }
+
+    #[test]
+    fn extract() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(99)?;
+        khf.commit(&mut rng)?;
+        khf.delete(45)?;
+
+        let mut extracted = khf.extract(40..60, &mut rng)?;
+        assert_eq!(extracted.len(), 20);
+        for key in 0..20 {
+            if key == 5 {
+                assert!(matches!(extracted.derive(key), Err(Error::Deleted(5))));
+            } else {
+                assert_eq!(extracted.derive(key)?, khf.derive(40 + key)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lease() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(99)?;
+        khf.commit(&mut rng)?;
+        khf.update(12)?;
+        khf.commit(&mut rng)?;
+
+        assert!(matches!(
+            khf.lease(90..110, 2),
+            Err(Error::KeyOutOfRange { key: 110, max: 100 })
+        ));
+        assert_eq!(khf.lease(0..1, u64::MAX)?.expires(), Epoch(u64::MAX));
+
+        let lease = khf.lease(10..20, 2)?;
+        assert_eq!(lease.expires(), khf.epoch() + 2);
+        for key in 10..20 {
+            assert_eq!(lease.derive(key), Some(khf.derive(key)?));
+        }
+        assert_eq!(lease.derive(9), None);
+        assert_eq!(lease.derive(20), None);
+
+        // The lease is still valid after one commit, but revoked by the next.
+        khf.commit(&mut rng)?;
+        assert_eq!(lease.derive(15), Some(khf.derive(15)?));
+        let rotated = khf.commit(&mut rng)?;
+        assert_eq!(rotated.len(), 10);
+        for key in 10..20 {
+            assert_ne!(lease.derive(key), Some(khf.derive(key)?));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn builder() -> Result<()> {
+        let mut rng = thread_rng();
+        let policy = CachePolicy::Adaptive { min: 4, max: 64 };
+        let mut khf: Khf<Sha3_256, SHA3_256_MD_SIZE> = KhfBuilder::new()
+            .fanouts(&[2, 2, 2])
+            .root_level(3)
+            .cache_policy(policy)
+            .strict_appends(true)
+            .root_lookup(RootLookup::Interpolation)
+            .build(&mut rng);
+
+        assert_eq!(khf.cache_policy(), policy);
+        assert_eq!(khf.root_lookup(), RootLookup::Interpolation);
+        assert!(matches!(
+            khf.derive(0),
+            Err(Error::KeyOutOfRange { key: 0, max: 0 })
+        ));
+
+        // Roots are fragmented to the configured level.
+        khf.append(8);
+        khf.commit(&mut rng)?;
+        khf.update(0)?;
+        khf.commit(&mut rng)?;
+        assert!(khf.roots().all(|(level, ..)| level >= 3));
+        assert_eq!(khf.roots().count(), 5);
+
+        // Changing the root level only affects later fragmentation.
+        khf.set_root_level(4);
+        assert_eq!(khf.root_level(), 4);
+        khf.update(7)?;
+        khf.commit(&mut rng)?;
+        assert_eq!(khf.roots().count(), 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn residency() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(99)?;
+        khf.commit(&mut rng)?;
+
+        assert!(!khf.is_resident(10));
+        assert!(khf.ensure_resident(10..20));
+        assert!((10..20).all(|key| khf.is_resident(key)));
+        assert!(!khf.is_resident(20));
+
+        // Keys out of range aren't made resident or appended.
+        assert!(khf.ensure_resident(95..200));
+        assert!(!khf.is_resident(100));
+        assert_eq!(khf.in_flight_len(), 100);
+
+        // A bounded cache can't hold every key.
+        khf.set_cache_policy(CachePolicy::Adaptive { min: 4, max: 4 });
+        assert!(!khf.ensure_resident(0..100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rekey_all() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.derive(99)?;
+        khf.commit(&mut rng)?;
+        khf.update(5)?;
+        khf.delete(7)?;
+        khf.commit(&mut rng)?;
+
+        let keys = (0..100)
+            .filter(|key| *key != 7)
+            .map(|key| Ok((key, khf.derive(key)?)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(khf.rekey_all(&mut rng)?, keys);
+        assert!(khf.is_consolidated());
+        for (key, value) in keys {
+            assert_ne!(khf.derive(key)?, value);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn pin() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2, 2], ThreadRng::default());
+        khf.append(32);
+        khf.commit(ThreadRng::default())?;
+
+        khf.pin(3..5);
+        khf.pin(5..6);
+        khf.pin(20..21);
+        khf.unpin(4..5);
+        assert!(khf.is_pinned(3) && khf.is_pinned(5) && khf.is_pinned(20));
+        assert!(!khf.is_pinned(4) && !khf.is_pinned(6));
+
+        let pinned = [3, 5, 20].map(|key| khf.derive(key).unwrap());
+        let affected = khf.consolidate_keys(Consolidation::Full, ThreadRng::default());
+        assert_eq!(affected.len(), 29);
+        assert!(!affected.contains(&3) && !affected.contains(&5) && !affected.contains(&20));
+        assert_eq!(pinned, [3, 5, 20].map(|key| khf.derive(key).unwrap()));
+
+        let affected = khf.consolidate_keys(
+            Consolidation::Ranged { start: 0, end: 8 },
+            ThreadRng::default(),
+        );
+        assert_eq!(affected, vec![0, 1, 2, 4, 6, 7]);
+        assert_eq!(pinned, [3, 5, 20].map(|key| khf.derive(key).unwrap()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn plan_coverage() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2, 2], ThreadRng::default());
+        khf.append(8);
+        khf.commit(ThreadRng::default())?;
+
+        let plan = khf.plan_coverage(2, 1, 7);
+        assert_eq!(plan, vec![(4, 1), (3, 1), (3, 2), (4, 6)]);
+
+        khf.consolidate_keys(
+            Consolidation::RangedLeveled {
+                level: 2,
+                start: 1,
+                end: 7,
+            },
+            ThreadRng::default(),
+        );
+        let roots: Vec<_> = khf
+            .roots()
+            .map(|(level, offset, _, _)| (level, offset))
+            .filter(|pos| plan.contains(pos))
+            .collect();
+        assert_eq!(roots, plan);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2], ThreadRng::default());
+        let key = khf.derive(3)?;
+        assert!(khf.verify(3, &key));
+        assert!(!khf.verify(2, &key));
+        assert!(!khf.verify(4, &key));
+
+        khf.commit(ThreadRng::default())?;
+        assert!(khf.verify(3, &key));
+
+        khf.update(3)?;
+        khf.commit(ThreadRng::default())?;
+        assert!(!khf.verify(3, &key));
+
+        let key = khf.derive(1)?;
+        khf.delete(1)?;
+        assert!(!khf.verify(1, &key));
+
+        Ok(())
+    }
+
+    #[test]
+    fn diff() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], ThreadRng::default());
+        khf.append(40);
+        khf.commit(ThreadRng::default())?;
+
+        let mut other = khf.clone();
+        assert!(khf.diff(&other)?.is_empty());
+
+        other.update(5)?;
+        other.update(6)?;
+        other.update(33)?;
+        other.delete(20)?;
+        other.append(2);
+        other.commit(ThreadRng::default())?;
+        assert_eq!(khf.diff(&other)?, vec![5, 6, 20, 33, 40, 41]);
+        assert_eq!(other.diff(&khf)?, khf.diff(&other)?);
+
+        let other = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2], ThreadRng::default());
+        assert!(khf.diff(&other).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn report() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], ThreadRng::default());
+        khf.append(64);
+        khf.commit(ThreadRng::default())?;
+
+        for key in (0..64).filter(|key| *key != 10) {
+            khf.update(key)?;
+        }
+        let report = khf.commit_report(ThreadRng::default())?;
+        assert!(report.is_negative());
+        assert_eq!(report.unaffected_ranges(), vec![10..11]);
+
+        khf.pin(20..30);
+        let report = khf.consolidate_report(Consolidation::Full, ThreadRng::default());
+        assert_eq!(report.affected_ranges(), vec![0..20, 30..64]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn consolidated_table() -> Result<()> {
+        let khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], ThreadRng::default());
+        let mut table = Vec::new();
+        khf.persist_table(&mut table)?;
+        let loaded =
+            Khf::<Sha3_256, SHA3_256_MD_SIZE>::load_table(&table[..], ThreadRng::default())?;
+        assert_eq!(loaded.digest(), khf.digest());
+
+        let mut khf = khf;
+        khf.append(64);
+        khf.commit(ThreadRng::default())?;
+        khf.consolidate_keys(Consolidation::Full, ThreadRng::default());
+        assert!(khf.is_consolidated());
+        let mut table = Vec::new();
+        khf.persist_table(&mut table)?;
+        let (mut loaded, recovery) =
+            Khf::<Sha3_256, SHA3_256_MD_SIZE>::recover_table(&table[..], ThreadRng::default())?;
+        assert_eq!(recovery.corrupt_roots, 0);
+        assert!(recovery.lost.is_empty());
+        assert_eq!(loaded.digest(), khf.digest());
+        assert_eq!(loaded.derive(63)?, khf.derive(63)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_table() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], ThreadRng::default());
+        khf.append(64);
+        khf.commit(ThreadRng::default())?;
+        for key in [5, 21, 40] {
+            khf.update(key)?;
+        }
+        khf.commit(ThreadRng::default())?;
+        let keys: Vec<_> = (0..64)
+            .map(|key| khf.derive(key))
+            .collect::<Result<_, _>>()?;
+
+        let mut table = Vec::new();
+        khf.persist_table(&mut table)?;
+        let loaded =
+            Khf::<Sha3_256, SHA3_256_MD_SIZE>::load_table(&table[..], ThreadRng::default())?;
+        assert_eq!(loaded.digest(), khf.digest());
+
+        // Corrupt the root covering key 21.
+        let (index, (_, _, start, end)) = khf
+            .roots()
+            .enumerate()
+            .find(|(_, (_, _, start, end))| (*start..*end).contains(&21))
+            .unwrap();
+        let record = 16 + 2 * SHA3_256_MD_SIZE;
+        let last = table.len() - (khf.roots().count() - index) * record;
+        table[last + 20] ^= 1;
+        assert!(matches!(
+            Khf::<Sha3_256, SHA3_256_MD_SIZE>::load_table(&table[..], ThreadRng::default()),
+            Err(Error::Corrupt)
+        ));
+
+        let (mut recovered, recovery) =
+            Khf::<Sha3_256, SHA3_256_MD_SIZE>::recover_table(&table[..], ThreadRng::default())?;
+        assert_eq!(recovery.corrupt_roots, 1);
+        assert_eq!(recovery.lost, vec![start..end]);
+        for key in 0..64 {
+            assert_eq!(recovered.is_updated(key), (start..end).contains(&key));
+            let lost = (start..end).contains(&key);
+            assert_eq!(recovered.derive(key)? == keys[key as usize], !lost);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn derive_at_epoch() -> Result<()> {
+        let mut khf = KhfBuilder::new()
+            .fanouts(&[4, 4, 4])
+            .retained_epochs(2)
+            .build::<Sha3_256, SHA3_256_MD_SIZE>(ThreadRng::default());
+        khf.append(16);
+        khf.commit(ThreadRng::default())?;
+
+        let mut epochs = Vec::new();
+        for key in [3, 7, 11] {
+            epochs.push((khf.epoch(), khf.derive(3)?, khf.derive(7)?));
+            khf.update(key)?;
+            khf.commit(ThreadRng::default())?;
+        }
+
+        // Only the current epoch and the last two are retained.
+        assert!(matches!(
+            khf.derive_at_epoch(KeyId(3), epochs[0].0),
+            Err(Error::UnavailableEpoch(_))
+        ));
+        for (epoch, three, seven) in &epochs[1..] {
+            assert_eq!(khf.derive_at_epoch(KeyId(3), *epoch)?, *three);
+            assert_eq!(khf.derive_at_epoch(KeyId(7), *epoch)?, *seven);
+        }
+        assert_eq!(khf.derive_at_epoch(KeyId(7), khf.epoch())?, khf.derive(7)?);
+        assert_ne!(khf.derive_at_epoch(KeyId(7), khf.epoch())?, epochs[1].2);
+        assert!(khf.derive_at_epoch(KeyId(16), khf.epoch()).is_err());
+
+        let persisted = bincode::serialize(&khf)?;
+        assert!(Khf::<Sha3_256, SHA3_256_MD_SIZE>::verify_persisted(
+            &persisted[..],
+            &khf.digest()
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild() -> Result<()> {
+        let master = [7; SHA3_256_MD_SIZE];
+        let epochs = vec![
+            vec![EpochOp::Append(20)],
+            vec![EpochOp::Update(3), EpochOp::Update(12)],
+            vec![EpochOp::Truncate(15), EpochOp::Append(5)],
+        ];
+
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::from_master(&[4, 4], &master);
+        for epoch in &epochs {
+            for op in epoch {
+                match op {
+                    EpochOp::Update(key) => {
+                        khf.update(*key)?;
+                    }
+                    EpochOp::Append(n) => {
+                        khf.append(*n);
+                    }
+                    EpochOp::Truncate(n) => khf.truncate_keys(*n),
+                }
+            }
+            khf.commit_with_master(&master)?;
+        }
+
+        let rebuilt = Khf::<Sha3_256, SHA3_256_MD_SIZE>::rebuild(&[4, 4], &master, epochs.clone())?;
+        assert_eq!(rebuilt.digest(), khf.digest());
+
+        let other = Khf::<Sha3_256, SHA3_256_MD_SIZE>::rebuild(&[4, 4], &[8; 32], epochs)?;
+        assert_ne!(other.digest(), khf.digest());
+
+        Ok(())
+    }
+
+    #[test]
+    fn transfer() -> Result<()> {
+        let mut rng = ThreadRng::default();
+        let mut src = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], &mut rng);
+        src.append(40);
+        src.commit(&mut rng)?;
+        src.update(13)?;
+        src.commit(&mut rng)?;
+        let mut dst = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], &mut rng);
+        dst.append(8);
+        dst.commit(&mut rng)?;
+
+        let keys: Vec<_> = (0..40)
+            .map(|key| src.derive(key))
+            .collect::<Result<_, _>>()?;
+        let before: Vec<_> = (0..8)
+            .map(|key| dst.derive(key))
+            .collect::<Result<_, _>>()?;
+        let receipt = src.transfer(10..30, &mut dst, &mut rng)?;
+
+        assert_eq!(dst.len(), 30);
+        for key in 0..8 {
+            assert_eq!(dst.derive(key)?, before[key as usize]);
+        }
+        for key in 10..30 {
+            assert_eq!(dst.derive(key)?, keys[key as usize]);
+            assert!(src.is_updated(key));
+        }
+        assert!(dst.verify_receipt(&receipt) && src.verify_receipt(&receipt));
+
+        // The source rotates the transferred keys, while the destination keeps them.
+        src.commit(&mut rng)?;
+        dst.commit(&mut rng)?;
+        assert!(!src.verify_receipt(&receipt));
+        assert!(dst.verify_receipt(&receipt));
+        assert_ne!(src.derive(20)?, dst.derive(20)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cross_check() -> Result<()> {
+        // Computes SHA3-256, except for a flipped bit.
+        struct Faulty(Sha3_256);
+
+        impl Hasher<SHA3_256_MD_SIZE> for Faulty {
+            fn new() -> Self {
+                Self(Sha3_256::new())
+            }
+
+            fn update(&mut self, data: &[u8]) {
+                self.0.update(data);
+            }
+
+            fn finish(self) -> [u8; SHA3_256_MD_SIZE] {
+                let mut hash = self.0.finish();
+                hash[0] ^= 1;
+                hash
+            }
+        }
+
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        khf.enable_cross_check::<Sha3_256>();
+        let key = khf.derive(3)?;
+        khf.commit(thread_rng())?;
+        assert_eq!(khf.derive(3)?, key);
+        khf.update(9)?;
+
+        khf.enable_cross_check::<Faulty>();
+        assert!(matches!(khf.derive(3), Err(Error::CrossCheckMismatch(3))));
+        khf.disable_cross_check();
+        assert_eq!(khf.derive(3)?, key);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delegate() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], thread_rng());
+        khf.append(64);
+        khf.update(40)?;
+        khf.commit(thread_rng())?;
+
+        let nodes = khf.delegate(3..36, 2)?;
+        assert_eq!(
+            nodes.iter().map(|node| node.pos).collect::<Vec<_>>(),
+            khf.plan_coverage(2, 3, 36)
+        );
+        for key in 3..36 {
+            let pos = khf.topology.leaf_position(key);
+            let node = nodes
+                .iter()
+                .find(|node| khf.topology.is_ancestor(node.pos, pos))
+                .unwrap();
+            assert_eq!(node.derive(&khf.topology, pos), khf.derive(key)?);
+        }
+        assert!(khf.delegate(60..65, 2).is_err());
+
+        let mut delegated = Khf::from_delegation(khf.topology.clone(), nodes)?;
+        assert!(delegated.is_read_only());
+        delegated.check_topology(khf.topology())?;
+        assert!(matches!(
+            delegated.check_topology(&Topology::new(&[4, 16])),
+            Err(Error::IncompatibleTopology)
+        ));
+        assert_eq!(delegated.derive(35)?, khf.derive(35)?);
+        assert!(matches!(delegated.derive(2), Err(Error::OutOfRange(2))));
+        assert!(matches!(delegated.derive(36), Err(Error::OutOfRange(36))));
+        assert!(matches!(delegated.update(3), Err(Error::ReadOnly)));
+        assert!(matches!(
+            delegated.commit(thread_rng()),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(
+            delegated.replay_epochs([[EpochOp::Append(4)]], thread_rng()),
+            Err(Error::ReadOnly)
+        ));
+        assert_eq!(delegated.in_flight_len(), 36);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_commit() -> Result<()> {
+        let master = [7; SHA3_256_MD_SIZE];
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::from_master(&[4, 4], &master);
+        khf.append(64);
+        khf.commit_with_master(&master)?;
+        let mut sequential = khf.clone();
+
+        let updates = [(3, 5), (17, 18), (30, 48), (49, 50), (63, 64)];
+        let nodes = |khf: &Khf<Sha3_256, SHA3_256_MD_SIZE>| {
+            updates
+                .iter()
+                .map(|&(start, end)| {
+                    (
+                        start,
+                        end,
+                        Node::with_pos((0, 0), khf.derive_key_immutable(start).unwrap()),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut parallel = nodes(&khf);
+        assert!(khf.replace_keys_parallel(&mut parallel));
+        for (start, end, root) in nodes(&sequential) {
+            sequential.replace_keys(1, start, end, root);
+        }
+        assert_eq!(
+            khf.roots_unredacted().collect::<Vec<_>>(),
+            sequential.roots_unredacted().collect::<Vec<_>>()
+        );
+
+        let mut unsharded = vec![(5, 9, Node::with_pos((0, 0), [0; SHA3_256_MD_SIZE]))];
+        assert!(!khf.replace_keys_parallel(&mut unsharded));
+        assert_eq!(unsharded.len(), 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn derive_range_par() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], &mut rng);
+        khf.append(64);
+        khf.commit(&mut rng)?;
+        khf.update(10)?;
+        khf.commit(&mut rng)?;
+        khf.append(8);
+
+        let keys = khf.derive_range_par(5..72)?;
+        for (key, value) in (5..72).zip(keys) {
+            assert_eq!(khf.derive(key)?, value);
+        }
+        assert!(matches!(
+            khf.derive_range_par(0..73),
+            Err(Error::KeyOutOfRange { key: 72, max: 72 })
+        ));
+
+        // A consolidated root is split into subtrees.
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(1000);
+        khf.commit(&mut rng)?;
+        let keys = khf.derive_range_par(0..1000)?;
+        for (key, value) in (0..1000).zip(keys) {
+            assert_eq!(khf.derive(key)?, value);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn derive_with_context() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        let data = khf.derive_with_context(3, b"data")?;
+        let mac = khf.derive_with_context(3, b"mac")?;
+        assert_ne!(data, mac);
+        assert_ne!(data, khf.derive(3)?);
+        assert_ne!(data, khf.derive_with_context(4, b"data")?);
+        assert_eq!(khf.derive_with_context(3, b"data")?, data);
+
+        khf.update(3)?;
+        khf.commit(thread_rng())?;
+        assert_ne!(khf.derive_with_context(3, b"data")?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_commit() -> Result<()> {
+        // Reports insufficient entropy until it's told otherwise.
+        struct Entropy(bool);
+
+        impl RngCore for Entropy {
+            fn next_u32(&mut self) -> u32 {
+                thread_rng().next_u32()
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                thread_rng().next_u64()
+            }
+
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                thread_rng().fill_bytes(dest)
+            }
+
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+                if self.0 {
+                    self.fill_bytes(dest);
+                    Ok(())
+                } else {
+                    Err(std::num::NonZeroU32::new(rand::Error::CUSTOM_START)
+                        .unwrap()
+                        .into())
+                }
+            }
+        }
+
+        impl CryptoRng for Entropy {}
+
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        let key = khf.derive(3)?;
+        khf.update(3)?;
+        assert!(khf.try_commit(Entropy(false))?.is_none());
+        assert!(khf.is_degraded());
+        assert_eq!(khf.epoch(), Epoch(0));
+        assert!(khf.is_updated(3));
+
+        let updated = khf.try_commit(Entropy(true))?.unwrap();
+        assert_eq!(updated, vec![(3, key)]);
+        assert!(!khf.is_degraded());
+        assert_ne!(khf.derive(3)?, key);
+
+        Ok(())
+    }
+
+    #[test]
+    fn derive_sized() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        let xts: [u8; 64] = khf.derive_sized(3)?;
+        let short: [u8; 16] = khf.derive_sized(3)?;
+        assert_eq!(khf.derive_sized::<64>(3)?, xts);
+        assert_ne!(xts[..32], xts[32..]);
+        assert_ne!(xts[..16], short);
+        assert_ne!(khf.derive_sized::<64>(4)?, xts);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_clone() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], &mut rng);
+        khf.append(64);
+        khf.commit(&mut rng)?;
+        khf.fragment(0..64, 3);
+
+        // Clones share the root list until one of them modifies it.
+        let mut clone = khf.clone();
+        assert!(Arc::ptr_eq(&khf.roots, &clone.roots));
+        let key = khf.derive(5)?;
+        clone.update(5)?;
+        clone.commit(&mut rng)?;
+        assert!(!Arc::ptr_eq(&khf.roots, &clone.roots));
+        assert_eq!(khf.derive(5)?, key);
+        assert_ne!(clone.derive(5)?, key);
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_usage() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], &mut rng);
+        khf.append(64);
+        khf.commit(&mut rng)?;
+        let committed = khf.memory_usage();
+
+        for key in 0..64 {
+            khf.derive(key)?;
         }
-        // We need to append keys.
-        else if self.in_flight_keys >= self.keys {
-            // If we've updated every single key since the last commit, we can consolidate
-            // everything to a new root.
-            if self.updated_keys.len() as u64 == self.in_flight_keys {
-                let res = self
-                    .updated_keys
-                    .iter()
-                    .map(|block| (*block, self.derive_key_immutable(*block)))
-                    .collect();
+        assert!(khf.memory_usage() > committed);
 
-                let node = Node::with_rng(&mut rng);
-                self.replace_keys(0, 0, 0, node);
+        khf.fragment(0..64, 3);
+        khf.consolidate_keys(Consolidation::Full, &mut rng);
+        let fragmented = khf.memory_usage();
+        khf.compact_memory();
+        assert!(khf.memory_usage() <= fragmented);
 
-                res
-            }
-            // Otherwise, we need to fragment in appended keys and then updated keys.
-            else {
-                let res = self
-                    .updated_keys
-                    .iter()
-                    .map(|block| (*block, self.derive_key_immutable(*block)))
-                    .collect();
+        Ok(())
+    }
 
-                // Fragment in the appended keys.
-                self.replace_keys(
-                    DEFAULT_ROOT_LEVEL,
-                    self.keys,
-                    self.in_flight_keys,
-                    self.appending_root.clone(),
-                );
+    #[test]
+    fn purge_cache() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        let key = khf.derive(3)?;
+        assert!(khf.is_resident(3));
+        khf.purge_cache();
+        assert!(!khf.is_resident(3));
+        assert_eq!(khf.derive(3)?, key);
 
-                // Fragment in updated keys.
-                for (start, end) in self.updated_key_ranges() {
-                    let node = Node::with_rng(&mut rng);
-                    self.replace_keys(DEFAULT_ROOT_LEVEL, start, end, node);
-                }
+        Ok(())
+    }
 
-                res
-            }
+    #[test]
+    fn path_caching() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], &mut rng);
+        khf.append(16);
+        khf.commit(&mut rng)?;
+
+        // Deriving a key caches every node on its path, so deriving a sibling only hashes once.
+        khf.derive(0)?;
+        assert_eq!(khf.cache_stats().len, 2);
+        khf.derive(1)?;
+        assert_eq!(khf.cache_stats().len, 3);
+        khf.derive(4)?;
+        assert_eq!(khf.cache_stats().len, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_stats() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], &mut rng);
+        khf.derive(0)?;
+        khf.derive(0)?;
+        khf.derive(1)?;
+        let stats = khf.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert!((stats.hit_rate() - 1.0 / 3.0).abs() < f64::EPSILON);
+
+        // The counters are per epoch.
+        khf.commit(&mut rng)?;
+        assert_eq!(khf.cache_stats(), CacheStats::default());
+        khf.derive(0)?;
+        khf.reset_cache_stats();
+        assert_eq!(khf.cache_stats().misses, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn quota() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], ThreadRng::default());
+        khf.set_quota(Some(Quota {
+            range: 4,
+            derivations: 2,
+        }));
+
+        khf.derive(0)?;
+        khf.update(3)?;
+        assert!(matches!(khf.derive(1), Err(Error::QuotaExceeded(1))));
+        khf.derive(4)?;
+
+        khf.commit(ThreadRng::default())?;
+        khf.derive(1)?;
+
+        // Failed derivations don't count against the quota.
+        khf.delete(4)?;
+        for _ in 0..4 {
+            assert!(matches!(khf.derive(4), Err(Error::Deleted(4))));
         }
-        // We need to truncate keys.
-        else {
-            // We can forget about updated keys that have been truncated.
-            self.updated_keys.retain(|key| *key < self.in_flight_keys);
+        khf.derive(5)?;
+        khf.derive(6)?;
+        assert!(matches!(khf.derive(7), Err(Error::QuotaExceeded(7))));
 
-            // If we've touched every key post-truncation, we can just consolidate to a new root.
-            if self.updated_keys.len() as u64 == self.in_flight_keys {
-                let res = self
-                    .updated_keys
-                    .iter()
-                    .map(|block| (*block, self.derive_key_immutable(*block)))
-                    .collect();
+        khf.set_quota(None);
+        for _ in 0..4 {
+            khf.derive(2)?;
+        }
 
-                let node = Node::with_rng(&mut rng);
-                self.replace_keys(0, 0, 0, node);
+        Ok(())
+    }
 
-                res
-            }
-            // Otherwise, we'll need to actually truncate something.
-            else {
-                // If we're consolidated, we'll just truncate using the top level root.
-                if self.is_consolidated() {
-                    let res = self
-                        .updated_keys
-                        .iter()
-                        .map(|block| (*block, self.derive_key_immutable(*block)))
-                        .collect();
-
-                    self.roots = self.roots[0].coverage(
-                        &self.topology,
-                        DEFAULT_ROOT_LEVEL,
-                        0,
-                        self.in_flight_keys,
-                    );
+    #[test]
+    fn strict_appends() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng)
+            .with_strict_appends(true);
 
-                    res
-                }
-                // Otherwise, we need to find the root that covers the last key and truncate it.
-                else {
-                    let res = self
-                        .updated_keys
-                        .iter()
-                        .map(|block| (*block, self.derive_key_immutable(*block)))
-                        .collect();
-
-                    let index = self
-                        .roots
-                        .iter()
-                        .position(|root| self.topology.end(root.pos) > self.in_flight_keys)
-                        .unwrap();
-                    let start = self.topology.start(self.roots[index].pos);
-                    let root = self.roots.drain(index..).next().unwrap();
-
-                    self.roots.append(&mut root.coverage(
-                        &self.topology,
-                        DEFAULT_ROOT_LEVEL,
-                        start,
-                        self.in_flight_keys,
-                    ));
+        assert!(matches!(
+            khf.derive(0),
+            Err(Error::KeyOutOfRange { key: 0, max: 0 })
+        ));
+        assert!(matches!(
+            khf.update(3),
+            Err(Error::KeyOutOfRange { key: 3, max: 0 })
+        ));
 
-                    res
-                }
+        khf.append(4);
+        khf.update(3)?;
+        khf.commit(&mut rng)?;
+        khf.derive(3)?;
+        assert!(matches!(
+            khf.derive(4),
+            Err(Error::KeyOutOfRange { key: 4, max: 4 })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_cache() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], thread_rng());
+        khf.set_cache_policy(CachePolicy::Adaptive { min: 16, max: 64 });
+
+        // A cold cache should stay at its minimum capacity.
+        for key in 0..4096 {
+            khf.derive(key)?;
+        }
+        let stats = khf.cache_stats();
+        assert_eq!(stats.capacity, Some(16));
+        assert!(stats.len <= 16);
+
+        // A hot cache should grow.
+        for _ in 0..256 {
+            for key in 0..16 {
+                khf.derive(key)?;
             }
-        };
+        }
+        let stats = khf.cache_stats();
+        assert_eq!(stats.capacity, Some(32));
+        assert!(stats.hits > 0);
 
-        // Clear out our cache.
-        self.cache.clear();
+        Ok(())
+    }
 
-        // Clear out our cache.
-        self.cached_keys.clear();
+    #[test]
+    fn replay_epochs() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(64);
+        khf.commit(&mut rng)?;
 
-        // Get a new appending root, and update our known number of keys.
-        self.appending_root = Node::with_rng(&mut rng);
-        self.keys = self.in_flight_keys;
+        let old: Vec<_> = (0..64).map(|key| khf.derive(key).unwrap()).collect();
 
-        // Clear out the updated keys.
-        self.updated_keys.clear();
-        self.updated_keys_dirty = true;
+        let revoked = khf.replay_epochs(
+            vec![
+                vec![EpochOp::Update(1), EpochOp::Update(40)],
+                vec![EpochOp::Truncate(32), EpochOp::Update(2)],
+                vec![EpochOp::Append(8)],
+            ],
+            &mut rng,
+        )?;
+        assert_eq!(revoked, vec![vec![1, 40], vec![2], vec![]]);
+        assert_eq!(khf.epoch(), Epoch(4));
 
-        Ok(res)
+        let new: Vec<_> = (0..40).map(|key| khf.derive(key).unwrap()).collect();
+        for key in 0..40 {
+            let changed = [1, 2].contains(&key) || key >= 32;
+            assert_eq!(old[key] != new[key], changed);
+        }
+
+        Ok(())
     }
-}
 
-impl<H, const N: usize> fmt::Display for Khf<H, N>
-where
-    H: Hasher<N>,
-{
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for (i, root) in self.roots.iter().enumerate() {
-            root.fmt(f, &self.topology)?;
-            if i + 1 != self.roots.len() {
-                writeln!(f)?;
+    #[test]
+    fn stats() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(300);
+        khf.commit(&mut rng)?;
+        khf.update(5)?;
+
+        let stats = khf.stats();
+        assert_eq!(stats.roots, khf.fragmentation());
+        assert_eq!(stats.roots_per_level.iter().sum::<u64>(), stats.roots);
+        assert_eq!(stats.covered, vec![(0, 300)]);
+        assert_eq!(stats.updated_keys, 1);
+
+        let roots: Vec<_> = khf.roots().collect();
+        assert_eq!(roots[0], (1, 0, 0, 256));
+        assert_eq!(roots.last().unwrap().3, 300);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fixture() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(300);
+        khf.commit(&mut rng)?;
+        khf.update(5)?;
+        khf.commit(&mut rng)?;
+
+        let fixture = khf.snapshot_fixture();
+        assert!(fixture.starts_with("khf-fixture v1\nfanouts 4 4 4 4\nkeys 300\nepoch 2\n"));
+        assert!(!fixture.contains(&*crate::encoding::encode_hex(&khf.roots[0].key)));
+
+        let restored = Khf::<Sha3_256, SHA3_256_MD_SIZE>::from_fixture(&fixture, &mut rng)?;
+        assert_eq!(restored.snapshot_fixture(), fixture);
+
+        // Malformed root lists are rejected along with the offending line.
+        let header = "khf-fixture v1\nfanouts 4 4\nkeys 20\nepoch 1\n";
+        for (roots, line) in [
+            ("root 9 0 <key>\n", Some("root 9 0 <key>")),
+            (
+                "root 1 9999999999999999999 <key>\n",
+                Some("root 1 9999999999999999999 <key>"),
+            ),
+            ("root 2 0 <key>\nroot 2 2 <key>\n", Some("root 2 2 <key>")),
+            ("root 2 1 <key>\n", Some("root 2 1 <key>")),
+            ("root 0 0 <key>\nroot 1 1 <key>\n", Some("root 0 0 <key>")),
+            (
+                "root 1 0 <key>\nroot 1 1 <key>\nroot 1 2 <key>\n",
+                Some("root 1 2 <key>"),
+            ),
+            ("root 2 0 <key>\n", None),
+        ] {
+            let fixture = format!("{header}{roots}");
+            match Khf::<Sha3_256, SHA3_256_MD_SIZE>::from_fixture(&fixture, &mut rng) {
+                Err(Error::InvalidFixture(invalid)) => {
+                    if let Some(line) = line {
+                        assert_eq!(invalid, line);
+                    }
+                }
+                _ => panic!("accepted {roots:?}"),
             }
         }
+
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use anyhow::Result;
-    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
-    // use rand::rngs::ThreadRng;
+    #[test]
+    fn key_state() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(10);
+        assert_eq!(khf.key_state(3), KeyState::AppendedUncommitted);
+        assert_eq!(khf.key_state(10), KeyState::Unallocated);
 
-    // #[test]
-    // fn it_works() -> Result<()> {
-    //     let mut rng = ThreadRng::default();
-    //     let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2], &mut rng);
+        khf.commit(&mut rng)?;
+        khf.update(3)?;
+        khf.update(4)?;
+        khf.update(6)?;
+        assert_eq!(khf.updated_key_ranges(), vec![(3, 5), (6, 7)]);
 
-    //     // We'll check that we can re-derive this after commit.
-    //     let key4 = khf.derive(4)?;
+        assert!(matches!(
+            khf.try_truncate(11),
+            Err(Error::KeyOutOfRange { key: 11, max: 10 })
+        ));
+        assert_eq!(khf.try_truncate(8)?, vec![8, 9]);
+        assert_eq!(khf.key_state(2), KeyState::Committed);
+        assert_eq!(khf.key_state(3), KeyState::UpdatedUncommitted);
+        assert_eq!(khf.key_state(9), KeyState::Truncated);
+        assert!(matches!(khf.derive(9), Err(Error::Truncated(9))));
+        assert!(matches!(khf.update(8), Err(Error::Truncated(8))));
 
-    //     // Keys updated/derived during the same epoch should be the same.
-    //     let key5 = khf.derive(5)?;
-    //     let key5_updated = khf.update(5)?;
-    //     assert_eq!(key5, key5_updated);
-    //     assert_eq!(khf.commit(&mut rng)?, vec![(5, key5)]);
+        Ok(())
+    }
 
-    //     // Should still be able to derive old keys, but not updated keys.
-    //     let key4_rederived = khf.derive(4)?;
-    //     let key5_rederived = khf.derive(5)?;
-    //     assert_eq!(key4, key4_rederived);
-    //     assert_ne!(key5, key5_rederived);
+    #[test]
+    fn retopologize() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], &mut rng);
+        khf.append(40);
+        khf.commit(&mut rng)?;
+        khf.delete(7)?;
+        khf.commit(&mut rng)?;
+        khf.truncate_keys(36);
 
-    //     // Truncating down to 2 keys won't change the value of keys derived before the next commit.
-    //     // It will also get rid of any prior updates to larger keys.
-    //     khf.update(5)?;
-    //     khf.truncate(2);
-    //     let key0 = khf.derive(0)?;
-    //     let key1 = khf.derive(1)?;
-    //     let key4_rederived_again = khf.derive(4)?;
-    //     let key5_rederived_again = khf.derive(5)?;
-    //     assert_eq!(key4_rederived_again, key4_rederived);
-    //     assert_eq!(key5_rederived_again, key5_rederived);
+        let old: Vec<_> = (0..36).map(|key| khf.derive(key).ok()).collect();
+        let mut converted = khf.retopologize(&[2, 2, 2], &mut rng)?;
+        assert_eq!(converted.topology.fanouts(), vec![2, 2, 2]);
+        assert_eq!(converted.len(), 36);
+        for (key, value) in old.iter().enumerate() {
+            assert_eq!(converted.derive(key as u64).ok(), *value);
+        }
 
-    //     assert_eq!(khf.commit(&mut rng), vec![]);
-    //     let key0_rederived = khf.derive(0)?;
-    //     let key1_rederived = khf.derive(1)?;
-    //     let key4_rederived_yet_again = khf.derive(4)?;
-    //     let key5_rederived_yet_again = khf.derive(5)?;
-    //     assert_eq!(key0, key0_rederived);
-    //     assert_eq!(key1, key1_rederived);
-    //     assert_ne!(key4_rederived_yet_again, key4_rederived_again);
-    //     assert_ne!(key5_rederived_yet_again, key5_rederived_again);
+        // The next commit rotates every key but the deleted one, reporting the old values.
+        let rotated = converted.commit(&mut rng)?;
+        assert_eq!(rotated.len(), 35);
+        for (key, value) in rotated {
+            assert_eq!(Some(value), old[key as usize]);
+            assert_ne!(converted.derive(key)?, value);
+        }
+        assert!(converted.is_deleted(7));
 
-    //     Ok(())
-    // }
+        Ok(())
+    }
 
-    use rand::prelude::*;
-    use std::collections::HashSet;
+    #[test]
+    fn max_keys() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[2, 2, 2, 2], thread_rng());
+        assert_eq!(khf.max_keys(), u64::MAX - 15);
+
+        // Any number of top-level nodes is addressable, so large keys don't alias small ones.
+        assert_ne!(khf.derive(1 << 40)?, khf.derive(0)?);
+        assert!(matches!(
+            khf.derive(u64::MAX - 1),
+            Err(Error::KeyOutOfRange { max, .. }) if max == u64::MAX - 15
+        ));
+        assert!(matches!(
+            khf.update(u64::MAX),
+            Err(Error::KeyOutOfRange { key: u64::MAX, .. })
+        ));
+
+        Ok(())
+    }
 
     #[test]
-    fn random_commit() -> Result<()> {
-        fn all_keys(khf: &mut Khf<Sha3_256, SHA3_256_MD_SIZE>) -> Vec<[u8; SHA3_256_MD_SIZE]> {
-            (0..100).map(|i| khf.derive(i).unwrap()).collect()
+    fn persisted_topology() -> Result<()> {
+        let topology = Topology::new(&[8, 4, 2]);
+        assert_eq!(serde_json::to_string(&topology)?, "[8,4,2]");
+        assert_eq!(serde_json::from_str::<Topology>("[8,4,2]")?, topology);
+        assert!(serde_json::from_str::<Topology>("[8,0,2]").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_topology() {
+        for fanouts in [&[][..], &[0, 4], &[4, 1], &[u64::MAX, 2]] {
+            assert!(matches!(
+                Khf::<Sha3_256, SHA3_256_MD_SIZE>::try_new(fanouts, thread_rng()),
+                Err(Error::InvalidTopology)
+            ));
         }
+        assert!(Khf::<Sha3_256, SHA3_256_MD_SIZE>::try_new(&[2, 2], thread_rng()).is_ok());
+
+        assert_eq!(
+            Topology::from_descendants(&[512, 8, 4]),
+            Some(Topology::new(&[64, 2, 4]))
+        );
+        assert_eq!(Topology::from_descendants(&[64, 24, 4]), None);
+        assert_eq!(Topology::from_descendants(&[16, 16]), None);
+    }
 
+    #[test]
+    fn corrupt_roots() -> Result<()> {
         let mut rng = thread_rng();
-        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], &mut rng);
+        khf.append(32);
+        khf.commit(&mut rng)?;
+        khf.update(20)?;
+        khf.commit(&mut rng)?;
 
-        for _ in 0..10000 {
-            let old = all_keys(&mut khf);
+        // A consolidated root covers every key, even though its position covers none.
+        let mut consolidated = khf.clone();
+        consolidated.consolidate_keys(Consolidation::Full, &mut rng);
+        assert!(consolidated.is_consolidated());
+        consolidated.check_coverage()?;
+        consolidated.update(3)?;
+        consolidated.commit(&mut rng)?;
 
-            let ks: HashSet<u64> = HashSet::from_iter((0..10).map(|_| {
-                let k = rng.gen_range(0..100);
-                khf.update(k).unwrap();
-                khf.commit(&mut rng).unwrap();
-                k
-            }));
+        // A hand-edited root list with a gap errors instead of panicking.
+        let value = khf.derive_key_immutable(18)?;
+        let intact = khf.clone();
+        Arc::make_mut(&mut khf.roots).remove(1);
+        assert!(matches!(khf.derive(18), Err(Error::NoCoverage(18))));
+        assert!(!khf.verify(18, &value));
+        assert!(matches!(khf.diff(&intact), Err(Error::NoCoverage(_))));
+        assert!(matches!(
+            khf.extract(0..32, &mut rng),
+            Err(Error::NoCoverage(_))
+        ));
+        assert!(matches!(
+            khf.overlay().derive(&khf, 18),
+            Err(Error::NoCoverage(18))
+        ));
+        assert!(matches!(khf.commit(&mut rng), Err(Error::NoCoverage(_))));
+        assert!(matches!(khf.split(8, &mut rng), Err(Error::NoCoverage(_))));
 
-            let new = all_keys(&mut khf);
+        Ok(())
+    }
 
-            for (i, (o, n)) in old.iter().zip(&new).enumerate() {
-                if !ks.contains(&(i as u64)) {
-                    assert_eq!(o, n);
-                }
-            }
+    #[test]
+    fn root_lookup() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(1000);
+        khf.commit(&mut rng)?;
+        for key in (0..1000).step_by(7) {
+            khf.update(key)?;
         }
+        khf.commit(&mut rng)?;
+
+        for key in 0..1000 {
+            let index = khf.root_index_binary(key);
+            assert!(index.is_some());
+            assert_eq!(index, khf.root_index_interpolation(key));
+        }
+        assert_eq!(khf.root_index_interpolation(1000), None);
 
         Ok(())
     }
 
     #[test]
-    fn caching() -> Result<()> {
-        let mut keys = HashMap::new();
-        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], thread_rng());
+    fn ranged_consolidation() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(256);
+        khf.commit(&mut rng)?;
 
-        for i in 0..10000 {
-            let key = khf.derive(i).unwrap();
-            keys.insert(i, key);
+        let old: Vec<_> = (0..256).map(|key| khf.derive(key).unwrap()).collect();
+        let affected = khf.consolidate_keys(Consolidation::Ranged { start: 5, end: 70 }, &mut rng);
+        assert_eq!(affected, (5..70).collect::<Vec<_>>());
+
+        khf.cache.clear();
+        for key in 0..256 {
+            assert_eq!(
+                old[key as usize] != khf.derive(key)?,
+                (5..70).contains(&key)
+            );
         }
 
-        for i in 0..10000 {
-            let cached_key = khf.derive(i).unwrap();
-            let saved_key = keys.get(&i).unwrap();
-            assert_eq!(&cached_key, saved_key);
+        assert_eq!(khf.align_range(5..70, 2), 0..128);
+        assert_eq!(khf.align_range(16..32, 3), 16..32);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4, 4], &mut rng);
+        khf.append(10);
+        khf.commit(&mut rng)?;
+
+        assert!(matches!(
+            khf.delete(10),
+            Err(Error::KeyOutOfRange { key: 10, max: 10 })
+        ));
+        khf.delete(9)?;
+        assert!(matches!(khf.derive(9), Err(Error::Deleted(9))));
+        assert!(matches!(khf.update(9), Err(Error::Deleted(9))));
+        khf.commit(&mut rng)?;
+        assert_eq!(khf.key_state(9), KeyState::Deleted);
+
+        // Deleted keys can be derived again once truncated and appended again.
+        khf.truncate_keys(9);
+        khf.commit(&mut rng)?;
+        khf.append(1);
+        khf.derive(9)?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "mlock")]
+    #[test]
+    fn locked_keys() -> Result<()> {
+        let mut rng = thread_rng();
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], &mut rng);
+        khf.set_retained_epochs(2);
+        for key in 0..20 {
+            khf.update(key)?;
+            khf.commit(&mut rng)?;
         }
+        for key in 0..20 {
+            khf.derive(key)?;
+        }
+
+        // The roots and retained root lists stay locked as they're reallocated.
+        assert!(khf.lock_keys());
+        assert_eq!(khf.key_regions().len(), 4);
+        assert!(khf.key_regions().into_iter().all(LockedRegion::is_locked));
 
         Ok(())
     }
@@ -0,0 +1,162 @@
+use crate::{aliases::Key, error::Error, khf::Khf};
+use hasher::Hasher;
+use kms::KeyManagementScheme;
+use rand::{CryptoRng, RngCore};
+
+/// A layout mapping `(object_id, block_id)` pairs onto the keys of a `Khf`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Each object gets a contiguous run of keys, one per block, so the keys of an object share
+    /// roots and updates to an object fragment the `Khf` as little as possible.
+    Strided { blocks_per_object: u64 },
+    /// The keys of the objects are interleaved, so the same block of each object is contiguous.
+    Interleaved { objects: u64 },
+}
+
+impl Layout {
+    /// Maps an `(object_id, block_id)` pair to a key, or `None` if it doesn't fit in the layout.
+    pub fn key(&self, (object, block): (u64, u64)) -> Option<u64> {
+        match *self {
+            Self::Strided { blocks_per_object } if block < blocks_per_object => {
+                object.checked_mul(blocks_per_object)?.checked_add(block)
+            }
+            Self::Interleaved { objects } if object < objects => {
+                block.checked_mul(objects)?.checked_add(object)
+            }
+            _ => None,
+        }
+    }
+
+    /// Maps a key back to its `(object_id, block_id)` pair.
+    ///
+    /// Panics if the layout has no blocks per object or no objects.
+    pub fn id(&self, key: u64) -> (u64, u64) {
+        match *self {
+            Self::Strided { blocks_per_object } => {
+                (key / blocks_per_object, key % blocks_per_object)
+            }
+            Self::Interleaved { objects } => (key % objects, key / objects),
+        }
+    }
+}
+
+/// A `Khf` addressing keys by `(object_id, block_id)` pairs, e.g., for per-file-per-block key
+/// management, by mapping the pairs onto its keys with a `Layout`.
+pub struct Khf2d<H, const N: usize> {
+    khf: Khf<H, N>,
+    layout: Layout,
+}
+
+impl<H, const N: usize> Khf2d<H, N>
+where
+    H: Hasher<N>,
+{
+    /// Constructs a new `Khf2d` mapping pairs onto the keys of a `Khf` with a layout.
+    pub fn new(khf: Khf<H, N>, layout: Layout) -> Self {
+        Self { khf, layout }
+    }
+
+    /// Returns the layout pairs are mapped with.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Returns the underlying `Khf`.
+    pub fn khf(&self) -> &Khf<H, N> {
+        &self.khf
+    }
+
+    /// Returns the underlying `Khf` mutably, e.g., to consolidate it.
+    pub fn khf_mut(&mut self) -> &mut Khf<H, N> {
+        &mut self.khf
+    }
+
+    /// Consumes the `Khf2d`, returning the underlying `Khf`.
+    pub fn into_khf(self) -> Khf<H, N> {
+        self.khf
+    }
+
+    // Maps a pair to a key, erroring if it doesn't fit in the layout.
+    fn key(&self, id: (u64, u64)) -> Result<u64, Error> {
+        self.layout.key(id).ok_or(Error::IdOutOfRange {
+            id,
+            layout: self.layout,
+        })
+    }
+}
+
+impl<H, const N: usize> KeyManagementScheme for Khf2d<H, N>
+where
+    H: Hasher<N>,
+{
+    /// Keys have the same size as the hash digest size.
+    type Key = Key<N>;
+    /// Keys are identified with `(object_id, block_id)` pairs.
+    type KeyId = (u64, u64);
+    /// Bespoke error type.
+    type Error = Error;
+
+    fn derive(&mut self, id: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        let key = self.key(id)?;
+        self.khf.derive(key)
+    }
+
+    fn update(&mut self, id: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        let key = self.key(id)?;
+        self.khf.update(key)
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Self::Key)>, Self::Error> {
+        let layout = self.layout;
+        let mut res = Vec::new();
+        self.khf
+            .commit_with(rng, |key, value| res.push((layout.id(key), value)))?;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use rand::prelude::*;
+
+    #[test]
+    fn layouts() -> Result<()> {
+        for layout in [
+            Layout::Strided {
+                blocks_per_object: 8,
+            },
+            Layout::Interleaved { objects: 4 },
+        ] {
+            let khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+            let mut khf = Khf2d::new(khf, layout);
+
+            let key = khf.derive((2, 5))?;
+            assert_eq!(khf.khf_mut().derive(layout.key((2, 5)).unwrap())?, key);
+            assert_eq!(layout.id(layout.key((2, 5)).unwrap()), (2, 5));
+
+            khf.update((3, 1))?;
+            let updated = khf.commit(thread_rng())?;
+            assert_eq!(updated.len(), 1);
+            assert_eq!(updated[0].0, (3, 1));
+            assert_eq!(khf.derive((2, 5))?, key);
+        }
+
+        let khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        let mut khf = Khf2d::new(khf, Layout::Interleaved { objects: 4 });
+        assert!(matches!(
+            khf.derive((4, 0)),
+            Err(Error::IdOutOfRange {
+                id: (4, 0),
+                layout: Layout::Interleaved { objects: 4 },
+            })
+        ));
+
+        Ok(())
+    }
+}
@@ -1,28 +1,97 @@
-use crate::{aliases::Key, node::Node, topology::Topology};
+use crate::{
+    aliases::Key,
+    error::Error,
+    khf::Khf,
+    node::{self, Node, PathCache, Tree},
+    topology::Topology,
+};
 use hasher::Hasher;
-use std::fmt;
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::Range};
 
-#[derive(Clone)]
+/// A keyed hash tree deriving every key from a single root, persisted like a `Khf` with `serde`.
+#[derive(Deserialize, Serialize)]
 pub struct Kht<H, const N: usize> {
+    #[serde(bound(serialize = "Node<H, N>: Serialize"))]
+    #[serde(bound(deserialize = "Node<H, N>: Deserialize<'de>"))]
     root: Node<H, N>,
     topology: Topology,
 }
 
+// Manually implemented to avoid restrictive bounds on `H`.
+impl<H, const N: usize> Clone for Kht<H, N> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            topology: self.topology.clone(),
+        }
+    }
+}
+
 impl<H, const N: usize> Kht<H, N>
 where
     H: Hasher<N>,
 {
-    pub fn new(key: Key<N>) -> Self {
+    /// Constructs a new `Kht` with a root key and a list of fanouts.
+    ///
+    /// Panics if the fanouts are invalid (see `Topology::try_new()`).
+    pub fn new(fanouts: &[u64], key: Key<N>) -> Self {
+        Self::with_topology(Topology::new(fanouts), key)
+    }
+
+    /// Constructs a new `Kht` with a root key and a topology.
+    pub fn with_topology(topology: Topology, key: Key<N>) -> Self {
         Self {
             root: Node::new(key),
-            topology: Topology::default(),
+            topology,
         }
     }
 
+    /// Returns the topology of the `Kht`.
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
     pub fn derive(&self, leaf: u64) -> Key<N> {
         self.root
             .derive(&self.topology, self.topology.leaf_position(leaf))
     }
+
+    /// Derives the key of the node at an offset within a level, e.g., to hand out the subtree below
+    /// it. The leaves are at the level `topology().height() - 1`.
+    ///
+    /// Panics if `level` isn't between 1 and the height of the topology (exclusive).
+    pub fn derive_node(&self, level: u64, offset: u64) -> Key<N> {
+        assert!(0 < level && level < self.topology.height());
+        self.root.derive(&self.topology, (level, offset))
+    }
+
+    /// Draws the root of the `Kht` as a Graphviz DOT graph, like `Khf::to_dot()`.
+    pub fn to_dot(&self) -> String {
+        node::to_dot(
+            "kht",
+            std::iter::once((&self.root, 0..self.topology.max_leaves())),
+        )
+    }
+
+    /// Iterates over the keys of a range of leaves in order. Consecutive leaves share the nodes
+    /// on their paths, so each node is only hashed once.
+    pub fn derive_range(&self, range: Range<u64>) -> impl Iterator<Item = Key<N>> + '_ {
+        let mut cache = PathCache::default();
+        range.map(move |leaf| {
+            self.root.derive_with(
+                &self.topology,
+                self.topology.leaf_position(leaf),
+                &mut cache,
+            )
+        })
+    }
+
+    /// Returns a view of the `Kht` that draws its tree like its `Display` implementation, but
+    /// with every key. Only use this when debugging.
+    pub fn dump_unredacted(&self) -> impl fmt::Display + '_ {
+        Tree::new(std::slice::from_ref(&self.root), &self.topology, true)
+    }
 }
 
 impl<H, const N: usize> fmt::Display for Kht<H, N>
@@ -30,6 +99,106 @@ where
     H: Hasher<N>,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.root.fmt(f, &self.topology)
+        Tree::new(std::slice::from_ref(&self.root), &self.topology, false).fmt(f)
+    }
+}
+
+/// Converts a consolidated `Khf` into a `Kht` deriving the keys the `Khf` provided as of its last
+/// commit. Errors with `Error::NotConsolidated` if the `Khf` isn't consolidated.
+impl<H, const N: usize> TryFrom<&Khf<H, N>> for Kht<H, N>
+where
+    H: Hasher<N>,
+{
+    type Error = Error;
+
+    fn try_from(khf: &Khf<H, N>) -> Result<Self, Self::Error> {
+        let root = khf.consolidated_root().ok_or(Error::NotConsolidated)?;
+        Ok(Self::with_topology(khf.topology().clone(), root.key))
+    }
+}
+
+/// Converts a `Kht` into an empty `Khf` that appends the `Kht`'s keys.
+impl<H, const N: usize> From<Kht<H, N>> for Khf<H, N>
+where
+    H: Hasher<N>,
+{
+    fn from(kht: Kht<H, N>) -> Self {
+        Khf::from_root(kht.topology, kht.root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::khf::Consolidation;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use kms::KeyManagementScheme;
+
+    #[test]
+    fn topologies() {
+        let wide = Kht::<Sha3_256, SHA3_256_MD_SIZE>::new(&[16, 16], [1; SHA3_256_MD_SIZE]);
+        let deep = Kht::<Sha3_256, SHA3_256_MD_SIZE>::with_topology(
+            Topology::new(&[2, 2, 2, 2, 2, 2, 2, 2]),
+            [1; SHA3_256_MD_SIZE],
+        );
+        assert_eq!(wide.topology().fanouts(), vec![16, 16]);
+        assert_ne!(wide.derive(0), wide.derive(255));
+        assert_ne!(wide.derive(255), deep.derive(255));
+    }
+
+    #[test]
+    fn derive_range() {
+        let kht = Kht::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], [3; SHA3_256_MD_SIZE]);
+        let keys: Vec<_> = kht.derive_range(30..70).collect();
+        assert_eq!(keys.len(), 40);
+        for (leaf, key) in (30..70).zip(keys) {
+            assert_eq!(key, kht.derive(leaf));
+        }
+    }
+
+    #[test]
+    fn derive_node() {
+        let kht = Kht::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4, 4], [4; SHA3_256_MD_SIZE]);
+        assert_eq!(kht.derive_node(4, 21), kht.derive(21));
+
+        // The subtree below a node derives the same keys.
+        let node = Node::<Sha3_256, SHA3_256_MD_SIZE>::with_pos((3, 5), kht.derive_node(3, 5));
+        for leaf in 20..24 {
+            assert_eq!(
+                node.derive(kht.topology(), kht.topology().leaf_position(leaf)),
+                kht.derive(leaf)
+            );
+        }
+    }
+
+    #[test]
+    fn khf_conversion() -> Result<()> {
+        let kht = Kht::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], [5; SHA3_256_MD_SIZE]);
+        let mut khf = Khf::from(kht.clone());
+        khf.append(20);
+        assert_eq!(khf.derive(17)?, kht.derive(17));
+        khf.commit(rand::thread_rng())?;
+        assert_eq!(khf.derive(17)?, kht.derive(17));
+
+        khf.update(3)?;
+        khf.commit(rand::thread_rng())?;
+        assert!(matches!(Kht::try_from(&khf), Err(Error::NotConsolidated)));
+        khf.consolidate_keys(Consolidation::Full, rand::thread_rng());
+        let kht = Kht::try_from(&khf)?;
+        assert_eq!(kht.derive(17), khf.derive(17)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn persistence() -> Result<()> {
+        let kht = Kht::<Sha3_256, SHA3_256_MD_SIZE>::new(&[8, 8], [2; SHA3_256_MD_SIZE]);
+        let persisted: Kht<Sha3_256, SHA3_256_MD_SIZE> =
+            bincode::deserialize(&bincode::serialize(&kht)?)?;
+        assert_eq!(persisted.topology(), kht.topology());
+        assert_eq!(persisted.derive(42), kht.derive(42));
+
+        Ok(())
     }
 }
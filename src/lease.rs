@@ -0,0 +1,86 @@
+use crate::{aliases::Key, ids::Epoch, node::Node, topology::Topology};
+use hasher::Hasher;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, fmt, ops::Range};
+
+/// Key material for deriving a range of keys outside of a `Khf`, e.g., in another process. A
+/// lease is only valid until the `Khf` it was taken from reaches the epoch it expires at, since
+/// the commit reaching that epoch rotates the leased keys.
+#[derive(Deserialize, Serialize)]
+pub struct Lease<H, const N: usize> {
+    topology: Topology,
+    range: Range<u64>,
+    expires: u64,
+    #[serde(bound(serialize = "Node<H, N>: Serialize"))]
+    #[serde(bound(deserialize = "Node<H, N>: Deserialize<'de>"))]
+    roots: Vec<Node<H, N>>,
+}
+
+// Manually implemented to avoid restrictive bounds on `H`.
+impl<H, const N: usize> Clone for Lease<H, N> {
+    fn clone(&self) -> Self {
+        Self {
+            topology: self.topology.clone(),
+            range: self.range.clone(),
+            expires: self.expires,
+            roots: self.roots.clone(),
+        }
+    }
+}
+
+impl<H, const N: usize> Lease<H, N>
+where
+    H: Hasher<N>,
+{
+    pub(crate) fn new(
+        topology: Topology,
+        range: Range<u64>,
+        expires: u64,
+        roots: Vec<Node<H, N>>,
+    ) -> Self {
+        Self {
+            topology,
+            range,
+            expires,
+            roots,
+        }
+    }
+
+    /// Returns the range of leased keys.
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+
+    /// Returns the epoch the lease expires at.
+    pub fn expires(&self) -> Epoch {
+        Epoch(self.expires)
+    }
+
+    /// Derives a leased key, or returns `None` if the key isn't leased.
+    pub fn derive(&self, key: u64) -> Option<Key<N>> {
+        let pos = self.topology.leaf_position(key);
+        let index = self
+            .roots
+            .binary_search_by(|root| {
+                if self.topology.is_ancestor(root.pos, pos) {
+                    Ordering::Equal
+                } else if self.topology.end(root.pos) <= key {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            })
+            .ok()?;
+        Some(self.roots[index].derive(&self.topology, pos))
+    }
+}
+
+// Manually implemented to avoid leaking key material.
+impl<H, const N: usize> fmt::Debug for Lease<H, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lease")
+            .field("range", &self.range)
+            .field("expires", &self.expires)
+            .finish_non_exhaustive()
+    }
+}
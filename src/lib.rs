@@ -1,15 +1,86 @@
 pub(crate) mod aliases;
 pub(crate) mod node;
-pub(crate) mod topology;
+pub(crate) use khf_core::topology;
 
+pub mod autotune;
+mod cache;
+pub mod ct;
+mod digest;
+pub mod encoding;
+mod epoch;
 mod error;
+mod extent;
+mod fallback;
+#[cfg(feature = "test-utils")]
+mod faulty;
+mod forest;
+#[cfg(any(feature = "blake3", feature = "openssl"))]
+mod hashers;
+mod ids;
+mod kdf;
 mod khf;
+mod khf2d;
 mod kht;
+mod lease;
+mod map;
+mod overlay;
+#[cfg(feature = "persist")]
+mod persist;
+mod ranges;
+mod report;
 mod result;
+pub mod scrub;
+#[cfg(feature = "secret")]
+mod secret;
+#[cfg(feature = "mlock")]
+mod secure;
+mod table;
 
 pub use crate::{
+    aliases::{Key, Khf256, Kht256, Pos},
+    cache::{CachePolicy, CacheStats},
+    epoch::{ClosingEpoch, OpenEpoch},
     error::Error,
-    khf::{Consolidation, Khf},
+    extent::{Extent, ExtentMap},
+    fallback::{Accelerated, Fallback},
+    forest::{Forest, ObjectStore},
+    ids::{Epoch, KeyId},
+    kdf::KdfRng,
+    khf::{
+        Consolidation, EpochOp, KeyState, Khf, KhfBuilder, KhfStats, Quota, Receipt, RootLookup,
+    },
+    khf2d::{Khf2d, Layout},
     kht::Kht,
+    lease::Lease,
+    map::KhfMap,
+    node::Tree,
+    overlay::Overlay,
+    ranges::RangeSet,
+    report::Report,
     result::Result,
+    table::Recovery,
+    topology::{Coverage, Path, Topology},
 };
+
+#[cfg(feature = "blake3")]
+pub use crate::{
+    aliases::{KhfBlake3, KhtBlake3},
+    hashers::{Blake3, BLAKE3_MD_SIZE},
+};
+
+#[cfg(feature = "openssl")]
+pub use crate::{aliases::KhfAccelerated256, hashers::OpensslSha3_256};
+
+#[cfg(feature = "persist")]
+pub use crate::persist::{IoBackend, MemoryBackend, Persist, StorageBackend};
+
+#[cfg(feature = "embedded-io")]
+pub use crate::persist::EmbeddedIoBackend;
+
+#[cfg(feature = "secret")]
+pub use crate::secret::{SecretKey, SecretKms};
+
+#[cfg(feature = "test-utils")]
+pub use crate::faulty::{Fault, FaultyStore};
+
+pub use khf_core;
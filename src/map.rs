@@ -0,0 +1,186 @@
+use crate::{aliases::Key, error::Error, khf::Khf};
+use hasher::Hasher;
+use kms::KeyManagementScheme;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::HashMap, hash::Hash};
+
+/// Maps arbitrary names (e.g., paths or UUIDs) to keys of a `Khf`, for users that don't have
+/// dense integer key IDs.
+///
+/// Removing a name updates its key, and the key is reused for new names once the update is
+/// committed. The mapping is persisted along with the `Khf` when the `KhfMap` is serialized.
+#[derive(Deserialize, Serialize)]
+#[serde(bound(serialize = "K: Serialize + Eq + Hash, Khf<H, N>: Serialize"))]
+#[serde(bound(
+    deserialize = "K: Deserialize<'de> + Eq + Hash, H: Hasher<N>, Khf<H, N>: Deserialize<'de>"
+))]
+pub struct KhfMap<K, H, const N: usize> {
+    #[serde(deserialize_with = "deserialize_khf")]
+    khf: Khf<H, N>,
+    // Maps each name to its key.
+    index: HashMap<K, u64>,
+    // Keys released since the last commit.
+    released: Vec<u64>,
+    // Keys that have been rotated since they were released, so they can be reused.
+    free: Vec<u64>,
+}
+
+// A deserialized `Khf` doesn't know how many keys are in flight, so every committed key is
+// considered in flight, so that keys are allocated after them.
+pub(crate) fn deserialize_khf<'de, D, H, const N: usize>(
+    deserializer: D,
+) -> Result<Khf<H, N>, D::Error>
+where
+    D: Deserializer<'de>,
+    H: Hasher<N>,
+    Khf<H, N>: Deserialize<'de>,
+{
+    let mut khf = Khf::deserialize(deserializer)?;
+    khf.restore_in_flight_keys();
+    Ok(khf)
+}
+
+impl<K, H, const N: usize> KhfMap<K, H, N>
+where
+    K: Clone + Eq + Hash,
+    H: Hasher<N>,
+{
+    /// Constructs a new, empty `KhfMap` allocating keys from a `Khf`.
+    pub fn new(khf: Khf<H, N>) -> Self {
+        Self {
+            khf,
+            index: HashMap::new(),
+            released: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Returns the `Khf` that keys are allocated from.
+    pub fn khf(&self) -> &Khf<H, N> {
+        &self.khf
+    }
+
+    /// Returns the ID of the key a name is mapped to, if it's mapped.
+    pub fn get(&self, name: &K) -> Option<u64> {
+        self.index.get(name).copied()
+    }
+
+    /// Returns `true` if a name is mapped to a key.
+    pub fn contains(&self, name: &K) -> bool {
+        self.index.contains_key(name)
+    }
+
+    /// Returns the number of mapped names.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns `true` if no names are mapped.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Unmaps a name, updating its key so that it's revoked at the next commit. Returns `false` if
+    /// the name wasn't mapped.
+    pub fn remove(&mut self, name: &K) -> Result<bool, Error> {
+        let Some(key) = self.index.remove(name) else {
+            return Ok(false);
+        };
+        self.khf.update(key)?;
+        self.released.push(key);
+        Ok(true)
+    }
+
+    // Returns the key a name is mapped to, mapping it to a fresh key if it isn't mapped.
+    fn key(&mut self, name: K) -> u64 {
+        let Self {
+            khf, index, free, ..
+        } = self;
+        *index
+            .entry(name)
+            .or_insert_with(|| free.pop().unwrap_or_else(|| khf.append(1).start))
+    }
+}
+
+impl<K, H, const N: usize> KeyManagementScheme for KhfMap<K, H, N>
+where
+    K: Clone + Eq + Hash,
+    H: Hasher<N>,
+{
+    /// Keys have the same size as the hash digest size.
+    type Key = Key<N>;
+    /// Keys are identified by names, which are mapped to keys on first use.
+    type KeyId = K;
+    /// Bespoke error type.
+    type Error = Error;
+
+    fn derive(&mut self, name: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        let key = self.key(name);
+        self.khf.derive(key)
+    }
+
+    fn update(&mut self, name: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        let key = self.key(name);
+        self.khf.update(key)
+    }
+
+    /// Commits the underlying `Khf`, reporting the updated keys of mapped names. Keys of removed
+    /// names aren't reported, and become free for reuse.
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Self::Key)>, Self::Error> {
+        let mut updated = HashMap::new();
+        self.khf.commit_with(rng, |key, value| {
+            updated.insert(key, value);
+        })?;
+        self.free.append(&mut self.released);
+
+        Ok(self
+            .index
+            .iter()
+            .filter_map(|(name, key)| Some((name.clone(), updated.remove(key)?)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use rand::prelude::*;
+
+    type Map = KhfMap<String, Sha3_256, SHA3_256_MD_SIZE>;
+
+    #[test]
+    fn names() -> Result<()> {
+        let mut map = Map::new(Khf::new(&[4, 4], thread_rng()));
+        let a = map.derive("/a".into())?;
+        map.derive("/b".into())?;
+        map.derive("/c".into())?;
+        map.commit(thread_rng())?;
+        assert_eq!(map.derive("/a".into())?, a);
+
+        // Removed names free their keys once the removal is committed.
+        let b = map.get(&"/b".into()).unwrap();
+        assert!(map.remove(&"/b".into())?);
+        assert!(!map.remove(&"/b".into())?);
+        map.update("/c".into())?;
+        let updated = map.commit(thread_rng())?;
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].0, "/c");
+        map.derive("/d".into())?;
+        assert_eq!(map.get(&"/d".into()), Some(b));
+
+        // The mapping survives persistence, and new names get fresh keys.
+        map.commit(thread_rng())?;
+        let mut map: Map = bincode::deserialize(&bincode::serialize(&map)?)?;
+        assert_eq!(map.derive("/a".into())?, a);
+        map.derive("/e".into())?;
+        assert_eq!(map.get(&"/e".into()), Some(3));
+
+        Ok(())
+    }
+}
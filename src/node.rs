@@ -1,234 +1,205 @@
 use crate::{
     aliases::{Key, Pos},
+    cache::Cache,
+    encoding,
     topology::Topology,
 };
 use hasher::Hasher;
-use rand::{CryptoRng, RngCore};
-use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
-use std::{collections::HashMap, fmt, marker::PhantomData};
-
-#[serde_as]
-#[derive(Serialize, Deserialize)]
-pub struct Node<H, const N: usize> {
-    pub pos: Pos,
-    #[serde_as(as = "[_; N]")]
-    pub key: Key<N>,
-    pd: PhantomData<H>,
-}
+use std::{
+    fmt::{self, Write},
+    ops::Range,
+};
 
-impl<H, const N: usize> fmt::Debug for Node<H, N> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Node")
-            .field("pos", &self.pos)
-            .field("key", &hex::encode(&self.key))
-            .finish()
-    }
+pub use khf_core::node::{KeyCache, Node, PathCache};
+
+/// Domain separation for the fingerprints of keys drawn in DOT graphs.
+const FINGERPRINT_DOMAIN: &[u8] = b"khf fingerprint v1";
+
+/// Derivation of keys through a `Cache`.
+pub trait NodeExt<const N: usize> {
+    fn derive_and_cache(&self, topology: &Topology, pos: Pos, cache: &mut Cache<N>) -> Key<N>;
+
+    fn derive_cached(&self, topology: &Topology, pos: Pos, cache: &Cache<N>) -> Key<N>;
 }
 
-// Manually implemented to avoid restrictive bounds on `H`.
-impl<H, const N: usize> Clone for Node<H, N> {
-    fn clone(&self) -> Self {
-        Self {
-            pos: self.pos,
-            key: self.key,
-            pd: PhantomData,
-        }
-    }
+/// Draws the trees below a list of roots, optionally bounded in depth and to a range of keys.
+/// Subtrees cut off by the depth limit are drawn as the range of keys they cover.
+pub struct Tree<'a, H, const N: usize> {
+    roots: &'a [Node<H, N>],
+    topology: &'a Topology,
+    unredacted: bool,
+    max_depth: Option<u64>,
+    range: Option<Range<u64>>,
 }
 
-impl<H, const N: usize> Node<H, N>
+impl<'a, H, const N: usize> Tree<'a, H, N>
 where
     H: Hasher<N>,
 {
-    pub fn new(key: Key<N>) -> Self {
+    // Constructs an unbounded drawing of the trees below some roots, with keys only if
+    // `unredacted` is set.
+    pub(crate) fn new(roots: &'a [Node<H, N>], topology: &'a Topology, unredacted: bool) -> Self {
         Self {
-            pos: (0, 0),
-            key,
-            pd: PhantomData,
+            roots,
+            topology,
+            unredacted,
+            max_depth: None,
+            range: None,
         }
     }
 
-    pub fn with_rng<R>(rng: &mut R) -> Self
-    where
-        R: RngCore + CryptoRng,
-    {
-        let mut key = [0; N];
-        rng.fill_bytes(&mut key);
-        Self::new(key)
-    }
-
-    pub fn with_pos(pos: Pos, key: Key<N>) -> Self {
-        Self {
-            pos,
-            key,
-            pd: PhantomData,
-        }
-    }
-
-    pub fn derive(&self, topology: &Topology, pos: Pos) -> Key<N> {
-        if self.pos == pos {
-            self.key
-        } else {
-            topology.path(self.pos, pos).fold(self.key, |key, pos| {
-                let mut hasher = H::new();
-                hasher.update(&key);
-                hasher.update(&pos.0.to_le_bytes());
-                hasher.update(&pos.1.to_le_bytes());
-                hasher.finish()
-            })
-        }
-    }
-
-    pub fn derive_and_cache(
-        &self,
-        topology: &Topology,
-        pos: Pos,
-        cache: &mut HashMap<Pos, Key<N>>,
-    ) -> Key<N> {
-        if self.pos == pos {
-            self.key
-        } else {
-            topology.path(self.pos, pos).fold(self.key, |key, pos| {
-                if let Some(cached_key) = cache.get(&pos) {
-                    *cached_key
-                } else {
-                    let mut hasher = H::new();
-                    hasher.update(&key);
-                    hasher.update(&pos.0.to_le_bytes());
-                    hasher.update(&pos.1.to_le_bytes());
-
-                    let key = hasher.finish();
-                    cache.insert(pos, key);
-                    key
-                }
-            })
-        }
-    }
-
-    pub fn derive_cached(
-        &self,
-        topology: &Topology,
-        pos: Pos,
-        cache: &HashMap<Pos, Key<N>>,
-    ) -> Key<N> {
-        if self.pos == pos {
-            self.key
-        } else {
-            topology.path(self.pos, pos).fold(self.key, |key, pos| {
-                if let Some(cached_key) = cache.get(&pos) {
-                    *cached_key
-                } else {
-                    let mut hasher = H::new();
-                    hasher.update(&key);
-                    hasher.update(&pos.0.to_le_bytes());
-                    hasher.update(&pos.1.to_le_bytes());
-                    hasher.finish()
-                }
-            })
-        }
-    }
-
-    pub fn coverage(&self, topology: &Topology, level: u64, start: u64, end: u64) -> Vec<Self> {
-        topology
-            .coverage(level, start, end)
-            .map(|pos| Self {
-                pos,
-                key: self.derive(topology, pos),
-                pd: PhantomData,
-            })
-            .collect()
-    }
-
-    pub fn coverage_and_cache(
-        &self,
-        topology: &Topology,
-        level: u64,
-        start: u64,
-        end: u64,
-        cache: &mut HashMap<Pos, Key<N>>,
-    ) -> Vec<Self> {
-        topology
-            .coverage(level, start, end)
-            .map(|pos| Self {
-                pos,
-                key: self.derive_and_cache(topology, pos, cache),
-                pd: PhantomData,
-            })
-            .collect()
+    /// Only draws nodes up to `depth` levels below each root. A depth of 0 only draws the roots.
+    pub fn max_depth(mut self, depth: u64) -> Self {
+        self.max_depth = Some(depth);
+        self
     }
 
-    pub fn coverage_cached(
-        &self,
-        topology: &Topology,
-        level: u64,
-        start: u64,
-        end: u64,
-        cache: &HashMap<Pos, Key<N>>,
-    ) -> Vec<Self> {
-        topology
-            .coverage(level, start, end)
-            .map(|pos| Self {
-                pos,
-                key: self.derive_cached(topology, pos, cache),
-                pd: PhantomData,
-            })
-            .collect()
+    /// Only draws nodes covering keys in a range.
+    pub fn range(mut self, range: Range<u64>) -> Self {
+        self.range = Some(range);
+        self
     }
 
-    pub(crate) fn fmt(&self, f: &mut fmt::Formatter<'_>, topology: &Topology) -> fmt::Result {
-        self.fmt_helper(f, topology, String::new(), self.pos, true)
+    // Returns `true` if a node should be drawn, i.e., if it covers a key in the range.
+    fn is_visible(&self, pos: Pos) -> bool {
+        let Some(range) = &self.range else {
+            return true;
+        };
+        let (start, end) = self.topology.range(pos);
+        pos == (0, 0) || (start < range.end && range.start < end)
     }
 
-    fn fmt_helper(
+    // Draws a node and the visible part of the subtree below it, starting on a new line unless
+    // it's the first node drawn.
+    fn fmt_node(
         &self,
-        f: &mut fmt::Formatter,
-        topology: &Topology,
-        prefix: String,
+        f: &mut fmt::Formatter<'_>,
+        node: &Node<H, N>,
+        prefix: &str,
         pos: Pos,
         last: bool,
     ) -> fmt::Result {
+        if pos != node.pos {
+            writeln!(f)?;
+        }
         if let Some(width) = f.width() {
             write!(f, "{}", " ".repeat(width))?;
         }
 
-        if pos == self.pos {
-            write!(f, "> {} ({}, {})", hex::encode(&self.key), pos.0, pos.1)?;
+        if pos == node.pos {
+            write!(f, "> ")?;
         } else {
             write!(f, "{}{} ", prefix, if last { "└───" } else { "├───" })?;
+        }
+        if self.unredacted {
             write!(
                 f,
-                "{} ({}, {})",
-                hex::encode(self.derive(topology, pos)),
-                pos.0,
-                pos.1
+                "{} ",
+                *encoding::encode_hex(&node.derive(self.topology, pos))
             )?;
         }
+        write!(f, "({}, {})", pos.0, pos.1)?;
 
-        if self.pos != (0, 0) && pos != (topology.height() - 1, topology.end(self.pos) - 1) {
-            writeln!(f)?;
+        if pos.0 == 0 || pos.0 >= self.topology.height() - 1 {
+            return Ok(());
+        }
+        if self
+            .max_depth
+            .is_some_and(|depth| pos.0 - node.pos.0 >= depth)
+        {
+            let (start, end) = self.topology.range(pos);
+            return write!(f, " {start}..{end}");
         }
 
-        if pos.0 < topology.height() - 1 {
-            for i in 0..topology.fanout(pos.0) {
-                let prefix = prefix.clone()
-                    + if pos == self.pos {
-                        ""
-                    } else if last {
-                        "     "
-                    } else {
-                        "│    "
-                    };
-                self.fmt_helper(
-                    f,
-                    topology,
-                    prefix,
-                    (pos.0 + 1, pos.1 * topology.fanout(pos.0) + i),
-                    i + 1 == topology.fanout(pos.0),
-                )?;
-            }
+        let prefix = String::from(prefix)
+            + if pos == node.pos {
+                ""
+            } else if last {
+                "     "
+            } else {
+                "│    "
+            };
+        let fanout = self.topology.fanout(pos.0);
+        let children: Vec<Pos> = (0..fanout)
+            .map(|i| (pos.0 + 1, pos.1 * fanout + i))
+            .filter(|child| self.is_visible(*child))
+            .collect();
+        for (i, child) in children.iter().enumerate() {
+            self.fmt_node(f, node, &prefix, *child, i + 1 == children.len())?;
         }
 
         Ok(())
     }
 }
+
+impl<'a, H, const N: usize> fmt::Display for Tree<'a, H, N>
+where
+    H: Hasher<N>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let roots: Vec<&Node<H, N>> = self
+            .roots
+            .iter()
+            .filter(|root| self.is_visible(root.pos))
+            .collect();
+        for (i, root) in roots.iter().enumerate() {
+            self.fmt_node(f, root, "", root.pos, true)?;
+            if i + 1 != roots.len() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<H, const N: usize> NodeExt<N> for Node<H, N>
+where
+    H: Hasher<N>,
+{
+    fn derive_and_cache(&self, topology: &Topology, pos: Pos, cache: &mut Cache<N>) -> Key<N> {
+        self.derive_with(topology, pos, cache)
+    }
+
+    fn derive_cached(&self, topology: &Topology, pos: Pos, cache: &Cache<N>) -> Key<N> {
+        self.derive_with(topology, pos, &mut ReadOnly(cache))
+    }
+}
+
+// Draws roots as a Graphviz DOT graph, labeling each root with its position, the range of keys it
+// covers, and a fingerprint of its key. Fingerprints are hashes of the keys, so the same key has
+// the same fingerprint across graphs without being revealed.
+pub(crate) fn to_dot<'a, H, const N: usize>(
+    name: &str,
+    roots: impl IntoIterator<Item = (&'a Node<H, N>, Range<u64>)>,
+) -> String
+where
+    H: Hasher<N> + 'a,
+{
+    let mut dot = format!("digraph {name} {{\n    node [shape=box];\n    {name};\n");
+    for (i, (root, range)) in roots.into_iter().enumerate() {
+        let mut hasher = H::new();
+        hasher.update(FINGERPRINT_DOMAIN);
+        hasher.update(&root.key);
+        let fingerprint = encoding::encode_hex(&hasher.finish()[..4]);
+
+        // Writing to a `String` can't fail.
+        let _ = writeln!(
+            dot,
+            "    root{i} [label=\"({}, {})\\n{}..{}\\n{}\"];\n    {name} -> root{i};",
+            root.pos.0, root.pos.1, range.start, range.end, *fingerprint
+        );
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+// Uses a cache without adding to it.
+struct ReadOnly<'a, const N: usize>(&'a Cache<N>);
+
+impl<'a, const N: usize> KeyCache<N> for ReadOnly<'a, N> {
+    fn get(&self, pos: &Pos) -> Option<Key<N>> {
+        self.0.peek(pos)
+    }
+
+    fn insert(&mut self, _: Pos, _: Key<N>) {}
+}
@@ -0,0 +1,147 @@
+use crate::{aliases::Key, epoch::OpenEpoch, error::Error, khf::Khf};
+use hasher::Hasher;
+use std::{collections::BTreeSet, ops::Range};
+
+/// Speculative updates and appends recorded against a `Khf` without modifying it, so they can be
+/// merged into it atomically with `merge_into()` or discarded by dropping the `Overlay`.
+///
+/// An `Overlay` doesn't borrow its parent, which is passed to each operation instead. Keys derived
+/// through an `Overlay` are the keys its parent would derive, and stay valid as long as the parent
+/// isn't committed, after which the `Overlay` is stale and can no longer be merged.
+#[derive(Clone, Debug)]
+pub struct Overlay {
+    pub(crate) epoch: u64,
+    // The number of keys the parent covered when the `Overlay` was created.
+    pub(crate) base_keys: u64,
+    pub(crate) in_flight_keys: u64,
+    pub(crate) updated: BTreeSet<u64>,
+}
+
+impl Overlay {
+    pub(crate) fn new(epoch: u64, in_flight_keys: u64) -> Self {
+        Self {
+            epoch,
+            base_keys: in_flight_keys,
+            in_flight_keys,
+            updated: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the number of keys the parent would cover if the `Overlay` were merged.
+    pub fn in_flight_len(&self) -> u64 {
+        self.in_flight_keys
+    }
+
+    /// Returns the keys speculatively updated.
+    pub fn updated_keys(&self) -> &BTreeSet<u64> {
+        &self.updated
+    }
+
+    /// Derives a key as the parent would, implicitly appending it to the `Overlay` if needed.
+    pub fn derive<H, const N: usize>(
+        &mut self,
+        parent: &Khf<H, N>,
+        key: u64,
+    ) -> Result<Key<N>, Error>
+    where
+        H: Hasher<N>,
+    {
+        parent.check_overlay(self)?;
+        parent.check_derivable_with(key, self.in_flight_keys)?;
+        let value = parent.derive_key_immutable(key)?;
+        self.in_flight_keys = self.in_flight_keys.max(key + 1);
+        Ok(value)
+    }
+
+    /// Speculatively updates a key, returning its current value like `Khf::update()`.
+    pub fn update<H, const N: usize>(
+        &mut self,
+        parent: &Khf<H, N>,
+        key: u64,
+    ) -> Result<Key<N>, Error>
+    where
+        H: Hasher<N>,
+    {
+        let value = self.derive(parent, key)?;
+        self.updated.insert(key);
+        Ok(value)
+    }
+
+    /// Speculatively appends a number of keys.
+    pub fn append(&mut self, n: u64) -> Range<u64> {
+        let start = self.in_flight_keys;
+        self.in_flight_keys += n;
+        start..self.in_flight_keys
+    }
+
+    /// Applies the speculative updates and appends to the open epoch of the parent. Nothing is
+    /// applied if the `Overlay` is stale (i.e., the parent was committed or truncated since it was
+    /// created), the parent is read-only, or any of its updated keys was deleted from the parent
+    /// in the meantime.
+    pub fn merge_into<H, const N: usize>(
+        self,
+        parent: &mut OpenEpoch<'_, H, N>,
+    ) -> Result<(), Error>
+    where
+        H: Hasher<N>,
+    {
+        parent.merge_overlay(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use kms::KeyManagementScheme;
+    use rand::prelude::*;
+
+    #[test]
+    fn speculate() -> Result<()> {
+        let mut khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        khf.append(8);
+        khf.commit(thread_rng())?;
+
+        let mut overlay = khf.overlay();
+        assert_eq!(overlay.update(&khf, 3)?, khf.derive(3)?);
+        assert_eq!(overlay.append(4), 8..12);
+        assert_eq!(overlay.derive(&khf, 14)?, khf.clone().derive(14)?);
+        assert!(khf.updated_keys().is_empty());
+        assert_eq!(khf.in_flight_len(), 8);
+
+        // Discarding an overlay leaves no trace, while merging applies it.
+        khf.overlay().update(&khf, 5)?;
+        overlay.merge_into(&mut khf.open_epoch())?;
+        assert_eq!(khf.updated_keys().iter().collect::<Vec<_>>(), [3]);
+        assert_eq!(khf.in_flight_len(), 15);
+
+        let mut overlay = khf.overlay();
+        overlay.update(&khf, 4)?;
+        khf.commit(thread_rng())?;
+        assert!(matches!(
+            overlay.merge_into(&mut khf.open_epoch()),
+            Err(Error::StaleOverlay(1))
+        ));
+
+        // Merging an overlay doesn't undo a truncation made after it was created.
+        let mut overlay = khf.overlay();
+        overlay.append(2);
+        khf.truncate_keys(10);
+        assert!(matches!(
+            overlay.merge_into(&mut khf.open_epoch()),
+            Err(Error::StaleOverlay(2))
+        ));
+        assert_eq!(khf.in_flight_len(), 10);
+
+        // Read-only forests can't be modified through an overlay either.
+        let mut delegated = Khf::from_delegation(khf.topology().clone(), khf.delegate(0..4, 2)?)?;
+        let overlay = delegated.overlay();
+        assert!(matches!(
+            overlay.merge_into(&mut delegated.open_epoch()),
+            Err(Error::ReadOnly)
+        ));
+
+        Ok(())
+    }
+}
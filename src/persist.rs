@@ -0,0 +1,365 @@
+use crate::{digest::Digest, error::Error, khf::Khf, kht::Kht};
+use hasher::Hasher;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Storage for a single persisted record, e.g., a file or a region of flash.
+///
+/// Implement this to persist to storage that isn't covered by `IoBackend`, `EmbeddedIoBackend`, or
+/// `MemoryBackend`.
+pub trait StorageBackend {
+    /// Loads the stored record, or returns `None` if nothing is stored.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Stores a record, replacing the previously stored one.
+    fn store(&mut self, bytes: &[u8]) -> Result<(), Error>;
+}
+
+/// Types that can be persisted to a `StorageBackend` and loaded back.
+///
+/// A record holds the serialized value followed by its digest, so that a load can tell a partial or
+/// torn record apart from a valid one, and errors with `Error::Corrupt` on the former.
+pub trait Persist: Sized {
+    /// Persists to a backend, replacing whatever it stored.
+    fn persist(&self, backend: &mut impl StorageBackend) -> Result<(), Error>;
+
+    /// Loads from a backend, or returns `None` if nothing is stored.
+    fn load(backend: &mut impl StorageBackend) -> Result<Option<Self>, Error>;
+}
+
+// Persists a value with `bincode`, followed by the digest of its serialization.
+fn persist<H, const N: usize, T>(value: &T, backend: &mut impl StorageBackend) -> Result<(), Error>
+where
+    H: Hasher<N>,
+    T: Serialize,
+{
+    let mut record = bincode::serialize(value)?;
+    let digest = record_digest::<H, N>(&record);
+    record.extend_from_slice(&digest);
+    backend.store(&record)
+}
+
+// Loads a value persisted with `persist()`, checking its digest.
+fn load<H, const N: usize, T>(backend: &mut impl StorageBackend) -> Result<Option<T>, Error>
+where
+    H: Hasher<N>,
+    T: DeserializeOwned,
+{
+    backend
+        .load()?
+        .map(|record| {
+            let len = record.len().checked_sub(N).ok_or(Error::Corrupt)?;
+            let (bytes, digest) = record.split_at(len);
+            if record_digest::<H, N>(bytes)[..] != *digest {
+                return Err(Error::Corrupt);
+            }
+            Ok(bincode::deserialize(bytes)?)
+        })
+        .transpose()
+}
+
+// Computes the digest of a serialized value.
+fn record_digest<H, const N: usize>(bytes: &[u8]) -> [u8; N]
+where
+    H: Hasher<N>,
+{
+    let mut digest = Digest::<H, N>::new();
+    digest.u64(bytes.len() as u64);
+    digest.bytes(bytes);
+    digest.finish()
+}
+
+impl<H, const N: usize> Persist for Khf<H, N>
+where
+    H: Hasher<N>,
+{
+    fn persist(&self, backend: &mut impl StorageBackend) -> Result<(), Error> {
+        persist::<H, N, _>(self, backend)
+    }
+
+    /// Every committed key of a loaded `Khf` is considered in flight, like in a `KhfMap`.
+    fn load(backend: &mut impl StorageBackend) -> Result<Option<Self>, Error> {
+        let mut khf: Option<Self> = load::<H, N, _>(backend)?;
+        if let Some(khf) = &mut khf {
+            khf.restore_in_flight_keys();
+        }
+        Ok(khf)
+    }
+}
+
+impl<H, const N: usize> Persist for Kht<H, N>
+where
+    H: Hasher<N>,
+{
+    fn persist(&self, backend: &mut impl StorageBackend) -> Result<(), Error> {
+        persist::<H, N, _>(self, backend)
+    }
+
+    fn load(backend: &mut impl StorageBackend) -> Result<Option<Self>, Error> {
+        load::<H, N, _>(backend)
+    }
+}
+
+/// A backend storing the record in memory.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    record: Option<Vec<u8>>,
+}
+
+impl MemoryBackend {
+    /// Constructs a new, empty `MemoryBackend`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the stored record, if there is one.
+    pub fn record(&self) -> Option<&[u8]> {
+        self.record.as_deref()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.record.clone())
+    }
+
+    fn store(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.record = Some(bytes.to_vec());
+        Ok(())
+    }
+}
+
+/// A backend storing the record at the start of a seekable `std::io` stream, e.g., a `File`.
+///
+/// The record is prefixed with its length, so a shorter record can overwrite a longer one without
+/// truncating the stream. An empty stream stores nothing.
+#[derive(Debug)]
+pub struct IoBackend<T> {
+    inner: T,
+}
+
+impl<T> IoBackend<T>
+where
+    T: Read + Write + Seek,
+{
+    /// Constructs a new `IoBackend` over a stream.
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    /// Consumes the `IoBackend`, returning the stream.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> StorageBackend for IoBackend<T>
+where
+    T: Read + Write + Seek,
+{
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.seek(SeekFrom::Start(0)).map_err(|_| Error::Io)?;
+
+        let mut len = [0; 8];
+        match self.inner.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(_) => return Err(Error::Io),
+        }
+        let len = usize::try_from(u64::from_le_bytes(len)).map_err(|_| Error::Corrupt)?;
+
+        let mut record = Vec::new();
+        Read::take(&mut self.inner, len as u64)
+            .read_to_end(&mut record)
+            .map_err(|_| Error::Io)?;
+        if record.len() != len {
+            return Err(Error::Corrupt);
+        }
+        Ok(Some(record))
+    }
+
+    fn store(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.inner.seek(SeekFrom::Start(0)).map_err(|_| Error::Io)?;
+        self.inner
+            .write_all(&(bytes.len() as u64).to_le_bytes())
+            .map_err(|_| Error::Io)?;
+        self.inner.write_all(bytes).map_err(|_| Error::Io)?;
+        self.inner.flush().map_err(|_| Error::Io)
+    }
+}
+
+/// A backend storing the record at the start of a seekable `embedded-io` device, e.g., a region of
+/// flash. The record is laid out like with `IoBackend`.
+#[cfg(feature = "embedded-io")]
+#[derive(Debug)]
+pub struct EmbeddedIoBackend<T> {
+    inner: T,
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T> EmbeddedIoBackend<T>
+where
+    T: embedded_io::Read + embedded_io::Write + embedded_io::Seek,
+{
+    /// Constructs a new `EmbeddedIoBackend` over a device.
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    /// Consumes the `EmbeddedIoBackend`, returning the device.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T> StorageBackend for EmbeddedIoBackend<T>
+where
+    T: embedded_io::Read + embedded_io::Write + embedded_io::Seek,
+{
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        use embedded_io::ReadExactError;
+
+        self.inner
+            .seek(embedded_io::SeekFrom::Start(0))
+            .map_err(|_| Error::Io)?;
+
+        let mut len = [0; 8];
+        match self.inner.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ReadExactError::UnexpectedEof) => return Ok(None),
+            Err(ReadExactError::Other(_)) => return Err(Error::Io),
+        }
+        let len = usize::try_from(u64::from_le_bytes(len)).map_err(|_| Error::Corrupt)?;
+
+        // Read in chunks, so that a corrupt length doesn't allocate more than the device holds.
+        let mut record = Vec::new();
+        let mut chunk = [0; 256];
+        while record.len() < len {
+            let want = chunk.len().min(len - record.len());
+            match self.inner.read(&mut chunk[..want]).map_err(|_| Error::Io)? {
+                0 => return Err(Error::Corrupt),
+                n => record.extend_from_slice(&chunk[..n]),
+            }
+        }
+        Ok(Some(record))
+    }
+
+    fn store(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.inner
+            .seek(embedded_io::SeekFrom::Start(0))
+            .map_err(|_| Error::Io)?;
+        self.inner
+            .write_all(&(bytes.len() as u64).to_le_bytes())
+            .map_err(|_| Error::Io)?;
+        self.inner.write_all(bytes).map_err(|_| Error::Io)?;
+        self.inner.flush().map_err(|_| Error::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use kms::KeyManagementScheme;
+    use rand::prelude::*;
+    use std::io::Cursor;
+
+    type Khf256 = Khf<Sha3_256, SHA3_256_MD_SIZE>;
+
+    #[test]
+    fn backends() -> Result<()> {
+        let mut khf = Khf256::new(&[4, 4], thread_rng());
+        let key = khf.derive(10)?;
+        khf.commit(thread_rng())?;
+
+        let mut memory = MemoryBackend::new();
+        let mut io = IoBackend::new(Cursor::new(Vec::new()));
+        assert!(Khf256::load(&mut memory)?.is_none());
+        assert!(Khf256::load(&mut io)?.is_none());
+
+        khf.persist(&mut memory)?;
+        khf.persist(&mut io)?;
+        assert_eq!(Khf256::load(&mut memory)?.unwrap().derive(10)?, key);
+        assert_eq!(Khf256::load(&mut io)?.unwrap().derive(10)?, key);
+
+        // A shorter record replaces a longer one.
+        let kht = Kht::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], [6; SHA3_256_MD_SIZE]);
+        kht.persist(&mut io)?;
+        let loaded = Kht::<Sha3_256, SHA3_256_MD_SIZE>::load(&mut io)?.unwrap();
+        assert_eq!(loaded.derive(10), kht.derive(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn torn_record() -> Result<()> {
+        let mut khf = Khf256::new(&[4, 4], thread_rng());
+        khf.derive(10)?;
+        khf.commit(thread_rng())?;
+
+        let mut io = IoBackend::new(Cursor::new(Vec::new()));
+        khf.persist(&mut io)?;
+
+        // A record torn partway through its overwrite no longer matches its digest.
+        let mut stream = io.into_inner();
+        let len = stream.get_ref().len();
+        stream.get_mut()[len / 2..].fill(0);
+        let mut io = IoBackend::new(stream);
+        assert!(matches!(Khf256::load(&mut io), Err(Error::Corrupt)));
+
+        // As does a record cut short.
+        let mut memory = MemoryBackend::new();
+        khf.persist(&mut memory)?;
+        let record = memory.record().unwrap().to_vec();
+        memory.store(&record[..record.len() - 1])?;
+        assert!(matches!(Khf256::load(&mut memory), Err(Error::Corrupt)));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn embedded_io() -> Result<()> {
+        // A stand-in for a flash device.
+        struct Flash(Cursor<Vec<u8>>);
+
+        impl embedded_io::ErrorType for Flash {
+            type Error = std::io::Error;
+        }
+
+        impl embedded_io::Read for Flash {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+                std::io::Read::read(&mut self.0, buf)
+            }
+        }
+
+        impl embedded_io::Write for Flash {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+                std::io::Write::write(&mut self.0, buf)
+            }
+
+            fn flush(&mut self) -> Result<(), Self::Error> {
+                Ok(())
+            }
+        }
+
+        impl embedded_io::Seek for Flash {
+            fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, Self::Error> {
+                std::io::Seek::seek(&mut self.0, pos.into())
+            }
+        }
+
+        let mut khf = Khf256::new(&[4, 4], thread_rng());
+        let key = khf.derive(10)?;
+        khf.commit(thread_rng())?;
+
+        let mut flash = EmbeddedIoBackend::new(Flash(Cursor::new(Vec::new())));
+        assert!(Khf256::load(&mut flash)?.is_none());
+        khf.persist(&mut flash)?;
+        assert_eq!(Khf256::load(&mut flash)?.unwrap().derive(10)?, key);
+
+        Ok(())
+    }
+}
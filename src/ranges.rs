@@ -0,0 +1,193 @@
+use std::{collections::BTreeMap, mem, ops::Range};
+
+/// A set of keys stored as sorted, disjoint ranges, so that a contiguous run of keys takes the
+/// same space as a single key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeSet {
+    // Maps the start of each range to its end. Ranges never overlap or touch.
+    ranges: BTreeMap<u64, u64>,
+    // The number of keys in the set.
+    len: u64,
+}
+
+impl RangeSet {
+    /// Constructs a new, empty `RangeSet`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of keys in the set.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the set has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns `true` if the set has a key.
+    pub fn contains(&self, key: u64) -> bool {
+        self.ranges
+            .range(..=key)
+            .next_back()
+            .is_some_and(|(_, end)| key < *end)
+    }
+
+    /// Adds a key to the set, returning `false` if it was already in the set.
+    pub fn insert(&mut self, key: u64) -> bool {
+        if self.contains(key) {
+            return false;
+        }
+        self.insert_range(key..key + 1);
+        true
+    }
+
+    /// Adds a range of keys to the set.
+    pub fn insert_range(&mut self, range: Range<u64>) {
+        let Range { mut start, mut end } = range;
+        if start >= end {
+            return;
+        }
+
+        // Extend the range over a range it starts in or touches.
+        if let Some((prev_start, prev_end)) = self.ranges.range(..=start).next_back() {
+            if *prev_end >= start {
+                start = *prev_start;
+                end = end.max(*prev_end);
+            }
+        }
+
+        // Absorb the ranges starting within or right after the range.
+        let absorbed: Vec<(u64, u64)> = self
+            .ranges
+            .range(start..=end)
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (absorbed_start, absorbed_end) in absorbed {
+            self.ranges.remove(&absorbed_start);
+            self.len -= absorbed_end - absorbed_start;
+            end = end.max(absorbed_end);
+        }
+
+        self.ranges.insert(start, end);
+        self.len += end - start;
+    }
+
+    /// Removes a key from the set, returning `false` if it wasn't in the set.
+    pub fn remove(&mut self, key: u64) -> bool {
+        if !self.contains(key) {
+            return false;
+        }
+        self.remove_range(key..key + 1);
+        true
+    }
+
+    /// Removes a range of keys from the set.
+    pub fn remove_range(&mut self, range: Range<u64>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        self.split(range.start);
+        self.split(range.end);
+        let removed: Vec<u64> = self.ranges.range(range).map(|(start, _)| *start).collect();
+        for start in removed {
+            let end = self.ranges.remove(&start).unwrap();
+            self.len -= end - start;
+        }
+    }
+
+    /// Removes the keys at or after `at` from the set.
+    pub fn truncate(&mut self, at: u64) {
+        self.split(at);
+        for (start, end) in self.ranges.split_off(&at) {
+            self.len -= end - start;
+        }
+    }
+
+    /// Moves every key of another set into the set, leaving the other set empty.
+    pub fn append(&mut self, other: &mut Self) {
+        for range in other.ranges() {
+            self.insert_range(range);
+        }
+        other.clear();
+    }
+
+    /// Removes every key from the set.
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+        self.len = 0;
+    }
+
+    /// Iterates over the sorted, disjoint ranges of keys in the set.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.ranges.iter().map(|(start, end)| *start..*end)
+    }
+
+    /// Iterates over the keys in the set in order.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges().flatten()
+    }
+
+    // Estimates the heap memory held by the set, in bytes.
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.ranges.len() * mem::size_of::<(u64, u64)>()
+    }
+
+    // Splits the range straddling `at`, if there is one, so that no range crosses it.
+    fn split(&mut self, at: u64) {
+        let Some((_, end)) = self.ranges.range_mut(..at).next_back() else {
+            return;
+        };
+        if at < *end {
+            let tail = std::mem::replace(end, at);
+            self.ranges.insert(at, tail);
+        }
+    }
+}
+
+impl Extend<u64> for RangeSet {
+    fn extend<I: IntoIterator<Item = u64>>(&mut self, keys: I) {
+        for key in keys {
+            self.insert(key);
+        }
+    }
+}
+
+impl FromIterator<u64> for RangeSet {
+    fn from_iter<I: IntoIterator<Item = u64>>(keys: I) -> Self {
+        let mut set = Self::new();
+        set.extend(keys);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let mut set: RangeSet = [5, 3, 4, 9].into_iter().collect();
+        assert_eq!(set.ranges().collect::<Vec<_>>(), vec![3..6, 9..10]);
+        assert!(!set.insert(4));
+
+        // Touching ranges are merged, and a run of keys is a single range.
+        set.insert_range(6..9);
+        set.insert_range(100..1_000_000);
+        assert_eq!(
+            set.ranges().collect::<Vec<_>>(),
+            vec![3..10, 100..1_000_000]
+        );
+        assert_eq!(set.len(), 7 + 999_900);
+
+        set.remove_range(5..200);
+        assert!(set.remove(3));
+        assert!(!set.remove(3));
+        set.truncate(150);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![4]);
+        assert_eq!(set.len(), 1);
+        assert!(set.contains(4) && !set.contains(5));
+    }
+}
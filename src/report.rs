@@ -0,0 +1,166 @@
+use std::ops::Range;
+
+/// A report of the keys affected by an operation on a `Khf`, out of a range of keys. When nearly
+/// every key is affected, the report describes the unaffected keys instead, so it stays compact.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Report {
+    /// The keys in the sorted, disjoint `ranges` are affected.
+    Positive {
+        keys: Range<u64>,
+        ranges: Vec<Range<u64>>,
+    },
+    /// Every key in `keys` is affected except the keys in the sorted, disjoint `except` ranges.
+    Negative {
+        keys: Range<u64>,
+        except: Vec<Range<u64>>,
+    },
+}
+
+impl Report {
+    /// Creates a report from the sorted, disjoint ranges of affected keys out of a range of keys,
+    /// picking whichever form takes fewer ranges.
+    pub fn new(keys: Range<u64>, ranges: Vec<Range<u64>>) -> Self {
+        let except = complement(&keys, &ranges);
+        if except.len() < ranges.len() {
+            Self::Negative { keys, except }
+        } else {
+            Self::Positive { keys, ranges }
+        }
+    }
+
+    /// Creates a report from the affected keys out of a range of keys, in any order.
+    pub fn from_keys(keys: Range<u64>, affected: impl IntoIterator<Item = u64>) -> Self {
+        let mut affected: Vec<u64> = affected.into_iter().collect();
+        affected.sort_unstable();
+        affected.dedup();
+
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for key in affected {
+            match ranges.last_mut() {
+                Some(last) if last.end == key => last.end += 1,
+                _ => ranges.push(key..key + 1),
+            }
+        }
+
+        Self::new(keys, ranges)
+    }
+
+    /// The range of keys the report is out of.
+    pub fn keys(&self) -> Range<u64> {
+        match self {
+            Self::Positive { keys, .. } | Self::Negative { keys, .. } => keys.clone(),
+        }
+    }
+
+    /// Returns `true` if the report describes the unaffected keys.
+    pub fn is_negative(&self) -> bool {
+        matches!(self, Self::Negative { .. })
+    }
+
+    /// Returns `true` if a key is affected.
+    pub fn contains(&self, key: u64) -> bool {
+        match self {
+            Self::Positive { ranges, .. } => contains(ranges, key),
+            Self::Negative { keys, except } => keys.contains(&key) && !contains(except, key),
+        }
+    }
+
+    /// The number of affected keys.
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Positive { ranges, .. } => {
+                ranges.iter().map(|range| range.end - range.start).sum()
+            }
+            Self::Negative { keys, except } => {
+                keys.end
+                    - keys.start
+                    - except
+                        .iter()
+                        .map(|range| range.end - range.start)
+                        .sum::<u64>()
+            }
+        }
+    }
+
+    /// Returns `true` if no keys are affected.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The sorted, disjoint ranges of affected keys.
+    pub fn affected_ranges(&self) -> Vec<Range<u64>> {
+        match self {
+            Self::Positive { ranges, .. } => ranges.clone(),
+            Self::Negative { keys, except } => complement(keys, except),
+        }
+    }
+
+    /// The sorted, disjoint ranges of unaffected keys.
+    pub fn unaffected_ranges(&self) -> Vec<Range<u64>> {
+        match self {
+            Self::Positive { keys, ranges } => complement(keys, ranges),
+            Self::Negative { except, .. } => except.clone(),
+        }
+    }
+
+    /// Iterates over the affected keys in order.
+    pub fn affected(&self) -> impl Iterator<Item = u64> {
+        self.affected_ranges().into_iter().flatten()
+    }
+
+    /// Iterates over the unaffected keys in order.
+    pub fn unaffected(&self) -> impl Iterator<Item = u64> {
+        self.unaffected_ranges().into_iter().flatten()
+    }
+}
+
+// Returns `true` if a key is in one of a list of sorted, disjoint ranges.
+fn contains(ranges: &[Range<u64>], key: u64) -> bool {
+    let index = ranges.partition_point(|range| range.end <= key);
+    ranges.get(index).is_some_and(|range| range.contains(&key))
+}
+
+// Returns the sorted, disjoint ranges within `keys` that aren't in a list of sorted, disjoint
+// ranges.
+fn complement(keys: &Range<u64>, ranges: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut complement = Vec::new();
+    let mut next = keys.start;
+    for range in ranges {
+        if next < range.start {
+            complement.push(next..range.start.min(keys.end));
+        }
+        next = next.max(range.end);
+    }
+    if next < keys.end {
+        complement.push(next..keys.end);
+    }
+    complement
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forms() {
+        let report = Report::from_keys(0..1000, (0..1000).filter(|key| key % 100 != 7));
+        assert!(report.is_negative());
+        assert_eq!(report.len(), 990);
+        assert_eq!(
+            report.unaffected().collect::<Vec<_>>(),
+            (0..10).map(|i| i * 100 + 7).collect::<Vec<_>>()
+        );
+        assert!(report.contains(8) && !report.contains(107) && !report.contains(1000));
+
+        let report = Report::from_keys(0..1000, [5, 3, 4, 900]);
+        assert!(!report.is_negative());
+        assert_eq!(report.affected_ranges(), vec![3..6, 900..901]);
+        assert_eq!(report.unaffected_ranges(), vec![0..3, 6..900, 901..1000]);
+        assert_eq!(report.affected().collect::<Vec<_>>(), vec![3, 4, 5, 900]);
+
+        assert!(Report::from_keys(0..10, []).is_empty());
+        assert!(Report::from_keys(0..10, 0..10)
+            .unaffected_ranges()
+            .is_empty());
+    }
+}
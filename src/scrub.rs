@@ -0,0 +1,114 @@
+//! Planning the media-level sanitization that completes the secure deletion of revoked keys.
+//!
+//! Revoking a key makes the data it encrypted unreadable, but the ciphertext (and any key
+//! material persisted alongside it) still lingers on the storage media. A `Planner` bridges the
+//! gap by translating revoked keys into the physical ranges of the media that must be overwritten.
+
+use std::ops::Range;
+
+/// Plans the physical ranges of a storage device that must be overwritten to complete the secure
+/// deletion of revoked keys, e.g., the keys reported by committing a `Khf`.
+pub struct Planner<F> {
+    // Maps a range of keys to the physical ranges of the data they encrypt.
+    map: F,
+
+    // The ranges of revoked keys that haven't been planned yet.
+    revoked: Vec<Range<u64>>,
+}
+
+impl<F, I> Planner<F>
+where
+    F: FnMut(Range<u64>) -> I,
+    I: IntoIterator<Item = Range<u64>>,
+{
+    /// Creates a planner using a device mapping callback, which maps a range of keys to the
+    /// physical ranges of the data they encrypt.
+    pub fn new(map: F) -> Self {
+        Self {
+            map,
+            revoked: Vec::new(),
+        }
+    }
+
+    /// Adds a revoked key to the plan.
+    pub fn revoke(&mut self, key: u64) {
+        self.revoke_range(key..key + 1);
+    }
+
+    /// Adds a range of revoked keys to the plan.
+    pub fn revoke_range(&mut self, keys: Range<u64>) {
+        if !keys.is_empty() {
+            self.revoked.push(keys);
+        }
+    }
+
+    /// Adds revoked keys to the plan.
+    pub fn revoke_all(&mut self, keys: impl IntoIterator<Item = u64>) {
+        for key in keys {
+            self.revoke(key);
+        }
+    }
+
+    /// Returns the sorted, disjoint physical ranges that must be overwritten for the keys revoked
+    /// since the last plan. The device mapping callback is invoked once for each maximal range of
+    /// revoked keys.
+    pub fn plan(&mut self) -> Vec<Range<u64>> {
+        let mut physical = Vec::new();
+        for keys in coalesce(std::mem::take(&mut self.revoked)) {
+            physical.extend((self.map)(keys));
+        }
+        coalesce(physical)
+    }
+}
+
+// Sorts ranges and merges the ones that overlap or touch, dropping empty ranges.
+fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.retain(|range| !range.is_empty());
+    ranges.sort_unstable_by_key(|range| range.start);
+
+    let mut coalesced: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan() {
+        // Each key encrypts a 4096 byte block, and keys from 16 onwards live on another extent.
+        let block = |key: u64| {
+            let start = if key < 16 { 0 } else { 1 << 20 } + key * 4096;
+            start..start + 4096
+        };
+        let mut calls = 0;
+        let mut planner = Planner::new(|keys: Range<u64>| {
+            calls += 1;
+            keys.map(block)
+        });
+
+        planner.revoke_all([3, 1, 2, 8]);
+        planner.revoke_range(6..8);
+        planner.revoke_range(15..17);
+        planner.revoke_range(4..4);
+
+        assert_eq!(
+            planner.plan(),
+            vec![
+                4096..4 * 4096,
+                6 * 4096..9 * 4096,
+                15 * 4096..16 * 4096,
+                (1 << 20) + 16 * 4096..(1 << 20) + 17 * 4096,
+            ]
+        );
+        assert!(planner.plan().is_empty());
+        drop(planner);
+        assert_eq!(calls, 3);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{aliases::Key, ct};
+use kms::KeyManagementScheme;
+use rand::{CryptoRng, RngCore};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// A derived key that can't be exposed by accident: its `Debug` implementation is redacted, it
+/// can't be serialized, and it's zeroized when dropped. The key itself is reached explicitly
+/// through `expose_secret()`.
+#[derive(Clone)]
+pub struct SecretKey<const N: usize>(Key<N>);
+
+impl<const N: usize> SecretKey<N> {
+    /// Wraps a key.
+    pub fn new(key: Key<N>) -> Self {
+        Self(key)
+    }
+
+    /// Returns the key.
+    pub fn expose_secret(&self) -> &Key<N> {
+        &self.0
+    }
+}
+
+impl<const N: usize> From<Key<N>> for SecretKey<N> {
+    fn from(key: Key<N>) -> Self {
+        Self(key)
+    }
+}
+
+/// Compares keys in constant time.
+impl<const N: usize> PartialEq for SecretKey<N> {
+    fn eq(&self, other: &Self) -> bool {
+        ct::eq(&self.0, &other.0)
+    }
+}
+
+impl<const N: usize> Eq for SecretKey<N> {}
+
+impl<const N: usize> fmt::Debug for SecretKey<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
+
+impl<const N: usize> Drop for SecretKey<N> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Wraps a key management scheme (e.g., a `Khf`) so that it derives `SecretKey`s.
+pub struct SecretKms<K>(pub K);
+
+impl<K, const N: usize> KeyManagementScheme for SecretKms<K>
+where
+    K: KeyManagementScheme<Key = Key<N>>,
+{
+    /// Keys are wrapped so they can't be exposed by accident.
+    type Key = SecretKey<N>;
+    /// Keys are identified like in the wrapped scheme.
+    type KeyId = K::KeyId;
+    /// Errors are those of the wrapped scheme.
+    type Error = K::Error;
+
+    fn derive(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.0.derive(key).map(SecretKey)
+    }
+
+    fn update(&mut self, key: Self::KeyId) -> Result<Self::Key, Self::Error> {
+        self.0.update(key).map(SecretKey)
+    }
+
+    fn commit(
+        &mut self,
+        rng: impl RngCore + CryptoRng,
+    ) -> Result<Vec<(Self::KeyId, Self::Key)>, Self::Error> {
+        Ok(self
+            .0
+            .commit(rng)?
+            .into_iter()
+            .map(|(id, key)| (id, SecretKey(key)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Khf;
+    use anyhow::Result;
+    use hasher::sha3::{Sha3_256, SHA3_256_MD_SIZE};
+    use rand::prelude::*;
+
+    #[test]
+    fn redacted() -> Result<()> {
+        let khf = Khf::<Sha3_256, SHA3_256_MD_SIZE>::new(&[4, 4], thread_rng());
+        let mut kms = SecretKms(khf);
+        let key = kms.derive(3)?;
+        assert_eq!(format!("{key:?}"), "SecretKey(<redacted>)");
+        assert_eq!(key.expose_secret(), &kms.0.derive(3)?);
+
+        kms.update(3)?;
+        assert_eq!(kms.commit(thread_rng())?, vec![(3, key)]);
+
+        Ok(())
+    }
+}
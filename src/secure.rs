@@ -0,0 +1,211 @@
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Mutex, OnceLock},
+};
+
+// The number of locked regions overlapping each locked page. Memory is locked a page at a time, so
+// a page is only unlocked once no region on it is locked anymore.
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// A region of memory that can be locked into RAM so that it isn't swapped to disk.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LockedRegion {
+    addr: usize,
+    len: usize,
+}
+
+impl LockedRegion {
+    /// Returns the region backing the allocated capacity of a vector.
+    pub fn of_vec<T>(vec: &Vec<T>) -> Self {
+        Self {
+            addr: vec.as_ptr() as usize,
+            len: vec.capacity() * mem::size_of::<T>(),
+        }
+    }
+
+    /// Returns the region backing a boxed value.
+    pub fn of_box<T>(value: &T) -> Self {
+        Self {
+            addr: value as *const T as usize,
+            len: mem::size_of::<T>(),
+        }
+    }
+
+    /// Locks the region, returning `true` on success.
+    pub fn lock(self) -> bool {
+        if self.len == 0 {
+            return true;
+        }
+
+        let mut pages = LOCKED_PAGES.lock().unwrap();
+        let (start, end) = self.pages();
+
+        // Safety: locking only changes how the pages backing the region are paged. Pages that are
+        // already locked stay locked.
+        if !unsafe { memsec::mlock(start as *mut u8, end - start) } {
+            return false;
+        }
+        for page in (start..end).step_by(page_size()) {
+            *pages.entry(page).or_default() += 1;
+        }
+        true
+    }
+
+    /// Unlocks the region, unlocking the pages that no other locked region overlaps. The region
+    /// may have been freed already, as its memory isn't accessed.
+    pub fn unlock(self) {
+        if self.len == 0 {
+            return;
+        }
+
+        let mut pages = LOCKED_PAGES.lock().unwrap();
+        let (start, end) = self.pages();
+        for page in (start..end).step_by(page_size()) {
+            match pages.get_mut(&page) {
+                Some(count) if *count > 1 => *count -= 1,
+                Some(_) => {
+                    pages.remove(&page);
+                    munlock(page);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Returns `true` if every page of the region is locked.
+    #[cfg(test)]
+    pub fn is_locked(self) -> bool {
+        let pages = LOCKED_PAGES.lock().unwrap();
+        let (start, end) = self.pages();
+        (start..end)
+            .step_by(page_size())
+            .all(|page| pages.contains_key(&page))
+    }
+
+    // Returns the page-aligned bounds of the region.
+    fn pages(self) -> (usize, usize) {
+        let page_size = page_size();
+        let start = self.addr - self.addr % page_size;
+        let end = (self.addr + self.len).div_ceil(page_size) * page_size;
+        (start, end)
+    }
+}
+
+/// A set of locked regions, which are unlocked when dropped.
+#[derive(Default)]
+pub(crate) struct LockedRegions {
+    regions: Option<Vec<LockedRegion>>,
+}
+
+impl LockedRegions {
+    /// Locks a set of regions in place of the currently locked ones, e.g., after the memory
+    /// holding keys was reallocated, returning `true` on success.
+    pub fn relock(&mut self, regions: Vec<LockedRegion>) -> bool {
+        if self.regions.as_ref() == Some(&regions) {
+            return true;
+        }
+
+        // The new regions are locked before the stale ones are unlocked, so pages they share
+        // remain locked throughout.
+        let locked = regions.iter().take_while(|region| region.lock()).count();
+        self.unlock();
+        if locked == regions.len() {
+            self.regions = Some(regions);
+            true
+        } else {
+            regions[..locked].iter().for_each(|region| region.unlock());
+            false
+        }
+    }
+
+    // Unlocks the locked regions.
+    fn unlock(&mut self) {
+        for region in self.regions.take().into_iter().flatten() {
+            region.unlock();
+        }
+    }
+}
+
+impl Drop for LockedRegions {
+    fn drop(&mut self) {
+        self.unlock();
+    }
+}
+
+// Returns the size of a page of memory.
+fn page_size() -> usize {
+    static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+
+    #[cfg(unix)]
+    // Safety: `sysconf` has no preconditions.
+    let page_size = || unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    #[cfg(not(unix))]
+    let page_size = || 4096;
+
+    *PAGE_SIZE.get_or_init(page_size)
+}
+
+// Unlocks a page, which may no longer be mapped, without accessing it.
+fn munlock(page: usize) {
+    // Safety: unlocking only changes how the page is paged, and fails harmlessly if it's unmapped.
+    #[cfg(unix)]
+    unsafe {
+        #[cfg(target_os = "linux")]
+        libc::madvise(page as *mut libc::c_void, page_size(), libc::MADV_DODUMP);
+        libc::munlock(page as *const libc::c_void, page_size());
+    }
+
+    // Elsewhere, unlocking would zero the page, so it's left locked until the process exits.
+    #[cfg(not(unix))]
+    let _ = page;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the number of locked regions overlapping a page.
+    fn count(page: usize) -> usize {
+        LOCKED_PAGES
+            .lock()
+            .unwrap()
+            .get(&page)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn shared_pages() {
+        // Regions within a page of memory no other test locks.
+        let buf = vec![0u8; 3 * page_size()];
+        let page = (buf.as_ptr() as usize).div_ceil(page_size()) * page_size();
+        let first = LockedRegion { addr: page, len: 8 };
+        let second = LockedRegion {
+            addr: page + 8,
+            len: 8,
+        };
+
+        assert!(first.lock());
+        assert!(second.lock());
+        assert_eq!(count(page), 2);
+
+        // Unlocking one region keeps the page locked for the other.
+        first.unlock();
+        assert_eq!(count(page), 1);
+        second.unlock();
+        assert_eq!(count(page), 0);
+
+        // Relocking after a reallocation unlocks the stale region.
+        let mut regions = LockedRegions::default();
+        assert!(regions.relock(vec![first]));
+        assert!(regions.relock(vec![LockedRegion {
+            addr: page + page_size(),
+            len: 8,
+        }]));
+        assert_eq!(count(page), 0);
+        assert_eq!(count(page + page_size()), 1);
+        drop(regions);
+        assert_eq!(count(page + page_size()), 0);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{digest::Digest, error::Error, node::Node, topology::Topology};
+use hasher::Hasher;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    io::{Read, Write},
+    ops::Range,
+};
+
+/// The outcome of recovering a `Khf` from a partially corrupt root table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// The number of roots in the table.
+    pub roots: u64,
+    /// The number of roots that were corrupt.
+    pub corrupt_roots: u64,
+    /// The sorted, disjoint ranges of keys that couldn't be recovered. These keys are derived from
+    /// fresh roots, and are marked as updated so they are rotated at the next commit.
+    pub lost: Vec<Range<u64>>,
+}
+
+/// Everything in a root table besides the roots.
+///
+/// A root table starts with the length of the serialized header, followed by the header and its
+/// checksum. Each root follows as a fixed-size record holding the serialized root and its
+/// checksum, so a corrupt root doesn't affect the others.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct Header<H, const N: usize> {
+    pub topology: Topology,
+    #[serde(bound(serialize = "Node<H, N>: Serialize"))]
+    #[serde(bound(deserialize = "Node<H, N>: Deserialize<'de>"))]
+    pub appending_root: Node<H, N>,
+    pub keys: u64,
+    pub epoch: u64,
+    pub deleted: BTreeSet<u64>,
+    pub leases: Vec<(Range<u64>, u64)>,
+    pub roots: u64,
+    pub record_len: u64,
+}
+
+/// A root table read back, with `None` in place of each corrupt root.
+pub(crate) struct Table<H, const N: usize> {
+    pub header: Header<H, N>,
+    pub roots: Vec<Option<Node<H, N>>>,
+}
+
+// Computes the checksum of a serialized header or root.
+fn checksum<H, const N: usize>(bytes: &[u8]) -> [u8; N]
+where
+    H: Hasher<N>,
+{
+    let mut digest = Digest::<H, N>::new();
+    digest.bytes(bytes);
+    digest.finish()
+}
+
+// Returns the data of a record of a certain length, if it matches the checksum that follows it.
+fn record<H, const N: usize>(bytes: &[u8], len: usize) -> Option<&[u8]>
+where
+    H: Hasher<N>,
+{
+    let data = bytes.get(..len)?;
+    let sum = bytes.get(len..len.checked_add(N)?)?;
+    (checksum::<H, N>(data)[..] == sum[..]).then_some(data)
+}
+
+/// Writes a root table.
+pub(crate) fn write<H, const N: usize>(
+    mut writer: impl Write,
+    header: &Header<H, N>,
+    roots: &[Node<H, N>],
+) -> Result<(), Error>
+where
+    H: Hasher<N>,
+{
+    let bytes = bincode::serialize(header)?;
+    writer
+        .write_all(&(bytes.len() as u64).to_le_bytes())
+        .map_err(|_| Error::Io)?;
+    writer.write_all(&bytes).map_err(|_| Error::Io)?;
+    writer
+        .write_all(&checksum::<H, N>(&bytes))
+        .map_err(|_| Error::Io)?;
+
+    for root in roots {
+        let bytes = bincode::serialize(root)?;
+        writer.write_all(&bytes).map_err(|_| Error::Io)?;
+        writer
+            .write_all(&checksum::<H, N>(&bytes))
+            .map_err(|_| Error::Io)?;
+    }
+
+    Ok(())
+}
+
+/// Reads a root table, failing if its header is corrupt.
+pub(crate) fn read<H, const N: usize>(mut reader: impl Read) -> Result<Table<H, N>, Error>
+where
+    H: Hasher<N>,
+{
+    let mut table = Vec::new();
+    reader.read_to_end(&mut table).map_err(|_| Error::Io)?;
+
+    let len = table
+        .get(..8)
+        .and_then(|len| len.try_into().ok())
+        .map(u64::from_le_bytes)
+        .ok_or(Error::Corrupt)?;
+    let header: Header<H, N> = usize::try_from(len)
+        .ok()
+        .and_then(|len| record::<H, N>(&table[8..], len))
+        .and_then(|bytes| bincode::deserialize(bytes).ok())
+        .ok_or(Error::Corrupt)?;
+
+    let start = 8 + len as usize + N;
+    let record_len = usize::try_from(header.record_len).map_err(|_| Error::Corrupt)?;
+    let roots = (0..header.roots as usize)
+        .map(|i| {
+            let offset = start.checked_add(i.checked_mul(record_len + N)?)?;
+            record::<H, N>(table.get(offset..)?, record_len)
+                .and_then(|bytes| bincode::deserialize(bytes).ok())
+        })
+        .collect();
+
+    Ok(Table { header, roots })
+}